@@ -0,0 +1,445 @@
+use private_key::PrivateKey;
+use public_key::PublicKey;
+use serde_de::{Deserializer, Error};
+use serde_de::ErrorKind::*;
+use serde_ser::Serializer;
+use serde;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+
+//  The ssh-agent protocol (draft-miller-ssh-agent). Each message is framed by
+//  a u32 length and begins with a type byte.
+
+pub const SSH_AGENT_FAILURE : u8 = 5;
+pub const SSH_AGENT_SUCCESS : u8 = 6;
+pub const SSH_AGENTC_REQUEST_IDENTITIES : u8 = 11;
+pub const SSH_AGENT_IDENTITIES_ANSWER : u8 = 12;
+pub const SSH_AGENTC_SIGN_REQUEST : u8 = 13;
+pub const SSH_AGENT_SIGN_RESPONSE : u8 = 14;
+pub const SSH_AGENTC_ADD_IDENTITY : u8 = 17;
+pub const SSH_AGENTC_REMOVE_IDENTITY : u8 = 18;
+pub const SSH_AGENTC_REMOVE_ALL_IDENTITIES : u8 = 19;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY : u8 = 20;
+pub const SSH_AGENTC_REMOVE_SMARTCARD_KEY : u8 = 21;
+pub const SSH_AGENTC_LOCK : u8 = 22;
+pub const SSH_AGENTC_UNLOCK : u8 = 23;
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED : u8 = 25;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED : u8 = 26;
+pub const SSH_AGENTC_EXTENSION : u8 = 27;
+pub const SSH_AGENT_EXTENSION_FAILURE : u8 = 28;
+
+/// Sign request flags selecting the RSA signature algorithm.
+pub const SSH_AGENT_RSA_SHA2_256 : u32 = 2;
+pub const SSH_AGENT_RSA_SHA2_512 : u32 = 4;
+
+pub const SSH_AGENT_CONSTRAIN_LIFETIME : u8 = 1;
+pub const SSH_AGENT_CONSTRAIN_CONFIRM : u8 = 2;
+pub const SSH_AGENT_CONSTRAIN_EXTENSION : u8 = 255;
+
+/// Messages longer than this are rejected, as by OpenSSH's ssh-agent.
+pub const MAX_MESSAGE_LEN : usize = 256 * 1024;
+
+//  Constraint extensions whose details are a single string or byte; the details
+//  of any other extension are taken to run to the end of the message.
+const STRING_CONSTRAINT_EXTENSIONS : &'static [&'static str] = &[
+    "restrict-destination-v00@openssh.com",
+    "sk-provider@openssh.com",
+];
+const BOOL_CONSTRAINT_EXTENSIONS : &'static [&'static str] = &["associated-certs-v00@openssh.com"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    /// Seconds after which the agent forgets the key.
+    Lifetime(u32),
+    /// The agent must confirm each use of the key.
+    Confirm,
+    /// An extension constraint with its details as they appear on the wire.
+    Extension(String, Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// A public key or certificate blob.
+    pub key_blob: Vec<u8>,
+    pub comment: String,
+}
+
+impl Identity {
+    pub fn new(public_key: &PublicKey, comment: &str) -> Result<Self, Error> {
+        Ok(Identity{
+            key_blob: public_key.to_bytes()?,
+            comment: comment.into(),
+        })
+    }
+
+    /// The identity's key, failing for certificates and unsupported key types.
+    pub fn public_key(&self) -> Result<PublicKey, Error> {
+        PublicKey::from_bytes(&self.key_blob)
+    }
+}
+
+#[derive(Clone)]
+pub enum Request {
+    RequestIdentities,
+    SignRequest{key_blob: Vec<u8>, data: Vec<u8>, flags: u32},
+    /// Sent as ADD_ID_CONSTRAINED when there are constraints.
+    AddIdentity{private_key: PrivateKey, comment: String, constraints: Vec<Constraint>},
+    RemoveIdentity{key_blob: Vec<u8>},
+    RemoveAllIdentities,
+    AddSmartcardKey{reader_id: String, pin: String, constraints: Vec<Constraint>},
+    RemoveSmartcardKey{reader_id: String, pin: String},
+    Lock{passphrase: Vec<u8>},
+    Unlock{passphrase: Vec<u8>},
+    Extension{name: String, contents: Vec<u8>},
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Failure,
+    Success,
+    IdentitiesAnswer(Vec<Identity>),
+    /// A wire-format signature blob.
+    SignResponse(Vec<u8>),
+    ExtensionFailure,
+    /// Sent as SUCCESS followed by extension-specific contents, such as the
+    /// names answering a `query`. A SUCCESS with no contents decodes as `Success`.
+    ExtensionResponse(Vec<u8>),
+}
+
+fn next<T>(cursor: &mut Cursor<&[u8]>) -> Result<T, Error> where T: for<'x> serde::Deserialize<'x> {
+    Deserializer::new(cursor).next()
+}
+
+fn put<T: ?Sized + serde::Serialize>(buf: &mut Vec<u8>, value: &T) -> Result<(), Error> {
+    Serializer::new(buf).write(value)
+}
+
+fn remaining(cursor: &Cursor<&[u8]>) -> usize {
+    cursor.get_ref().len() - cursor.position() as usize
+}
+
+//  Fails if a fixed-layout message has bytes left over.
+fn finish(cursor: &Cursor<&[u8]>) -> Result<(), Error> {
+    if remaining(cursor) != 0 {
+        return Err(Error{kind: InvalidFormat});
+    }
+    Ok(())
+}
+
+fn read_constraints(cursor: &mut Cursor<&[u8]>) -> Result<Vec<Constraint>, Error> {
+    let mut constraints = vec![];
+    while remaining(cursor) > 0 {
+        let constraint = match next::<u8>(cursor)? {
+            SSH_AGENT_CONSTRAIN_LIFETIME => Constraint::Lifetime(next(cursor)?),
+            SSH_AGENT_CONSTRAIN_CONFIRM => Constraint::Confirm,
+            SSH_AGENT_CONSTRAIN_EXTENSION => {
+                let name : String = next(cursor)?;
+                let start = cursor.position() as usize;
+                if STRING_CONSTRAINT_EXTENSIONS.contains(&name.as_str()) {
+                    next::<Vec<u8>>(cursor)?;
+                } else if BOOL_CONSTRAINT_EXTENSIONS.contains(&name.as_str()) {
+                    next::<u8>(cursor)?;
+                } else {
+                    let end = cursor.get_ref().len() as u64;
+                    cursor.set_position(end);
+                }
+                let details = cursor.get_ref()[start..cursor.position() as usize].to_vec();
+                Constraint::Extension(name, details)
+            },
+            _ => return Err(Error{kind: UnsupportedType}),
+        };
+        constraints.push(constraint);
+    }
+    Ok(constraints)
+}
+
+fn write_constraints(buf: &mut Vec<u8>, constraints: &[Constraint]) -> Result<(), Error> {
+    for constraint in constraints {
+        match *constraint {
+            Constraint::Lifetime(seconds) => {
+                put(buf, &SSH_AGENT_CONSTRAIN_LIFETIME)?;
+                put(buf, &seconds)?;
+            },
+            Constraint::Confirm => put(buf, &SSH_AGENT_CONSTRAIN_CONFIRM)?,
+            Constraint::Extension(ref name, ref details) => {
+                put(buf, &SSH_AGENT_CONSTRAIN_EXTENSION)?;
+                put(buf, name)?;
+                buf.extend_from_slice(details);
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Reads one length-framed message, which starts with its type byte.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(Error{kind: InvalidLength});
+    }
+    let mut message = vec![0; len];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> Result<(), Error> {
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err(Error{kind: InvalidLength});
+    }
+    writer.write_u32::<BigEndian>(message.len() as u32)?;
+    writer.write_all(message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+impl Request {
+    /// Decodes an unframed message. Unknown message types fail with `UnsupportedType`,
+    /// to which an agent should reply with `Response::Failure`.
+    pub fn from_bytes(message: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(message);
+        let request = match next::<u8>(&mut cursor)? {
+            SSH_AGENTC_REQUEST_IDENTITIES => Request::RequestIdentities,
+            SSH_AGENTC_SIGN_REQUEST => Request::SignRequest{
+                key_blob: next(&mut cursor)?,
+                data: next(&mut cursor)?,
+                flags: next(&mut cursor)?,
+            },
+            message_type @ SSH_AGENTC_ADD_IDENTITY | message_type @ SSH_AGENTC_ADD_ID_CONSTRAINED => {
+                let private_key = PrivateKey::deserialize_openssh(&mut Deserializer::new(&mut cursor))?;
+                let comment = next(&mut cursor)?;
+                let constraints = if message_type == SSH_AGENTC_ADD_ID_CONSTRAINED {
+                    read_constraints(&mut cursor)?
+                } else {
+                    vec![]
+                };
+                Request::AddIdentity{private_key: private_key, comment: comment, constraints: constraints}
+            },
+            SSH_AGENTC_REMOVE_IDENTITY => Request::RemoveIdentity{key_blob: next(&mut cursor)?},
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => Request::RemoveAllIdentities,
+            message_type @ SSH_AGENTC_ADD_SMARTCARD_KEY | message_type @ SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => {
+                let reader_id = next(&mut cursor)?;
+                let pin = next(&mut cursor)?;
+                let constraints = if message_type == SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED {
+                    read_constraints(&mut cursor)?
+                } else {
+                    vec![]
+                };
+                Request::AddSmartcardKey{reader_id: reader_id, pin: pin, constraints: constraints}
+            },
+            SSH_AGENTC_REMOVE_SMARTCARD_KEY => Request::RemoveSmartcardKey{
+                reader_id: next(&mut cursor)?,
+                pin: next(&mut cursor)?,
+            },
+            SSH_AGENTC_LOCK => Request::Lock{passphrase: next(&mut cursor)?},
+            SSH_AGENTC_UNLOCK => Request::Unlock{passphrase: next(&mut cursor)?},
+            SSH_AGENTC_EXTENSION => {
+                let name = next(&mut cursor)?;
+                let start = cursor.position() as usize;
+                cursor.set_position(message.len() as u64);
+                Request::Extension{name: name, contents: message[start..].to_vec()}
+            },
+            _ => return Err(Error{kind: UnsupportedType}),
+        };
+        finish(&cursor)?;
+        Ok(request)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![];
+        match *self {
+            Request::RequestIdentities => put(&mut buf, &SSH_AGENTC_REQUEST_IDENTITIES)?,
+            Request::SignRequest{ref key_blob, ref data, flags} => {
+                put(&mut buf, &SSH_AGENTC_SIGN_REQUEST)?;
+                put(&mut buf, key_blob)?;
+                put(&mut buf, data)?;
+                put(&mut buf, &flags)?;
+            },
+            Request::AddIdentity{ref private_key, ref comment, ref constraints} => {
+                let message_type = if constraints.len() > 0 { SSH_AGENTC_ADD_ID_CONSTRAINED } else { SSH_AGENTC_ADD_IDENTITY };
+                put(&mut buf, &message_type)?;
+                private_key.serialize_openssh(&mut Serializer::new(&mut buf))?;
+                put(&mut buf, comment)?;
+                write_constraints(&mut buf, constraints)?;
+            },
+            Request::RemoveIdentity{ref key_blob} => {
+                put(&mut buf, &SSH_AGENTC_REMOVE_IDENTITY)?;
+                put(&mut buf, key_blob)?;
+            },
+            Request::RemoveAllIdentities => put(&mut buf, &SSH_AGENTC_REMOVE_ALL_IDENTITIES)?,
+            Request::AddSmartcardKey{ref reader_id, ref pin, ref constraints} => {
+                let message_type = if constraints.len() > 0 { SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED } else { SSH_AGENTC_ADD_SMARTCARD_KEY };
+                put(&mut buf, &message_type)?;
+                put(&mut buf, reader_id)?;
+                put(&mut buf, pin)?;
+                write_constraints(&mut buf, constraints)?;
+            },
+            Request::RemoveSmartcardKey{ref reader_id, ref pin} => {
+                put(&mut buf, &SSH_AGENTC_REMOVE_SMARTCARD_KEY)?;
+                put(&mut buf, reader_id)?;
+                put(&mut buf, pin)?;
+            },
+            Request::Lock{ref passphrase} => {
+                put(&mut buf, &SSH_AGENTC_LOCK)?;
+                put(&mut buf, passphrase)?;
+            },
+            Request::Unlock{ref passphrase} => {
+                put(&mut buf, &SSH_AGENTC_UNLOCK)?;
+                put(&mut buf, passphrase)?;
+            },
+            Request::Extension{ref name, ref contents} => {
+                put(&mut buf, &SSH_AGENTC_EXTENSION)?;
+                put(&mut buf, name)?;
+                buf.extend_from_slice(contents);
+            },
+        }
+        Ok(buf)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        Request::from_bytes(&read_message(reader)?)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_message(writer, &self.to_bytes()?)
+    }
+}
+
+impl Response {
+    pub fn from_bytes(message: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(message);
+        let response = match next::<u8>(&mut cursor)? {
+            SSH_AGENT_FAILURE => Response::Failure,
+            SSH_AGENT_SUCCESS if message.len() == 1 => Response::Success,
+            SSH_AGENT_SUCCESS => {
+                cursor.set_position(message.len() as u64);
+                Response::ExtensionResponse(message[1..].to_vec())
+            },
+            SSH_AGENT_IDENTITIES_ANSWER => {
+                let count : u32 = next(&mut cursor)?;
+                let mut identities = vec![];
+                for _ in 0..count {
+                    identities.push(Identity{
+                        key_blob: next(&mut cursor)?,
+                        comment: next(&mut cursor)?,
+                    });
+                }
+                Response::IdentitiesAnswer(identities)
+            },
+            SSH_AGENT_SIGN_RESPONSE => Response::SignResponse(next(&mut cursor)?),
+            SSH_AGENT_EXTENSION_FAILURE => Response::ExtensionFailure,
+            _ => return Err(Error{kind: UnsupportedType}),
+        };
+        finish(&cursor)?;
+        Ok(response)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![];
+        match *self {
+            Response::Failure => put(&mut buf, &SSH_AGENT_FAILURE)?,
+            Response::Success => put(&mut buf, &SSH_AGENT_SUCCESS)?,
+            Response::IdentitiesAnswer(ref identities) => {
+                put(&mut buf, &SSH_AGENT_IDENTITIES_ANSWER)?;
+                put(&mut buf, &(identities.len() as u32))?;
+                for identity in identities {
+                    put(&mut buf, &identity.key_blob)?;
+                    put(&mut buf, &identity.comment)?;
+                }
+            },
+            Response::SignResponse(ref signature) => {
+                put(&mut buf, &SSH_AGENT_SIGN_RESPONSE)?;
+                put(&mut buf, signature)?;
+            },
+            Response::ExtensionFailure => put(&mut buf, &SSH_AGENT_EXTENSION_FAILURE)?,
+            Response::ExtensionResponse(ref contents) => {
+                put(&mut buf, &SSH_AGENT_SUCCESS)?;
+                buf.extend_from_slice(contents);
+            },
+        }
+        Ok(buf)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        Response::from_bytes(&read_message(reader)?)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_message(writer, &self.to_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::KeyType;
+
+    #[test]
+    fn messages_are_framed() {
+        let mut framed = vec![];
+        Request::RequestIdentities.write_to(&mut framed).unwrap();
+        assert!(framed == vec![0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES]);
+        let mut framed = vec![];
+        Request::SignRequest{key_blob: vec![1], data: vec![2, 3], flags: SSH_AGENT_RSA_SHA2_512}.write_to(&mut framed).unwrap();
+        assert!(framed == vec![0, 0, 0, 16, SSH_AGENTC_SIGN_REQUEST, 0, 0, 0, 1, 1, 0, 0, 0, 2, 2, 3, 0, 0, 0, 4]);
+        match Request::read_from(&mut &framed[..]).unwrap() {
+            Request::SignRequest{key_blob, data, flags} => {
+                assert!(key_blob == vec![1] && data == vec![2, 3] && flags == SSH_AGENT_RSA_SHA2_512);
+            },
+            _ => assert!("expected" == "SignRequest"),
+        }
+        match Request::read_from(&mut &[0, 4, 0, 1, 11][..]) {
+            Err(Error{kind: InvalidLength}) => {},
+            _ => assert!("expected" == "InvalidLength"),
+        }
+    }
+
+    #[test]
+    fn add_identity_round_trips() {
+        let (private_key, public_key) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        let restriction = vec![0, 0, 0, 2, 0xaa, 0xbb];
+        let request = Request::AddIdentity{
+            private_key: private_key,
+            comment: "laptop".into(),
+            constraints: vec![
+                Constraint::Lifetime(3600),
+                Constraint::Confirm,
+                Constraint::Extension("restrict-destination-v00@openssh.com".into(), restriction.clone()),
+            ],
+        };
+        let bytes = request.to_bytes().unwrap();
+        assert!(bytes[0] == SSH_AGENTC_ADD_ID_CONSTRAINED);
+        match Request::from_bytes(&bytes).unwrap() {
+            Request::AddIdentity{private_key, comment, constraints} => {
+                assert!(private_key.public_key_bytes().unwrap() == public_key);
+                assert!(comment == "laptop");
+                assert!(constraints == vec![
+                    Constraint::Lifetime(3600),
+                    Constraint::Confirm,
+                    Constraint::Extension("restrict-destination-v00@openssh.com".into(), restriction),
+                ]);
+            },
+            _ => assert!("expected" == "AddIdentity"),
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let (private_key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let responses = vec![
+            Response::Failure,
+            Response::Success,
+            Response::IdentitiesAnswer(vec![Identity::new(&private_key.public_key(), "ed25519").unwrap()]),
+            Response::SignResponse(private_key.sign(b"data").unwrap()),
+            Response::ExtensionFailure,
+            Response::ExtensionResponse(vec![0, 0, 0, 5, b'q', b'u', b'e', b'r', b'y']),
+        ];
+        for response in responses {
+            assert!(Response::from_bytes(&response.to_bytes().unwrap()).unwrap() == response);
+        }
+        match Response::from_bytes(&[SSH_AGENT_FAILURE, 0]) {
+            Err(Error{kind: InvalidFormat}) => {},
+            _ => assert!("expected" == "InvalidFormat"),
+        }
+    }
+}
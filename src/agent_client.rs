@@ -0,0 +1,206 @@
+use agent::*;
+use agent_constraints::{SessionBind, SESSION_BIND};
+use private_key::PrivateKey;
+use public_key::PublicKey;
+use rsa::{RSA_SHA2_256, RSA_SHA2_512};
+use serde_de::{self, Error};
+use serde_de::ErrorKind::*;
+use serde_ser;
+use ssh::Signature;
+
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+//  A client for a running ssh-agent, such as OpenSSH's or an `AgentServer`.
+
+pub const SSH_AUTH_SOCK : &'static str = "SSH_AUTH_SOCK";
+
+pub struct AgentClient<S> {
+    stream: S,
+}
+
+impl AgentClient<UnixStream> {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(AgentClient::new(UnixStream::connect(path)?))
+    }
+
+    /// Connects to the agent named by `SSH_AUTH_SOCK`.
+    pub fn connect_env() -> Result<Self, Error> {
+        match env::var_os(SSH_AUTH_SOCK) {
+            Some(path) => AgentClient::connect(path),
+            None => Err(Error{kind: Custom(format!("{} is not set", SSH_AUTH_SOCK))}),
+        }
+    }
+}
+
+fn refused(request: &str) -> Error {
+    Error{kind: Custom(format!("agent refused {}", request))}
+}
+
+impl<S: Read + Write> AgentClient<S> {
+    pub fn new(stream: S) -> Self {
+        AgentClient{
+            stream: stream,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Sends one request and reads the agent's response.
+    pub fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        request.write_to(&mut self.stream)?;
+        Response::read_from(&mut self.stream)
+    }
+
+    //  For requests answered by SUCCESS or FAILURE.
+    fn expect_success(&mut self, request: &Request, name: &str) -> Result<(), Error> {
+        match self.request(request)? {
+            Response::Success => Ok(()),
+            Response::Failure => Err(refused(name)),
+            _ => Err(Error{kind: InvalidFormat}),
+        }
+    }
+
+    pub fn identities(&mut self) -> Result<Vec<Identity>, Error> {
+        match self.request(&Request::RequestIdentities)? {
+            Response::IdentitiesAnswer(identities) => Ok(identities),
+            Response::Failure => Err(refused("identities")),
+            _ => Err(Error{kind: InvalidFormat}),
+        }
+    }
+
+    /// Asks the agent to sign with the key or certificate `key_blob`. The signature is
+    /// not checked; see `sign_verified`.
+    pub fn sign(&mut self, key_blob: &[u8], data: &[u8], flags: u32) -> Result<Signature, Error> {
+        let request = Request::SignRequest{key_blob: key_blob.to_vec(), data: data.to_vec(), flags: flags};
+        match self.request(&request)? {
+            Response::SignResponse(blob) => serde_de::from_slice(&blob),
+            Response::Failure => Err(refused("signature")),
+            _ => Err(Error{kind: InvalidFormat}),
+        }
+    }
+
+    /// Signs with `public_key`, failing with `Crypto` unless the signature verifies
+    /// and uses the RSA algorithm `flags` asked for.
+    pub fn sign_verified(&mut self, public_key: &PublicKey, data: &[u8], flags: u32) -> Result<Signature, Error> {
+        let signature = self.sign(&public_key.to_bytes()?, data, flags)?;
+        let algorithm = match *public_key {
+            PublicKey::RSA(_) if flags & SSH_AGENT_RSA_SHA2_512 != 0 => RSA_SHA2_512,
+            PublicKey::RSA(_) if flags & SSH_AGENT_RSA_SHA2_256 != 0 => RSA_SHA2_256,
+            _ => public_key.key_type(),
+        };
+        if signature._type != algorithm || !public_key.verify(&serde_ser::to_vec(&signature)?, data) {
+            return Err(Error{kind: Crypto});
+        }
+        Ok(signature)
+    }
+
+    pub fn add_identity(&mut self, private_key: &PrivateKey, comment: &str, constraints: &[Constraint]) -> Result<(), Error> {
+        let request = Request::AddIdentity{
+            private_key: private_key.clone(),
+            comment: comment.into(),
+            constraints: constraints.to_vec(),
+        };
+        self.expect_success(&request, "key")
+    }
+
+    pub fn remove_identity(&mut self, key_blob: &[u8]) -> Result<(), Error> {
+        self.expect_success(&Request::RemoveIdentity{key_blob: key_blob.to_vec()}, "removal")
+    }
+
+    pub fn remove_all_identities(&mut self) -> Result<(), Error> {
+        self.expect_success(&Request::RemoveAllIdentities, "removal")
+    }
+
+    pub fn lock(&mut self, passphrase: &[u8]) -> Result<(), Error> {
+        self.expect_success(&Request::Lock{passphrase: passphrase.to_vec()}, "lock")
+    }
+
+    /// Fails if the agent is not locked or the passphrase is wrong.
+    pub fn unlock(&mut self, passphrase: &[u8]) -> Result<(), Error> {
+        self.expect_success(&Request::Unlock{passphrase: passphrase.to_vec()}, "unlock")
+    }
+
+    /// Tells the agent which host the connection is talking to, as `ssh` does after
+    /// key exchange.
+    pub fn bind_session(&mut self, bind: &SessionBind) -> Result<(), Error> {
+        self.expect_success(&bind.to_request()?, SESSION_BIND)
+    }
+
+    /// Calls an extension, returning its response contents, which are empty for a
+    /// plain SUCCESS.
+    pub fn extension(&mut self, name: &str, contents: &[u8]) -> Result<Vec<u8>, Error> {
+        let request = Request::Extension{name: name.into(), contents: contents.to_vec()};
+        match self.request(&request)? {
+            Response::Success => Ok(vec![]),
+            Response::ExtensionResponse(contents) => Ok(contents),
+            Response::Failure | Response::ExtensionFailure => Err(refused(name)),
+            _ => Err(Error{kind: InvalidFormat}),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use agent_server::{AgentServer, MemoryKeyStore};
+    use private_key::KeyType;
+    use std::fs;
+    use std::process;
+    use std::thread;
+
+    fn client(name: &str) -> AgentClient<UnixStream> {
+        let path = env::temp_dir().join(format!("ssh-wire-client-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = AgentServer::<MemoryKeyStore>::bind(&path).unwrap();
+        thread::spawn(move || AgentServer::new(MemoryKeyStore::new()).run(listener));
+        let client = AgentClient::connect(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        client
+    }
+
+    #[test]
+    fn manages_and_signs_with_keys() {
+        let mut agent = client("keys");
+        let (ed25519, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (rsa, _) = PrivateKey::generate(KeyType::Rsa(2048)).unwrap();
+        agent.add_identity(&ed25519, "ed25519", &[]).unwrap();
+        agent.add_identity(&rsa, "rsa", &[]).unwrap();
+        let identities = agent.identities().unwrap();
+        assert!(identities.iter().map(|identity| &identity.comment[..]).collect::<Vec<_>>() == vec!["ed25519", "rsa"]);
+
+        let signature = agent.sign_verified(&ed25519.public_key(), b"data", 0).unwrap();
+        assert!(signature._type == "ssh-ed25519");
+        let signature = agent.sign_verified(&rsa.public_key(), b"data", SSH_AGENT_RSA_SHA2_256).unwrap();
+        assert!(signature._type == RSA_SHA2_256);
+        match agent.sign(&rsa.public_key_bytes().unwrap(), b"data", 0) {
+            Err(Error{kind: Custom(_)}) => {},
+            _ => assert!("expected" == "Custom"),
+        }
+
+        agent.remove_identity(&rsa.public_key_bytes().unwrap()).unwrap();
+        assert!(agent.identities().unwrap().len() == 1);
+        agent.remove_all_identities().unwrap();
+        assert!(agent.identities().unwrap().len() == 0);
+        assert!(agent.remove_identity(&rsa.public_key_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    fn lock_and_extensions() {
+        let mut agent = client("lock");
+        let (private_key, _) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        agent.add_identity(&private_key, "ecdsa", &[]).unwrap();
+        assert!(agent.unlock(b"secret").is_err());
+        agent.lock(b"secret").unwrap();
+        assert!(agent.identities().unwrap().len() == 0);
+        assert!(agent.sign_verified(&private_key.public_key(), b"data", 0).is_err());
+        assert!(agent.unlock(b"wrong").is_err());
+        agent.unlock(b"secret").unwrap();
+        assert!(agent.sign_verified(&private_key.public_key(), b"data", 0).is_ok());
+        assert!(agent.extension("query", &[]).is_err());
+    }
+}
@@ -0,0 +1,485 @@
+use agent::{Constraint, Request};
+use certificate::{Certificate, CertType};
+use pattern;
+use private_key::PrivateKey;
+use serde_de::{Deserializer, Error};
+use serde_de::ErrorKind::*;
+use serde_ser::Serializer;
+use serde;
+
+use std::io::Cursor;
+
+//  Constraints on keys added to an agent: a lifetime, confirmation of each use,
+//  and OpenSSH's destination restrictions (`ssh-add -h`), which limit the hosts
+//  a key may authenticate to and through.
+
+pub const RESTRICT_DESTINATION : &'static str = "restrict-destination-v00@openssh.com";
+pub const SESSION_BIND : &'static str = "session-bind@openssh.com";
+
+/// Sessions recorded per connection before further binds are refused, as by OpenSSH.
+pub const MAX_BOUND_SESSIONS : usize = 16;
+
+/// A host key a hop may present, or with `is_ca` the CA signing its host certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopKey {
+    pub key_blob: Vec<u8>,
+    pub is_ca: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    /// A user name pattern; only allowed on the destination.
+    pub user: Option<String>,
+    /// `None` on the source hop means the host running the agent.
+    pub hostname: Option<String>,
+    pub keys: Vec<HopKey>,
+}
+
+/// Permits a key to be used from one host to another, as in `ssh-add -h from>to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestinationConstraint {
+    pub from: Hop,
+    pub to: Hop,
+}
+
+/// A session the client connection has been bound to with `session-bind@openssh.com`.
+/// A connection's sessions run from the agent's host outward.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundSession {
+    pub host_key: Vec<u8>,
+    pub session_id: Vec<u8>,
+    /// Whether the session is forwarding the agent rather than authenticating.
+    pub forwarded: bool,
+}
+
+/// The `session-bind@openssh.com` extension, with which `ssh` tells the agent which
+/// host it is talking to: the server's host key signs the session identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionBind {
+    pub host_key: Vec<u8>,
+    pub session_id: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub forwarded: bool,
+}
+
+fn next<T>(cursor: &mut Cursor<&[u8]>) -> Result<T, Error> where T: for<'x> serde::Deserialize<'x> {
+    Deserializer::new(cursor).next()
+}
+
+fn remaining(cursor: &Cursor<&[u8]>) -> usize {
+    cursor.get_ref().len() - cursor.position() as usize
+}
+
+fn non_empty(s: String) -> Option<String> {
+    if s.len() > 0 { Some(s) } else { None }
+}
+
+impl SessionBind {
+    /// Binds `session_id` with a signature by the server's `host_key`.
+    pub fn sign(host_key: &PrivateKey, session_id: &[u8], forwarded: bool) -> Result<Self, Error> {
+        Ok(SessionBind{
+            host_key: host_key.public_key_bytes()?,
+            session_id: session_id.to_vec(),
+            signature: host_key.sign(session_id)?,
+            forwarded: forwarded,
+        })
+    }
+
+    /// Parses the extension contents following its name.
+    pub fn parse(contents: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(contents);
+        let bind = SessionBind{
+            host_key: next(&mut cursor)?,
+            session_id: next(&mut cursor)?,
+            signature: next(&mut cursor)?,
+            forwarded: match next::<u8>(&mut cursor)? {
+                0 => false,
+                1 => true,
+                _ => return Err(Error{kind: InvalidFormat}),
+            },
+        };
+        if remaining(&cursor) != 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(bind)
+    }
+
+    pub fn to_request(&self) -> Result<Request, Error> {
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&self.host_key)?;
+        ser.write(&self.session_id)?;
+        ser.write(&self.signature)?;
+        ser.write(&(self.forwarded as u8))?;
+        Ok(Request::Extension{name: SESSION_BIND.into(), contents: ser.into_inner()})
+    }
+
+    /// Whether the host key's signature over the session identifier is valid.
+    pub fn verify(&self) -> bool {
+        ::verify_signature(&self.host_key, &self.signature, &self.session_id)
+    }
+
+    /// Records the bind on a connection's `sessions` if its signature verifies. Like
+    /// OpenSSH, this refuses binds after one for authentication rather than forwarding,
+    /// and a known session identifier with a different host key.
+    pub fn record(&self, sessions: &mut Vec<BoundSession>) -> Result<(), Error> {
+        if !self.verify() {
+            return Err(Error{kind: Crypto});
+        }
+        for session in sessions.iter() {
+            if !session.forwarded {
+                return Err(Error{kind: InvalidFormat});
+            }
+            if session.session_id == self.session_id {
+                return match session.host_key == self.host_key {
+                    true => Ok(()),
+                    false => Err(Error{kind: InvalidKey}),
+                };
+            }
+        }
+        if sessions.len() >= MAX_BOUND_SESSIONS {
+            return Err(Error{kind: InvalidLength});
+        }
+        sessions.push(BoundSession{
+            host_key: self.host_key.clone(),
+            session_id: self.session_id.clone(),
+            forwarded: self.forwarded,
+        });
+        Ok(())
+    }
+}
+
+impl Hop {
+    pub fn new(hostname: &str, keys: Vec<HopKey>) -> Self {
+        Hop{
+            user: None,
+            hostname: non_empty(hostname.into()),
+            keys: keys,
+        }
+    }
+
+    /// The source hop for the host running the agent.
+    pub fn origin() -> Self {
+        Hop{
+            user: None,
+            hostname: None,
+            keys: vec![],
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(bytes);
+        let user : String = next(&mut cursor)?;
+        let hostname : String = next(&mut cursor)?;
+        let _reserved : Vec<u8> = next(&mut cursor)?;
+        let mut keys = vec![];
+        while remaining(&cursor) > 0 {
+            let key_blob : Vec<u8> = next(&mut cursor)?;
+            let is_ca = match next::<u8>(&mut cursor)? {
+                0 => false,
+                1 => true,
+                _ => return Err(Error{kind: InvalidFormat}),
+            };
+            keys.push(HopKey{key_blob: key_blob, is_ca: is_ca});
+        }
+        Ok(Hop{
+            user: non_empty(user),
+            hostname: non_empty(hostname),
+            keys: keys,
+        })
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(self.user.as_ref().map(|user| &user[..]).unwrap_or(""))?;
+        ser.write(self.hostname.as_ref().map(|hostname| &hostname[..]).unwrap_or(""))?;
+        ser.write(&Vec::<u8>::new())?;
+        for key in &self.keys {
+            ser.write(&key.key_blob)?;
+            ser.write(&(key.is_ca as u8))?;
+        }
+        Ok(ser.into_inner())
+    }
+
+    /// Whether `key_blob` is one of the hop's keys, or a valid host certificate for
+    /// the hop's hostname from one of its CAs.
+    pub fn matches_key(&self, key_blob: &[u8], time: u64) -> bool {
+        self.keys.iter().any(|key| {
+            if !key.is_ca {
+                return key.key_blob == key_blob;
+            }
+            let certificate = match Certificate::from_bytes(key_blob) {
+                Ok(certificate) => certificate,
+                _ => return false,
+            };
+            let hostname = match self.hostname {
+                Some(ref hostname) => hostname,
+                None => return false,
+            };
+            certificate.cert_type == CertType::Host
+                && certificate.signature_key.to_bytes().ok().as_ref() == Some(&key.key_blob)
+                && certificate.principals.iter().any(|principal| principal == hostname)
+                && certificate.is_valid_at(time)
+                && certificate.verify_signature()
+        })
+    }
+}
+
+impl DestinationConstraint {
+    /// Parses the details of a `restrict-destination-v00@openssh.com` constraint,
+    /// as they appear on the wire, rejecting hops OpenSSH would reject.
+    pub fn parse_list(details: &[u8]) -> Result<Vec<Self>, Error> {
+        let mut cursor = Cursor::new(details);
+        let list : Vec<u8> = next(&mut cursor)?;
+        if remaining(&cursor) != 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let mut cursor = Cursor::new(&list[..]);
+        let mut constraints = vec![];
+        while remaining(&cursor) > 0 {
+            let constraint : Vec<u8> = next(&mut cursor)?;
+            let mut fields = Cursor::new(&constraint[..]);
+            let from = Hop::parse(&next::<Vec<u8>>(&mut fields)?)?;
+            let to = Hop::parse(&next::<Vec<u8>>(&mut fields)?)?;
+            let _reserved : Vec<u8> = next(&mut fields)?;
+            if remaining(&fields) != 0 {
+                return Err(Error{kind: InvalidFormat});
+            }
+            if from.user.is_some() || (from.hostname.is_some() != (from.keys.len() > 0)) {
+                return Err(Error{kind: InvalidFormat});
+            }
+            if to.hostname.is_none() || to.keys.len() == 0 {
+                return Err(Error{kind: InvalidFormat});
+            }
+            constraints.push(DestinationConstraint{from: from, to: to});
+        }
+        Ok(constraints)
+    }
+
+    pub fn list_to_bytes(constraints: &[Self]) -> Result<Vec<u8>, Error> {
+        let mut list = Serializer::new(Vec::new());
+        for constraint in constraints {
+            let mut fields = Serializer::new(Vec::new());
+            fields.write(&constraint.from.to_bytes()?)?;
+            fields.write(&constraint.to.to_bytes()?)?;
+            fields.write(&Vec::<u8>::new())?;
+            list.write(&fields.into_inner())?;
+        }
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&list.into_inner())?;
+        Ok(ser.into_inner())
+    }
+
+    /// The constraint `ssh-add -h` sends for these destinations.
+    pub fn to_constraint(constraints: &[Self]) -> Result<Constraint, Error> {
+        Ok(Constraint::Extension(RESTRICT_DESTINATION.into(), DestinationConstraint::list_to_bytes(constraints)?))
+    }
+
+    //  A missing `from` key is the agent's host; a missing `to` key skips that hop's check.
+    fn permits(&self, from: Option<&[u8]>, to: Option<&[u8]>, user: Option<&str>, time: u64) -> bool {
+        let from_matches = match from {
+            None => self.from.hostname.is_none() && self.from.keys.len() == 0,
+            Some(from) => self.from.matches_key(from, time),
+        };
+        let to_matches = match to {
+            None => true,
+            Some(to) => self.to.matches_key(to, time),
+        };
+        let user_matches = match (user, self.to.user.as_ref()) {
+            (Some(user), Some(pattern)) => pattern::matches(user, pattern),
+            _ => true,
+        };
+        from_matches && to_matches && user_matches
+    }
+}
+
+/// The constraints in force on a key held by an agent.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct KeyConstraints {
+    /// Unix time at which the agent forgets the key.
+    pub expires: Option<u64>,
+    pub confirm: bool,
+    pub destinations: Vec<DestinationConstraint>,
+}
+
+impl KeyConstraints {
+    /// Interprets the constraints sent with a key at `now`. Repeated or unknown
+    /// constraints fail with `UnsupportedType`, so the key is refused, as by OpenSSH.
+    pub fn from_constraints(constraints: &[Constraint], now: u64) -> Result<Self, Error> {
+        let mut key_constraints = KeyConstraints::default();
+        let mut restricted = false;
+        for constraint in constraints {
+            match *constraint {
+                Constraint::Lifetime(seconds) if key_constraints.expires.is_none() => {
+                    key_constraints.expires = Some(now + seconds as u64);
+                },
+                Constraint::Confirm if !key_constraints.confirm => key_constraints.confirm = true,
+                Constraint::Extension(ref name, ref details) if name == RESTRICT_DESTINATION && !restricted => {
+                    key_constraints.destinations = DestinationConstraint::parse_list(details)?;
+                    restricted = true;
+                },
+                _ => return Err(Error{kind: UnsupportedType}),
+            }
+        }
+        Ok(key_constraints)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map(|expires| now >= expires).unwrap_or(false)
+    }
+
+    /// Whether each of the connection's `sessions` is a hop some destination
+    /// constraint permits, matching `user` against the final destination if given.
+    /// Unrestricted keys, and any key on a connection with no bound sessions, pass.
+    /// A `user` means authenticating, which is refused if the last session was
+    /// bound for forwarding, as by OpenSSH: only the host the agent was forwarded
+    /// to holds that session, and it may not authenticate to itself.
+    pub fn permits_path(&self, sessions: &[BoundSession], user: Option<&str>, time: u64) -> bool {
+        if self.destinations.len() == 0 || sessions.len() == 0 {
+            return true;
+        }
+        if user.is_some() && sessions.last().map(|last| last.forwarded).unwrap_or(false) {
+            return false;
+        }
+        let mut from = None;
+        for (i, session) in sessions.iter().enumerate() {
+            let user = if i == sessions.len() - 1 { user } else { None };
+            if !self.destinations.iter().any(|d| d.permits(from, Some(&session.host_key), user, time)) {
+                return false;
+            }
+            from = Some(&session.host_key[..]);
+        }
+        true
+    }
+
+    /// Whether to list the key to a client bound over `sessions`. Past a forwarding
+    /// session, keys only usable to authenticate to that host, not beyond it, are hidden.
+    pub fn permits_listing(&self, sessions: &[BoundSession], time: u64) -> bool {
+        if !self.permits_path(sessions, None, time) {
+            return false;
+        }
+        match sessions.last() {
+            Some(last) if last.forwarded && self.destinations.len() > 0 => {
+                self.destinations.iter().any(|d| d.permits(Some(&last.host_key), None, None, time))
+            },
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::{PrivateKey, KeyType};
+
+    fn host_key() -> Vec<u8> {
+        PrivateKey::generate(KeyType::Ed25519).unwrap().1
+    }
+
+    fn session(host_key: &[u8], forwarded: bool) -> BoundSession {
+        BoundSession{host_key: host_key.to_vec(), session_id: vec![1; 32], forwarded: forwarded}
+    }
+
+    #[test]
+    fn destinations_round_trip() {
+        let (bastion, target) = (host_key(), host_key());
+        let mut to = Hop::new("target", vec![HopKey{key_blob: target, is_ca: false}]);
+        to.user = Some("deploy".into());
+        let destinations = vec![
+            DestinationConstraint{from: Hop::origin(), to: Hop::new("bastion", vec![HopKey{key_blob: bastion.clone(), is_ca: false}])},
+            DestinationConstraint{from: Hop::new("bastion", vec![HopKey{key_blob: bastion, is_ca: false}]), to: to},
+        ];
+        let constraint = DestinationConstraint::to_constraint(&destinations).unwrap();
+        let constraints = KeyConstraints::from_constraints(&[Constraint::Lifetime(60), Constraint::Confirm, constraint], 1000).unwrap();
+        assert!(constraints.expires == Some(1060) && constraints.confirm);
+        assert!(constraints.destinations == destinations);
+        assert!(!constraints.is_expired(1059) && constraints.is_expired(1060));
+
+        match KeyConstraints::from_constraints(&[Constraint::Confirm, Constraint::Confirm], 0) {
+            Err(Error{kind: UnsupportedType}) => {},
+            _ => assert!("expected" == "UnsupportedType"),
+        }
+        //  The destination needs a host key.
+        let keyless = vec![DestinationConstraint{from: Hop::origin(), to: Hop::new("target", vec![])}];
+        let details = DestinationConstraint::list_to_bytes(&keyless).unwrap();
+        match DestinationConstraint::parse_list(&details) {
+            Err(Error{kind: InvalidFormat}) => {},
+            _ => assert!("expected" == "InvalidFormat"),
+        }
+    }
+
+    #[test]
+    fn hops_are_checked_in_order() {
+        let (bastion, target, other) = (host_key(), host_key(), host_key());
+        let mut to = Hop::new("target", vec![HopKey{key_blob: target.clone(), is_ca: false}]);
+        to.user = Some("deploy".into());
+        let constraints = KeyConstraints{
+            expires: None,
+            confirm: false,
+            destinations: vec![
+                DestinationConstraint{from: Hop::origin(), to: Hop::new("bastion", vec![HopKey{key_blob: bastion.clone(), is_ca: false}])},
+                DestinationConstraint{from: Hop::new("bastion", vec![HopKey{key_blob: bastion.clone(), is_ca: false}]), to: to},
+            ],
+        };
+        assert!(constraints.permits_path(&[], None, 0));
+        assert!(constraints.permits_path(&[session(&bastion, false)], Some("anyone"), 0));
+        assert!(!constraints.permits_path(&[session(&other, false)], None, 0));
+        let through = [session(&bastion, true), session(&target, false)];
+        assert!(constraints.permits_path(&through, Some("deploy"), 0));
+        assert!(!constraints.permits_path(&through, Some("root"), 0));
+        assert!(!constraints.permits_path(&[session(&target, false)], None, 0));
+
+        //  Forwarded to the bastion the key is listed, as it may go on to the target;
+        //  forwarded to the target it is not.
+        assert!(constraints.permits_listing(&[session(&bastion, true)], 0));
+        assert!(!constraints.permits_listing(&[session(&bastion, true), session(&target, true)], 0));
+    }
+
+    #[test]
+    fn session_binds_are_verified_and_recorded() {
+        let (bastion, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (target, _) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        let forward = SessionBind::sign(&bastion, &[1; 32], true).unwrap();
+        match forward.to_request().unwrap() {
+            Request::Extension{name, contents} => {
+                assert!(name == SESSION_BIND);
+                assert!(SessionBind::parse(&contents).unwrap() == forward);
+            },
+            _ => assert!("expected" == "Extension"),
+        }
+
+        let mut sessions = vec![];
+        forward.record(&mut sessions).unwrap();
+        forward.record(&mut sessions).unwrap();
+        let mut forged = SessionBind::sign(&target, &[2; 32], false).unwrap();
+        forged.session_id = vec![3; 32];
+        match forged.record(&mut sessions) {
+            Err(Error{kind: Crypto}) => {},
+            _ => assert!("expected" == "Crypto"),
+        }
+        let mut reused = SessionBind::sign(&target, &[1; 32], false).unwrap();
+        assert!(reused.record(&mut sessions).is_err());
+        reused = SessionBind::sign(&target, &[2; 32], false).unwrap();
+        reused.record(&mut sessions).unwrap();
+        assert!(sessions.len() == 2 && sessions[0].forwarded && !sessions[1].forwarded);
+        //  Nothing may be bound after a session used for authentication.
+        assert!(SessionBind::sign(&bastion, &[4; 32], true).unwrap().record(&mut sessions).is_err());
+    }
+
+    #[test]
+    fn hop_accepts_host_certificates() {
+        let (ca, ca_blob) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (host, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let mut certificate = Certificate::new(host.public_key(), CertType::Host, "host").unwrap();
+        certificate.principals = vec!["target".into()];
+        certificate.valid_after = 100;
+        certificate.valid_before = 200;
+        certificate.sign(&ca).unwrap();
+        let cert_blob = certificate.to_bytes().unwrap();
+
+        let hop = Hop::new("target", vec![HopKey{key_blob: ca_blob.clone(), is_ca: true}]);
+        assert!(hop.matches_key(&cert_blob, 150));
+        assert!(!hop.matches_key(&cert_blob, 200));
+        assert!(!hop.matches_key(&host.public_key_bytes().unwrap(), 150));
+        assert!(!Hop::new("other", vec![HopKey{key_blob: ca_blob, is_ca: true}]).matches_key(&cert_blob, 150));
+    }
+}
@@ -0,0 +1,661 @@
+use agent::*;
+use agent_constraints::{BoundSession, KeyConstraints, SessionBind, SESSION_BIND};
+use cipher::constant_time_eq;
+use hex;
+use host_key_store::unix_time;
+use private_key::PrivateKey;
+use public_key::PublicKey;
+use rsa::{RSA_TYPE, RSA_SHA2_256, RSA_SHA2_512};
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use userauth::UserauthSignedData;
+
+use rand::{OsRng, Rng};
+use ring::digest;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+//  An ssh-agent serving keys from a pluggable store over a Unix socket. The
+//  server decodes requests and handles locking; the store lists keys and signs.
+
+/// The keys an `AgentServer` exposes. Operations a store does not support fail,
+/// which the server reports to the client as `Response::Failure`. `sessions` are
+/// those the requesting connection is bound to, for enforcing destination constraints.
+pub trait KeyStore {
+    fn identities(&mut self, sessions: &[BoundSession]) -> Result<Vec<Identity>, Error>;
+
+    /// Signs `data` with the key for `key_blob`, returning a wire-format signature
+    /// blob. `flags` select the RSA signature algorithm; see `signature_algorithm`.
+    /// `confirmed` is whether the user allowed this use through `confirmation`.
+    fn sign(&mut self, key_blob: &[u8], data: &[u8], flags: u32, sessions: &[BoundSession], confirmed: bool) -> Result<Vec<u8>, Error>;
+
+    /// The prompt to run before signing `data` with a key whose every use must be
+    /// confirmed, or `None` if no confirmation is needed.
+    fn confirmation(&mut self, _key_blob: &[u8], _data: &[u8], _sessions: &[BoundSession]) -> Option<Confirmation> {
+        None
+    }
+
+    fn add_identity(&mut self, _private_key: PrivateKey, _comment: String, _constraints: Vec<Constraint>) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn remove_identity(&mut self, _key_blob: &[u8]) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn remove_all_identities(&mut self) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    /// Handles an extension request; unknown extensions should answer `Failure`.
+    fn extension(&mut self, _name: &str, _contents: &[u8]) -> Result<Response, Error> {
+        Ok(Response::Failure)
+    }
+
+    /// Forgets keys whose lifetime ended by `now`, returning when the next one
+    /// ends. `AgentServer::run` calls it again at that time.
+    fn expire(&mut self, _now: u64) -> Option<u64> {
+        None
+    }
+}
+
+/// The signature algorithm a sign request asks for. RSA keys default to legacy
+/// `ssh-rsa`, which `PrivateKey` cannot produce, so such requests fail.
+pub fn signature_algorithm(private_key: &PrivateKey, flags: u32) -> String {
+    match *private_key {
+        PrivateKey::RSA(_) if flags & SSH_AGENT_RSA_SHA2_512 != 0 => RSA_SHA2_512.into(),
+        PrivateKey::RSA(_) if flags & SSH_AGENT_RSA_SHA2_256 != 0 => RSA_SHA2_256.into(),
+        PrivateKey::RSA(_) => RSA_TYPE.into(),
+        _ => private_key.key_type(),
+    }
+}
+
+pub struct StoredKey {
+    pub private_key: PrivateKey,
+    pub comment: String,
+    pub constraints: KeyConstraints,
+}
+
+/// Asked whether to allow each use of a key added with the confirm constraint.
+pub type ConfirmCallback = Box<dyn FnMut(&PublicKey, &str) -> bool + Send>;
+
+/// A prompt to confirm one use of a key. The server runs it without holding its
+/// lock, so a prompt waiting on the user does not block other connections.
+pub type Confirmation = Box<dyn FnOnce() -> bool + Send>;
+
+/// Keys held in memory, as added by clients with `ssh-add`. An expired key is
+/// never listed or used, and is dropped when its lifetime ends by the timer
+/// `AgentServer::run` keeps; without one, by the next request.
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    pub keys: Vec<StoredKey>,
+    confirm: Option<Arc<Mutex<ConfirmCallback>>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        MemoryKeyStore{
+            keys: vec![],
+            confirm: None,
+        }
+    }
+
+    /// Without a callback, keys requiring confirmation cannot sign.
+    pub fn set_confirm(&mut self, confirm: ConfirmCallback) {
+        self.confirm = Some(Arc::new(Mutex::new(confirm)));
+    }
+
+    pub fn remove_expired(&mut self, now: u64) {
+        self.keys.retain(|key| !key.constraints.is_expired(now));
+    }
+
+    fn position(&self, key_blob: &[u8]) -> Option<usize> {
+        self.keys.iter().position(|key| {
+            key.private_key.public_key_bytes().ok().as_ref().map(|blob| &blob[..]) == Some(key_blob)
+        })
+    }
+
+    //  Destination-constrained keys only sign userauth requests on connections bound
+    //  to a session, for the session and host bound last, as OpenSSH's agent does.
+    fn permitted(&mut self, key_blob: &[u8], data: &[u8], sessions: &[BoundSession]) -> Result<&StoredKey, Error> {
+        let now = unix_time();
+        self.remove_expired(now);
+        let key = &self.keys[self.position(key_blob).ok_or(Error{kind: InvalidKey})?];
+        let constraints = &key.constraints;
+        if constraints.destinations.len() > 0 {
+            let permitted = match (UserauthSignedData::parse(data), sessions.last()) {
+                (Ok(request), Some(last)) => {
+                    request.public_key == key_blob
+                        && request.session_id == last.session_id
+                        && (request.host_key.is_some() || sessions.len() == 1)
+                        && request.host_key.as_ref().map(|host_key| *host_key == last.host_key).unwrap_or(true)
+                        && constraints.permits_path(sessions, Some(&request.user), now)
+                },
+                _ => false,
+            };
+            if !permitted {
+                return Err(Error{kind: InvalidKey});
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn identities(&mut self, sessions: &[BoundSession]) -> Result<Vec<Identity>, Error> {
+        let now = unix_time();
+        self.remove_expired(now);
+        self.keys.iter()
+            .filter(|key| key.constraints.permits_listing(sessions, now))
+            .map(|key| Identity::new(&key.private_key.public_key(), &key.comment))
+            .collect()
+    }
+
+    fn sign(&mut self, key_blob: &[u8], data: &[u8], flags: u32, sessions: &[BoundSession], confirmed: bool) -> Result<Vec<u8>, Error> {
+        let key = self.permitted(key_blob, data, sessions)?;
+        if key.constraints.confirm && !confirmed {
+            return Err(Error{kind: InvalidKey});
+        }
+        key.private_key.sign_with_algorithm(data, &signature_algorithm(&key.private_key, flags))
+    }
+
+    //  Without a callback, the prompt refuses. Requests the key may not sign anyway
+    //  are not prompted for.
+    fn confirmation(&mut self, key_blob: &[u8], data: &[u8], sessions: &[BoundSession]) -> Option<Confirmation> {
+        let confirm = self.confirm.clone();
+        let key = match self.permitted(key_blob, data, sessions) {
+            Ok(key) if key.constraints.confirm => key,
+            _ => return None,
+        };
+        let public_key = key.private_key.public_key();
+        let comment = key.comment.clone();
+        Some(Box::new(move || match confirm {
+            Some(confirm) => (*confirm.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))(&public_key, &comment),
+            None => false,
+        }))
+    }
+
+    fn add_identity(&mut self, private_key: PrivateKey, comment: String, constraints: Vec<Constraint>) -> Result<(), Error> {
+        let key = StoredKey{
+            constraints: KeyConstraints::from_constraints(&constraints, unix_time())?,
+            private_key: private_key,
+            comment: comment,
+        };
+        match self.position(&key.private_key.public_key_bytes()?) {
+            Some(i) => self.keys[i] = key,
+            None => self.keys.push(key),
+        }
+        Ok(())
+    }
+
+    fn remove_identity(&mut self, key_blob: &[u8]) -> Result<(), Error> {
+        let i = self.position(key_blob).ok_or(Error{kind: InvalidKey})?;
+        self.keys.remove(i);
+        Ok(())
+    }
+
+    fn remove_all_identities(&mut self) -> Result<(), Error> {
+        self.keys.clear();
+        Ok(())
+    }
+
+    fn expire(&mut self, now: u64) -> Option<u64> {
+        self.remove_expired(now);
+        self.keys.iter().filter_map(|key| key.constraints.expires).min()
+    }
+}
+
+struct State<K> {
+    key_store: K,
+    //  The salt and salted hash of the lock passphrase while locked.
+    lock: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn passphrase_hash(salt: &[u8], passphrase: &[u8]) -> Vec<u8> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(salt);
+    context.update(passphrase);
+    context.finish().as_ref().to_vec()
+}
+
+/// Serves a `KeyStore` to agent clients. Clones share the store, so connections
+/// may be served concurrently.
+pub struct AgentServer<K> {
+    state: Arc<Mutex<State<K>>>,
+    //  Signalled when a key is added, so the expiry timer can wait for it.
+    added: Arc<Condvar>,
+}
+
+impl<K> Clone for AgentServer<K> {
+    fn clone(&self) -> Self {
+        AgentServer{
+            state: self.state.clone(),
+            added: self.added.clone(),
+        }
+    }
+}
+
+impl<K: KeyStore> AgentServer<K> {
+    pub fn new(key_store: K) -> Self {
+        AgentServer{
+            state: Arc::new(Mutex::new(State{
+                key_store: key_store,
+                lock: None,
+            })),
+            added: Arc::new(Condvar::new()),
+        }
+    }
+
+    //  A panic in the key store poisons the mutex; recover it rather than failing
+    //  every later request on every connection.
+    fn state(&self) -> MutexGuard<'_, State<K>> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs `f` with exclusive access to the key store.
+    pub fn with_key_store<T, F: FnOnce(&mut K) -> T>(&self, f: F) -> T {
+        f(&mut self.state().key_store)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state().lock.is_some()
+    }
+
+    /// Answers one request from a connection with no bound sessions.
+    pub fn handle(&self, request: Request) -> Response {
+        self.handle_bound(&mut vec![], request)
+    }
+
+    /// Answers one request from a connection bound to `sessions`, recording any
+    /// `session-bind@openssh.com` it makes. While locked, the agent lists no keys
+    /// and refuses everything but `Unlock`, like OpenSSH's ssh-agent.
+    pub fn handle_bound(&self, sessions: &mut Vec<BoundSession>, request: Request) -> Response {
+        let mut state = self.state();
+        if state.lock.is_some() {
+            return match request {
+                Request::RequestIdentities => Response::IdentitiesAnswer(vec![]),
+                Request::Unlock{passphrase} => {
+                    let unlocked = match state.lock {
+                        Some((ref salt, ref hash)) => constant_time_eq(&passphrase_hash(salt, &passphrase), hash),
+                        None => false,
+                    };
+                    if unlocked {
+                        state.lock = None;
+                        Response::Success
+                    } else {
+                        Response::Failure
+                    }
+                },
+                _ => Response::Failure,
+            };
+        }
+
+        let result = match request {
+            Request::RequestIdentities => state.key_store.identities(sessions).map(Response::IdentitiesAnswer),
+            Request::SignRequest{key_blob, data, flags} => self.sign(state, sessions, &key_blob, &data, flags),
+            Request::AddIdentity{private_key, comment, constraints} => {
+                let result = state.key_store.add_identity(private_key, comment, constraints);
+                self.added.notify_all();
+                result.map(|_| Response::Success)
+            },
+            Request::RemoveIdentity{key_blob} => {
+                state.key_store.remove_identity(&key_blob).map(|_| Response::Success)
+            },
+            Request::RemoveAllIdentities => state.key_store.remove_all_identities().map(|_| Response::Success),
+            Request::Lock{passphrase} => {
+                OsRng::new().map_err(Error::from).map(|mut rng| {
+                    let mut salt = vec![0; 16];
+                    rng.fill_bytes(&mut salt);
+                    let hash = passphrase_hash(&salt, &passphrase);
+                    state.lock = Some((salt, hash));
+                    Response::Success
+                })
+            },
+            Request::Extension{ref name, ref contents} if name == SESSION_BIND => {
+                SessionBind::parse(contents).and_then(|bind| bind.record(sessions)).map(|_| Response::Success)
+            },
+            Request::Extension{name, contents} => state.key_store.extension(&name, &contents),
+            Request::Unlock{..} | Request::AddSmartcardKey{..} | Request::RemoveSmartcardKey{..} => {
+                Ok(Response::Failure)
+            },
+        };
+        result.unwrap_or(Response::Failure)
+    }
+
+    //  Prompts for confirmation with the lock released, then signs unless the agent
+    //  was locked meanwhile.
+    fn sign<'a>(&'a self, mut state: MutexGuard<'a, State<K>>, sessions: &[BoundSession], key_blob: &[u8], data: &[u8], flags: u32) -> Result<Response, Error> {
+        let confirmed = match state.key_store.confirmation(key_blob, data, sessions) {
+            Some(confirmation) => {
+                drop(state);
+                let confirmed = confirmation();
+                state = self.state();
+                if state.lock.is_some() {
+                    return Ok(Response::Failure);
+                }
+                confirmed
+            },
+            None => false,
+        };
+        state.key_store.sign(key_blob, data, flags, sessions, confirmed).map(Response::SignResponse)
+    }
+
+    //  Forgets each key as its lifetime ends, sleeping until the next expiry or
+    //  until a key is added.
+    fn expire_keys(&self) {
+        let mut state = self.state();
+        loop {
+            let now = unix_time();
+            state = match state.key_store.expire(now) {
+                Some(expires) => {
+                    let timeout = Duration::from_secs(expires.saturating_sub(now).max(1));
+                    self.added.wait_timeout(state, timeout).map(|(state, _)| state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner().0)
+                },
+                None => self.added.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner()),
+            };
+        }
+    }
+
+    /// Serves requests on one connection until the client closes it. Requests that
+    /// fail to decode are answered with `Failure`; malformed framing ends the connection.
+    pub fn serve<S: Read + Write>(&self, mut stream: S) -> Result<(), Error> {
+        let mut sessions = vec![];
+        loop {
+            let message = match read_message(&mut stream) {
+                Ok(message) => message,
+                Err(Error{kind: Io(ref err)}) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let response = match Request::from_bytes(&message) {
+                Ok(request) => self.handle_bound(&mut sessions, request),
+                Err(_) => Response::Failure,
+            };
+            response.write_to(&mut stream)?;
+        }
+    }
+}
+
+impl<K: KeyStore + Send + 'static> AgentServer<K> {
+    /// Binds a socket at `path` that only the current user may connect to. Like
+    /// ssh-agent, it is made in a new directory only the user can enter, so that
+    /// no one can connect before its mode is set, and then linked into place.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<UnixListener, Error> {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        let mut suffix = [0; 8];
+        OsRng::new()?.fill_bytes(&mut suffix);
+        let dir = parent.join(format!(".agent-{}", hex::encode(&suffix)));
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let socket = dir.join("agent.sock");
+        //  Unlike a rename, the link fails rather than replace an existing socket.
+        let listener = UnixListener::bind(&socket)
+            .and_then(|listener| fs::set_permissions(&socket, fs::Permissions::from_mode(0o600)).map(|_| listener))
+            .and_then(|listener| fs::hard_link(&socket, path).map(|_| listener));
+        let _ = fs::remove_file(&socket);
+        let _ = fs::remove_dir(&dir);
+        Ok(listener?)
+    }
+
+    /// Accepts connections forever, serving each on its own thread, while another
+    /// drops keys as their lifetimes end.
+    pub fn run(&self, listener: UnixListener) -> Result<(), Error> {
+        let timer = self.clone();
+        thread::spawn(move || timer.expire_keys());
+        for stream in listener.incoming() {
+            let stream : UnixStream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                let _ = server.serve(stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::KeyType;
+    use public_key::PublicKey;
+    use ssh::Signature;
+    use serde_de;
+    use std::env;
+    use std::process;
+
+    fn request(stream: &mut UnixStream, request: Request) -> Response {
+        request.write_to(stream).unwrap();
+        Response::read_from(stream).unwrap()
+    }
+
+    #[test]
+    fn sign_flags_choose_rsa_algorithm() {
+        let (rsa, _) = PrivateKey::generate(KeyType::Rsa(2048)).unwrap();
+        let (ed25519, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        assert!(signature_algorithm(&rsa, SSH_AGENT_RSA_SHA2_256) == RSA_SHA2_256);
+        assert!(signature_algorithm(&rsa, SSH_AGENT_RSA_SHA2_512) == RSA_SHA2_512);
+        assert!(signature_algorithm(&rsa, 0) == RSA_TYPE);
+        assert!(signature_algorithm(&ed25519, SSH_AGENT_RSA_SHA2_256) == "ssh-ed25519");
+
+        let server = AgentServer::new(MemoryKeyStore::new());
+        let key_blob = rsa.public_key_bytes().unwrap();
+        let add = Request::AddIdentity{private_key: rsa, comment: "rsa".into(), constraints: vec![]};
+        assert!(server.handle(add) == Response::Success);
+        for &(flags, algorithm) in &[(SSH_AGENT_RSA_SHA2_256, RSA_SHA2_256), (SSH_AGENT_RSA_SHA2_512, RSA_SHA2_512)] {
+            match server.handle(Request::SignRequest{key_blob: key_blob.clone(), data: b"data".to_vec(), flags: flags}) {
+                Response::SignResponse(blob) => {
+                    let signature : Signature = serde_de::from_slice(&blob).unwrap();
+                    assert!(signature._type == algorithm);
+                    assert!(::verify_signature(&key_blob, &blob, b"data"));
+                },
+                _ => assert!("expected" == "SignResponse"),
+            }
+        }
+        let legacy = Request::SignRequest{key_blob: key_blob, data: b"data".to_vec(), flags: 0};
+        assert!(server.handle(legacy) == Response::Failure);
+    }
+
+    #[test]
+    fn lock_hides_keys_until_unlocked() {
+        let (private_key, key_blob) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let server = AgentServer::new(MemoryKeyStore::new());
+        server.with_key_store(|store| store.add_identity(private_key, "ed25519".into(), vec![])).unwrap();
+        assert!(server.handle(Request::Lock{passphrase: b"secret".to_vec()}) == Response::Success);
+        assert!(server.is_locked());
+        assert!(server.handle(Request::RequestIdentities) == Response::IdentitiesAnswer(vec![]));
+        let sign = Request::SignRequest{key_blob: key_blob, data: b"data".to_vec(), flags: 0};
+        assert!(server.handle(sign.clone()) == Response::Failure);
+        assert!(server.handle(Request::Unlock{passphrase: b"wrong".to_vec()}) == Response::Failure);
+        assert!(server.handle(Request::Unlock{passphrase: b"secret".to_vec()}) == Response::Success);
+        match server.handle(sign) {
+            Response::SignResponse(_) => {},
+            _ => assert!("expected" == "SignResponse"),
+        }
+    }
+
+    #[test]
+    fn constraints_are_enforced() {
+        use agent_constraints::{DestinationConstraint, Hop, HopKey};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let mut store = MemoryKeyStore::new();
+        let allow = Arc::new(AtomicBool::new(false));
+        let answer = allow.clone();
+        store.set_confirm(Box::new(move |_, comment| comment == "confirm" && answer.load(Ordering::SeqCst)));
+        let server = AgentServer::new(store);
+
+        let (expiring, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let add = Request::AddIdentity{private_key: expiring, comment: "expiring".into(), constraints: vec![Constraint::Lifetime(0)]};
+        assert!(server.handle(add) == Response::Success);
+        assert!(server.handle(Request::RequestIdentities) == Response::IdentitiesAnswer(vec![]));
+
+        let (confirmed, key_blob) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let add = Request::AddIdentity{private_key: confirmed, comment: "confirm".into(), constraints: vec![Constraint::Confirm]};
+        assert!(server.handle(add) == Response::Success);
+        let sign = Request::SignRequest{key_blob: key_blob, data: b"data".to_vec(), flags: 0};
+        assert!(server.handle(sign.clone()) == Response::Failure);
+        allow.store(true, Ordering::SeqCst);
+        match server.handle(sign) {
+            Response::SignResponse(_) => {},
+            _ => assert!("expected" == "SignResponse"),
+        }
+
+        //  Destination-constrained keys are listed locally but sign only for bound sessions.
+        let (restricted, key_blob) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (host, host_key) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let to = Hop::new("host", vec![HopKey{key_blob: host_key, is_ca: false}]);
+        let constraint = DestinationConstraint::to_constraint(&[DestinationConstraint{from: Hop::origin(), to: to}]).unwrap();
+        let add = Request::AddIdentity{private_key: restricted, comment: "restricted".into(), constraints: vec![constraint]};
+        assert!(server.handle(add) == Response::Success);
+        match server.handle(Request::RequestIdentities) {
+            Response::IdentitiesAnswer(identities) => assert!(identities.len() == 2),
+            _ => assert!("expected" == "IdentitiesAnswer"),
+        }
+        assert!(server.handle(Request::SignRequest{key_blob: key_blob.clone(), data: b"data".to_vec(), flags: 0}) == Response::Failure);
+
+        //  Once bound to the host, it signs for that session only.
+        let mut sessions = vec![];
+        let bind = SessionBind::sign(&host, &[7; 32], false).unwrap().to_request().unwrap();
+        assert!(server.handle_bound(&mut sessions, bind) == Response::Success);
+        let mut userauth = UserauthSignedData{
+            session_id: vec![7; 32],
+            user: "deploy".into(),
+            service: "ssh-connection".into(),
+            method: "publickey-hostbound-v00@openssh.com".into(),
+            algorithm: "ssh-ed25519".into(),
+            public_key: key_blob.clone(),
+            host_key: Some(host.public_key_bytes().unwrap()),
+        };
+        let sign = Request::SignRequest{key_blob: key_blob.clone(), data: userauth.to_bytes().unwrap(), flags: 0};
+        match server.handle_bound(&mut sessions, sign) {
+            Response::SignResponse(_) => {},
+            _ => assert!("expected" == "SignResponse"),
+        }
+
+        //  Bound for forwarding, the session is the hop's own, and may not be signed for.
+        let mut forwarded = vec![];
+        let bind = SessionBind::sign(&host, &[7; 32], true).unwrap().to_request().unwrap();
+        assert!(server.handle_bound(&mut forwarded, bind) == Response::Success);
+        let sign = Request::SignRequest{key_blob: key_blob.clone(), data: userauth.to_bytes().unwrap(), flags: 0};
+        assert!(server.handle_bound(&mut forwarded, sign) == Response::Failure);
+
+        userauth.session_id = vec![8; 32];
+        let sign = Request::SignRequest{key_blob: key_blob.clone(), data: userauth.to_bytes().unwrap(), flags: 0};
+        assert!(server.handle_bound(&mut sessions, sign) == Response::Failure);
+        let sign = Request::SignRequest{key_blob: key_blob, data: b"not userauth".to_vec(), flags: 0};
+        assert!(server.handle_bound(&mut sessions, sign) == Response::Failure);
+
+        let (unknown, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let extension = Constraint::Extension("sk-provider@openssh.com".into(), vec![0, 0, 0, 0]);
+        let add = Request::AddIdentity{private_key: unknown, comment: "unknown".into(), constraints: vec![extension]};
+        assert!(server.handle(add) == Response::Failure);
+    }
+
+    #[test]
+    fn expired_keys_are_dropped_without_requests() {
+        let server = AgentServer::new(MemoryKeyStore::new());
+        let timer = server.clone();
+        thread::spawn(move || timer.expire_keys());
+        let (private_key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let add = Request::AddIdentity{private_key: private_key, comment: "short".into(), constraints: vec![Constraint::Lifetime(1)]};
+        assert!(server.handle(add) == Response::Success);
+        assert!(server.with_key_store(|store| store.keys.len()) == 1);
+        thread::sleep(Duration::from_millis(2500));
+        assert!(server.with_key_store(|store| store.keys.len()) == 0);
+    }
+
+    #[test]
+    fn confirmation_does_not_block_other_requests() {
+        use std::sync::mpsc::channel;
+
+        let (prompting, prompted) = channel();
+        let (answer, answered) = channel();
+        let mut store = MemoryKeyStore::new();
+        store.set_confirm(Box::new(move |_, _| {
+            prompting.send(()).unwrap();
+            answered.recv().unwrap()
+        }));
+        let server = AgentServer::new(store);
+        let (private_key, key_blob) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let add = Request::AddIdentity{private_key: private_key, comment: "confirm".into(), constraints: vec![Constraint::Confirm]};
+        assert!(server.handle(add) == Response::Success);
+
+        let signing = server.clone();
+        let sign = Request::SignRequest{key_blob: key_blob, data: b"data".to_vec(), flags: 0};
+        let signer = thread::spawn(move || signing.handle(sign));
+        prompted.recv().unwrap();
+        match server.handle(Request::RequestIdentities) {
+            Response::IdentitiesAnswer(identities) => assert!(identities.len() == 1),
+            _ => assert!("expected" == "IdentitiesAnswer"),
+        }
+        answer.send(true).unwrap();
+        match signer.join().unwrap() {
+            Response::SignResponse(_) => {},
+            _ => assert!("expected" == "SignResponse"),
+        }
+    }
+
+    #[test]
+    fn store_panic_does_not_poison_server() {
+        struct Panicking;
+        impl KeyStore for Panicking {
+            fn identities(&mut self, _sessions: &[BoundSession]) -> Result<Vec<Identity>, Error> {
+                Ok(vec![])
+            }
+            fn sign(&mut self, _key_blob: &[u8], _data: &[u8], _flags: u32, _sessions: &[BoundSession], _confirmed: bool) -> Result<Vec<u8>, Error> {
+                panic!("store bug")
+            }
+        }
+        let server = AgentServer::new(Panicking);
+        let panicking = server.clone();
+        let sign = Request::SignRequest{key_blob: vec![], data: vec![], flags: 0};
+        assert!(thread::spawn(move || panicking.handle(sign)).join().is_err());
+        assert!(server.handle(Request::RequestIdentities) == Response::IdentitiesAnswer(vec![]));
+        assert!(!server.is_locked());
+    }
+
+    #[test]
+    fn serves_unix_socket() {
+        let path = env::temp_dir().join(format!("ssh-wire-agent-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = AgentServer::<MemoryKeyStore>::bind(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().permissions().mode() & 0o777 == 0o600);
+        assert!(AgentServer::<MemoryKeyStore>::bind(&path).is_err());
+        let server = AgentServer::new(MemoryKeyStore::new());
+        let running = server.clone();
+        thread::spawn(move || running.run(listener));
+
+        let (private_key, key_blob) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        let public_key = private_key.public_key();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let add = Request::AddIdentity{private_key: private_key, comment: "ecdsa".into(), constraints: vec![]};
+        assert!(request(&mut stream, add) == Response::Success);
+        let identities = match request(&mut stream, Request::RequestIdentities) {
+            Response::IdentitiesAnswer(identities) => identities,
+            _ => vec![],
+        };
+        assert!(identities == vec![Identity::new(&public_key, "ecdsa").unwrap()]);
+        let sign = Request::SignRequest{key_blob: key_blob.clone(), data: b"data".to_vec(), flags: 0};
+        match request(&mut stream, sign) {
+            Response::SignResponse(blob) => assert!(::verify_signature(&key_blob, &blob, b"data")),
+            _ => assert!("expected" == "SignResponse"),
+        }
+
+        //  Unknown message types are refused without closing the connection.
+        write_message(&mut stream, &[99]).unwrap();
+        assert!(Response::read_from(&mut stream).unwrap() == Response::Failure);
+        let remove = Request::RemoveIdentity{key_blob: PublicKey::from_bytes(&key_blob).unwrap().to_bytes().unwrap()};
+        assert!(request(&mut stream, remove) == Response::Success);
+        assert!(request(&mut stream, Request::RequestIdentities) == Response::IdentitiesAnswer(vec![]));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,461 @@
+use authorized_keys::{dequote, split_unquoted};
+use certificate::{Certificate, CertType};
+use public_key::PublicKey;
+use sshsig::SshSig;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use pattern;
+
+use std::fmt;
+use std::io::Read;
+
+//  ssh-keygen's allowed_signers format for `-Y verify`: a principal pattern list,
+//  optional options and a key. Principals and namespaces match case-sensitively.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignerOption {
+    CertAuthority,
+    /// Comma-separated namespace patterns the key may sign for.
+    Namespaces(String),
+    /// A time as `YYYYMMDD[HHMM[SS]]`, optionally followed by `Z`; see `parse_time`.
+    ValidAfter(String),
+    ValidBefore(String),
+}
+
+impl SignerOption {
+    fn parse(option: &str) -> Result<SignerOption, Error> {
+        let mut parts = option.splitn(2, '=');
+        let name = parts.next().unwrap_or("").to_ascii_lowercase();
+        let value = match parts.next() {
+            Some(quoted) => Some(dequote(quoted)?),
+            None => None,
+        };
+        match (name.as_ref(), value) {
+            ("cert-authority", None) => Ok(SignerOption::CertAuthority),
+            ("namespaces", Some(value)) => Ok(SignerOption::Namespaces(value)),
+            ("valid-after", Some(value)) => {
+                parse_time(&value)?;
+                Ok(SignerOption::ValidAfter(value))
+            },
+            ("valid-before", Some(value)) => {
+                parse_time(&value)?;
+                Ok(SignerOption::ValidBefore(value))
+            },
+            _ => Err(Error{kind: InvalidFormat}),
+        }
+    }
+}
+
+impl fmt::Display for SignerOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, value) = match *self {
+            SignerOption::CertAuthority => return write!(f, "cert-authority"),
+            SignerOption::Namespaces(ref value) => ("namespaces", value),
+            SignerOption::ValidAfter(ref value) => ("valid-after", value),
+            SignerOption::ValidBefore(ref value) => ("valid-before", value),
+        };
+        write!(f, "{}=\"{}\"", name, value.replace("\"", "\\\""))
+    }
+}
+
+/// Parses ssh-keygen's absolute time format, `YYYYMMDD`, `YYYYMMDDHHMM` or
+/// `YYYYMMDDHHMMSS` with an optional trailing `Z`, to Unix seconds. ssh-keygen
+/// reads times without the `Z` in the local time zone; here they are read as UTC
+/// too, so that the result does not depend on the process environment.
+pub fn parse_time(s: &str) -> Result<u64, Error> {
+    let digits = if s.ends_with('Z') || s.ends_with('z') { &s[..s.len() - 1] } else { s };
+    if !digits.chars().all(|c| c.is_ascii_digit()) || ![8, 12, 14].contains(&digits.len()) {
+        return Err(Error{kind: InvalidFormat});
+    }
+    let field = |from: usize, len: usize| -> u64 {
+        if digits.len() >= from + len { digits[from..from + len].parse().unwrap_or(0) } else { 0 }
+    };
+    let (year, month, day) = (field(0, 4), field(4, 2), field(6, 2));
+    let (hour, minute, second) = (field(8, 2), field(10, 2), field(12, 2));
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > month_days ||
+        hour > 23 || minute > 59 || second > 59 {
+        return Err(Error{kind: InvalidFormat});
+    }
+    Ok(days_from_epoch(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+//  Days from 1970-01-01 to a date in the proleptic Gregorian calendar, counting
+//  years from March so that the leap day falls at the end.
+fn days_from_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[derive(Clone)]
+pub struct AllowedSigner {
+    /// Principal patterns, such as `*@example.com`; `!` negates.
+    pub principals: Vec<String>,
+    pub options: Vec<SignerOption>,
+    pub public_key: PublicKey,
+    pub comment: String,
+    /// The line as read, written back verbatim; set to `None` after editing
+    /// the fields to render them instead.
+    pub source: Option<String>,
+}
+
+impl AllowedSigner {
+    pub fn new(principals: Vec<String>, options: Vec<SignerOption>, public_key: PublicKey) -> Self {
+        AllowedSigner{
+            principals: principals,
+            options: options,
+            public_key: public_key,
+            comment: String::new(),
+            source: None,
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let trimmed = line.trim();
+        //  The principal list may be quoted, and is followed either by a bare
+        //  key or by options and then a key.
+        let (principals, rest) = split_unquoted(trimmed, |c| c == ' ' || c == '\t');
+        let principals = if principals.starts_with('"') { dequote(principals)? } else { principals.into() };
+        let principals : Vec<String> = principals.split(',').map(|p| p.to_string()).collect();
+        if principals.iter().any(|p| p.len() == 0) {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let rest = rest.trim_start();
+        let (options, key) = match PublicKey::from_openssh_line(rest) {
+            Ok(key) => (vec![], key),
+            Err(_) => {
+                let (options, rest) = split_unquoted(rest, |c| c == ' ' || c == '\t');
+                let mut parsed = vec![];
+                let mut remaining = options;
+                while remaining.len() > 0 {
+                    let (option, rest) = split_unquoted(remaining, |c| c == ',');
+                    parsed.push(SignerOption::parse(option)?);
+                    remaining = if rest.len() > 0 { &rest[1..] } else { rest };
+                }
+                (parsed, PublicKey::from_openssh_line(rest)?)
+            },
+        };
+        Ok(AllowedSigner{
+            principals: principals,
+            options: options,
+            public_key: key.0,
+            comment: key.1,
+            source: Some(line.into()),
+        })
+    }
+
+    pub fn is_cert_authority(&self) -> bool {
+        self.options.contains(&SignerOption::CertAuthority)
+    }
+
+    pub fn matches_principal(&self, principal: &str) -> bool {
+        pattern::matches_list_exact(principal, &self.principals) == Some(true)
+    }
+
+    /// Whether the key may sign for `namespace`; without a `namespaces` option it may sign for any.
+    pub fn allows_namespace(&self, namespace: &str) -> bool {
+        self.options.iter().all(|option| match *option {
+            SignerOption::Namespaces(ref namespaces) => {
+                pattern::matches_list_exact(namespace, namespaces.split(',')) == Some(true)
+            },
+            _ => true,
+        })
+    }
+
+    /// Whether `time` (Unix seconds) lies within the `valid-after`/`valid-before` window.
+    pub fn is_valid_at(&self, time: u64) -> bool {
+        self.options.iter().all(|option| match *option {
+            SignerOption::ValidAfter(ref after) => parse_time(after).map(|after| time >= after).unwrap_or(false),
+            SignerOption::ValidBefore(ref before) => parse_time(before).map(|before| time <= before).unwrap_or(false),
+            _ => true,
+        })
+    }
+
+    /// Whether this entry lists `key` itself. Certificate authority entries only
+    /// vouch for certificates they signed, never the CA key directly.
+    pub fn matches_key(&self, key: &PublicKey) -> bool {
+        !self.is_cert_authority() && self.public_key == *key
+    }
+
+    /// Whether this is a `cert-authority` entry for the CA that signed `certificate`,
+    /// a user certificate valid at `time`.
+    pub fn certifies(&self, certificate: &Certificate, time: u64) -> bool {
+        self.is_cert_authority() &&
+            certificate.signature_key == self.public_key &&
+            certificate.cert_type == CertType::User &&
+            certificate.is_valid_at(time) &&
+            certificate.verify_signature()
+    }
+
+    /// The principals of `certificate` that this entry's patterns allow.
+    pub fn certified_principals(&self, certificate: &Certificate) -> Vec<String> {
+        certificate.principals.iter().filter(|p| self.matches_principal(p)).cloned().collect()
+    }
+
+    //  Whether the entry covers the signature's key, or for a certificate its CA,
+    //  for `namespace` at `time`. Certificates only match `cert-authority` entries.
+    fn allows_signature(&self, signature: &SshSig, namespace: &str, time: u64) -> bool {
+        let key_matches = match signature.certificate {
+            Some(ref certificate) => self.certifies(certificate, time),
+            None => self.matches_key(&signature.public_key),
+        };
+        key_matches && self.allows_namespace(namespace) && self.is_valid_at(time)
+    }
+}
+
+impl fmt::Display for AllowedSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref source) = self.source {
+            return write!(f, "{}", source);
+        }
+        let principals = self.principals.join(",");
+        if principals.contains(|c: char| c == ' ' || c == '\t' || c == '"') {
+            write!(f, "\"{}\" ", principals.replace("\"", "\\\""))?;
+        } else {
+            write!(f, "{} ", principals)?;
+        }
+        if self.options.len() > 0 {
+            let options : Vec<String> = self.options.iter().map(|o| o.to_string()).collect();
+            write!(f, "{} ", options.join(","))?;
+        }
+        let line = self.public_key.to_openssh_line(&self.comment).map_err(|_| fmt::Error)?;
+        write!(f, "{}", line)
+    }
+}
+
+#[derive(Clone)]
+pub enum Entry {
+    Blank(String),
+    Comment(String),
+    Signer(AllowedSigner),
+    Invalid(String),
+}
+
+#[derive(Clone)]
+pub struct AllowedSigners {
+    pub entries: Vec<Entry>,
+}
+
+impl AllowedSigners {
+    /// Parses a whole file, keeping lines that fail to parse as `Entry::Invalid`.
+    pub fn parse(text: &str) -> Self {
+        let entries = text.split('\n').map(|line| {
+            let trimmed = line.trim();
+            if trimmed.len() == 0 {
+                Entry::Blank(line.into())
+            } else if trimmed.starts_with('#') {
+                Entry::Comment(line.into())
+            } else {
+                match AllowedSigner::parse(line) {
+                    Ok(signer) => Entry::Signer(signer),
+                    Err(_) => Entry::Invalid(line.into()),
+                }
+            }
+        }).collect();
+        AllowedSigners{entries: entries}
+    }
+
+    pub fn signers(&self) -> Vec<&AllowedSigner> {
+        self.entries.iter().filter_map(|entry| match *entry {
+            Entry::Signer(ref signer) => Some(signer),
+            _ => None,
+        }).collect()
+    }
+
+    /// Every entry that allows the signature's key, or for a certificate its CA
+    /// and at least one of its principals, to sign for `namespace` at `time`.
+    pub fn find_signers(&self, signature: &SshSig, namespace: &str, time: u64) -> Vec<&AllowedSigner> {
+        self.signers().into_iter().filter(|signer| {
+            signer.allows_signature(signature, namespace, time) && match signature.certificate {
+                Some(ref certificate) => signer.certified_principals(certificate).len() > 0,
+                None => true,
+            }
+        }).collect()
+    }
+
+    /// The principals of each entry from `find_signers`, comma-separated, as
+    /// `ssh-keygen -Y find-principals` prints them (it prints only the first).
+    /// For a listed key these are the entry's patterns, such as `*@example.com`,
+    /// and for a certificate the certified principals those patterns allow.
+    pub fn find_principals(&self, signature: &SshSig, namespace: &str, time: u64) -> Vec<String> {
+        self.find_signers(signature, namespace, time).into_iter().map(|signer| {
+            match signature.certificate {
+                Some(ref certificate) => signer.certified_principals(certificate).join(","),
+                None => signer.principals.join(","),
+            }
+        }).collect()
+    }
+
+    /// Verifies that `signature` is over the contents of `reader`, was made for
+    /// `namespace`, and is by a key this file allows `principal` to sign with at
+    /// `time`. A certificate must also list `principal` itself.
+    pub fn verify_for_principal<R: Read>(&self, principal: &str, namespace: &str, signature: &SshSig, reader: R, time: u64) -> Result<bool, Error> {
+        let certified = match signature.certificate {
+            Some(ref certificate) => certificate.principals.iter().any(|p| p == principal),
+            None => true,
+        };
+        let allowed = certified && self.signers().into_iter().any(|signer| {
+            signer.matches_principal(principal) && signer.allows_signature(signature, namespace, time)
+        });
+        if !allowed {
+            return Ok(false);
+        }
+        signature.verify_reader(namespace, reader)
+    }
+}
+
+impl fmt::Display for AllowedSigners {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match *entry {
+                Entry::Blank(ref line) | Entry::Comment(ref line) | Entry::Invalid(ref line) => write!(f, "{}", line)?,
+                Entry::Signer(ref signer) => write!(f, "{}", signer)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::{KeyType, PrivateKey};
+
+    const MESSAGE : &'static [u8] = b"release artifact\n";
+
+    //  `ssh-keygen -Y sign -n file` over MESSAGE with a user certificate for principals
+    //  alice@krypt.co and deploy, valid 2020 to 2040 and signed by CA_KEY.
+    const CERT_SIGNATURE : &'static str = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAAdYAAAAgc3NoLWVkMjU1MTktY2VydC12MDFAb3BlbnNzaC5jb20AAA
+AgREBRM/pWawZuCy1gQF1rA2MrLPhPodaD2SUMkbdI4VkAAAAgyfXHhwj6Oud/56J+HckA
+iNG03nLujrHtuMmRzaMrRxAAAAAAAAAAAAAAAAEAAAAOYWxpY2VAa3J5cHQuY28AAAAcAA
+AADmFsaWNlQGtyeXB0LmNvAAAABmRlcGxveQAAAABeC+EAAAAAAIOqfoAAAAAAAAAAggAA
+ABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW
+5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAA
+AAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACAoNU
+tk/2Y2PmUg8t5bVniCalij01stn/ClSKjJFBLzbgAAAFMAAAALc3NoLWVkMjU1MTkAAABA
+asYPPFjucOq8nzRldc+jHufpCC7zq3M/lK8R/jgnBrCPkL7HmxGUy+9dM7DtFUo5LLjyxf
+3tiPfNFSX23pECBQAAAARmaWxlAAAAAAAAAAZzaGE1MTIAAABTAAAAC3NzaC1lZDI1NTE5
+AAAAQG/IqShC2lVMw7Rk9AGxwayoZ/RM/caNC6LVCYrubgkQiE98VuEXTn8KfpB36tfVit
+hhLbhurgeF1E+ptgo79QQ=
+-----END SSH SIGNATURE-----
+";
+    const CA_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICg1S2T/ZjY+ZSDy3ltWeIJqWKPTWy2f8KVIqMkUEvNu";
+
+    #[test]
+    fn times_parse() {
+        assert!(parse_time("20300101Z").unwrap() == 1893456000);
+        assert!(parse_time("20300101").unwrap() == 1893456000);
+        assert!(parse_time("19700101Z").unwrap() == 0);
+        assert!(parse_time("20240229235959Z").unwrap() == 1709251199);
+        assert!(parse_time("21000301Z").unwrap() == 4107542400);
+        assert!(parse_time("20230229Z").is_err());
+        assert!(parse_time("20230431Z").is_err());
+        assert!(parse_time("19691231Z").is_err());
+        assert!(parse_time("203001011230Z").unwrap() == 1893501000);
+        assert!(parse_time("20300101123045Z").unwrap() == 1893501045);
+        assert!(parse_time("2030010112Z").is_err());
+        assert!(parse_time("20301301Z").is_err());
+        assert!(parse_time("tomorrow").is_err());
+    }
+
+    #[test]
+    fn signatures_verify_for_principals() {
+        let (private_key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (other_key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let key_line = private_key.public_key().to_openssh_line("release key").unwrap();
+        let text = format!("# release signers\n\
+\n\
+*@example.com,!mallory@example.com namespaces=\"file\",valid-after=\"20200101Z\",VALID-BEFORE=\"20300101Z\" {}\n\
+\"ci bot\" {}\n\
+not a valid line\n\
+root@example.org cert-authority {}\n", key_line, key_line, key_line);
+        let signers = AllowedSigners::parse(&text);
+        assert!(signers.to_string() == text);
+        assert!(signers.signers().len() == 3);
+        match signers.entries[4] {
+            Entry::Invalid(_) => {},
+            _ => assert!("expected" == "Invalid"),
+        }
+
+        let now = 1700000000;
+        let signature = SshSig::sign(&private_key, "file", MESSAGE).unwrap();
+        assert!(signers.find_principals(&signature, "file", now) == vec!["*@example.com,!mallory@example.com", "ci bot"]);
+        assert!(signers.find_principals(&signature, "file", 1900000000) == vec!["ci bot"]);
+        assert!(signers.find_principals(&signature, "git", now) == vec!["ci bot"]);
+
+        assert!(signers.verify_for_principal("alice@example.com", "file", &signature, MESSAGE, now).unwrap());
+        assert!(signers.verify_for_principal("ci bot", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("alice@EXAMPLE.com", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("mallory@example.com", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("root@example.org", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("alice@example.com", "file", &signature, MESSAGE, 1900000000).unwrap());
+        assert!(!signers.verify_for_principal("alice@example.com", "file", &signature, &b"tampered"[..], now).unwrap());
+
+        let git_signature = SshSig::sign(&private_key, "git", MESSAGE).unwrap();
+        assert!(!signers.verify_for_principal("alice@example.com", "git", &git_signature, MESSAGE, now).unwrap());
+        assert!(signers.verify_for_principal("ci bot", "git", &git_signature, MESSAGE, now).unwrap());
+
+        let other_signature = SshSig::sign(&other_key, "file", MESSAGE).unwrap();
+        assert!(signers.find_principals(&other_signature, "file", now).len() == 0);
+        assert!(!signers.verify_for_principal("alice@example.com", "file", &other_signature, MESSAGE, now).unwrap());
+    }
+
+    #[test]
+    fn certificates_verify_for_cert_authorities() {
+        let signature = SshSig::from_pem(CERT_SIGNATURE).unwrap();
+        assert!(signature.to_pem().unwrap() == CERT_SIGNATURE);
+        let certificate = signature.certificate.clone().unwrap();
+        assert!(certificate.public_key == signature.public_key);
+        let now = 1700000000;
+
+        let signers = AllowedSigners::parse(&format!("*@krypt.co cert-authority {}\n", CA_KEY));
+        assert!(signers.find_principals(&signature, "file", now) == vec!["alice@krypt.co"]);
+        assert!(signers.verify_for_principal("alice@krypt.co", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("bob@krypt.co", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("deploy", "file", &signature, MESSAGE, now).unwrap());
+        assert!(!signers.verify_for_principal("alice@krypt.co", "file", &signature, &b"tampered"[..], now).unwrap());
+        //  The certificate expires in 2040.
+        assert!(signers.find_principals(&signature, "file", 2300000000).len() == 0);
+        assert!(!signers.verify_for_principal("alice@krypt.co", "file", &signature, MESSAGE, 2300000000).unwrap());
+
+        //  The CA must be marked as one, and a listed certified key does not vouch for its certificates.
+        let plain = AllowedSigners::parse(&format!("*@krypt.co {}\n", CA_KEY));
+        assert!(plain.find_principals(&signature, "file", now).len() == 0);
+        let key_line = signature.public_key.to_openssh_line("").unwrap();
+        let listed = AllowedSigners::parse(&format!("alice@krypt.co {}\n", key_line));
+        assert!(!listed.verify_for_principal("alice@krypt.co", "file", &signature, MESSAGE, now).unwrap());
+
+        let mut host = certificate.clone();
+        host.cert_type = CertType::Host;
+        let (ca, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        host.sign(&ca).unwrap();
+        let signers = AllowedSigners::parse(&format!("* cert-authority {}\n", ca.public_key().to_openssh_line("").unwrap()));
+        let forged = SshSig{certificate: Some(host), ..signature.clone()};
+        assert!(!signers.verify_for_principal("alice@krypt.co", "file", &forged, MESSAGE, now).unwrap());
+    }
+
+    #[test]
+    fn rendered_signer_parses() {
+        let (private_key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let signer = AllowedSigner::new(vec!["ci bot".into(), "*@example.com".into()],
+                                        vec![SignerOption::Namespaces("git,file".into())],
+                                        private_key.public_key());
+        let parsed = AllowedSigner::parse(&signer.to_string()).unwrap();
+        assert!(parsed.principals == signer.principals);
+        assert!(parsed.options == signer.options);
+        assert!(parsed.public_key == signer.public_key);
+    }
+}
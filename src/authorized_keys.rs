@@ -0,0 +1,316 @@
+use public_key::PublicKey;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+use std::fmt;
+
+//  sshd's authorized_keys format: one key per line, optionally preceded by a
+//  comma-separated option list and followed by a comment.
+
+const FLAG_OPTIONS : &'static [&'static str] = &[
+    "agent-forwarding", "no-agent-forwarding",
+    "port-forwarding", "no-port-forwarding",
+    "pty", "no-pty",
+    "user-rc", "no-user-rc",
+    "x11-forwarding", "no-x11-forwarding",
+    "touch-required", "no-touch-required",
+    "verify-required",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyOption {
+    CertAuthority,
+    Restrict,
+    /// Any other option without a value, such as `no-pty` or `pty`.
+    Flag(String),
+    Command(String),
+    Environment(String),
+    ExpiryTime(String),
+    From(String),
+    PermitListen(String),
+    PermitOpen(String),
+    Principals(String),
+    Tunnel(String),
+}
+
+impl KeyOption {
+    fn parse(option: &str) -> Result<KeyOption, Error> {
+        let mut parts = option.splitn(2, '=');
+        let name = parts.next().unwrap_or("").to_ascii_lowercase();
+        let value = match parts.next() {
+            Some(quoted) => Some(dequote(quoted)?),
+            None => None,
+        };
+        match (name.as_ref(), value) {
+            ("cert-authority", None) => Ok(KeyOption::CertAuthority),
+            ("restrict", None) => Ok(KeyOption::Restrict),
+            (flag, None) if FLAG_OPTIONS.contains(&flag) => Ok(KeyOption::Flag(flag.into())),
+            ("command", Some(value)) => Ok(KeyOption::Command(value)),
+            ("environment", Some(value)) => Ok(KeyOption::Environment(value)),
+            ("expiry-time", Some(value)) => Ok(KeyOption::ExpiryTime(value)),
+            ("from", Some(value)) => Ok(KeyOption::From(value)),
+            ("permitlisten", Some(value)) => Ok(KeyOption::PermitListen(value)),
+            ("permitopen", Some(value)) => Ok(KeyOption::PermitOpen(value)),
+            ("principals", Some(value)) => Ok(KeyOption::Principals(value)),
+            ("tunnel", Some(value)) => Ok(KeyOption::Tunnel(value)),
+            _ => Err(Error{kind: InvalidFormat}),
+        }
+    }
+}
+
+impl fmt::Display for KeyOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, value) = match *self {
+            KeyOption::CertAuthority => return write!(f, "cert-authority"),
+            KeyOption::Restrict => return write!(f, "restrict"),
+            KeyOption::Flag(ref flag) => return write!(f, "{}", flag),
+            KeyOption::Command(ref value) => ("command", value),
+            KeyOption::Environment(ref value) => ("environment", value),
+            KeyOption::ExpiryTime(ref value) => ("expiry-time", value),
+            KeyOption::From(ref value) => ("from", value),
+            KeyOption::PermitListen(ref value) => ("permitlisten", value),
+            KeyOption::PermitOpen(ref value) => ("permitopen", value),
+            KeyOption::Principals(ref value) => ("principals", value),
+            KeyOption::Tunnel(ref value) => ("tunnel", value),
+        };
+        write!(f, "{}=\"{}\"", name, value.replace("\"", "\\\""))
+    }
+}
+
+/// Option values must be double-quoted; inside, only `\"` is an escape.
+pub fn dequote(quoted: &str) -> Result<String, Error> {
+    if quoted.len() < 2 || !quoted.starts_with('"') || !quoted.ends_with('"') {
+        return Err(Error{kind: InvalidFormat});
+    }
+    let inner = &quoted[1..quoted.len() - 1];
+    let mut value = String::new();
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'"') {
+            value.push(chars.next().unwrap());
+        } else if c == '"' {
+            return Err(Error{kind: InvalidFormat});
+        } else {
+            value.push(c);
+        }
+    }
+    Ok(value)
+}
+
+/// Splits `s` at the first unquoted occurrence of a character matching `at`.
+pub fn split_unquoted<F: Fn(char) -> bool>(s: &str, at: F) -> (&str, &str) {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if c == '"' && !escaped {
+            in_quotes = !in_quotes;
+        } else if !in_quotes && at(c) {
+            return (&s[..i], &s[i..]);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    (s, "")
+}
+
+#[derive(Clone)]
+pub struct AuthorizedKey {
+    pub options: Vec<KeyOption>,
+    pub public_key: PublicKey,
+    pub comment: String,
+    /// The line as read, written back verbatim; set to `None` after editing
+    /// the fields to render them instead.
+    pub source: Option<String>,
+}
+
+impl AuthorizedKey {
+    pub fn new(options: Vec<KeyOption>, public_key: PublicKey, comment: &str) -> Self {
+        AuthorizedKey{
+            options: options,
+            public_key: public_key,
+            comment: comment.into(),
+            source: None,
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let trimmed = line.trim();
+        //  As in sshd, a line is first tried as a bare key, then as options followed by a key.
+        let (options, key) = match PublicKey::from_openssh_line(trimmed) {
+            Ok(key) => (vec![], key),
+            Err(_) => {
+                let (options, rest) = split_unquoted(trimmed, |c| c == ' ' || c == '\t');
+                let mut parsed = vec![];
+                let mut remaining = options;
+                while remaining.len() > 0 {
+                    let (option, rest) = split_unquoted(remaining, |c| c == ',');
+                    parsed.push(KeyOption::parse(option)?);
+                    remaining = if rest.len() > 0 { &rest[1..] } else { rest };
+                }
+                (parsed, PublicKey::from_openssh_line(rest)?)
+            },
+        };
+        Ok(AuthorizedKey{
+            options: options,
+            public_key: key.0,
+            comment: key.1,
+            source: Some(line.into()),
+        })
+    }
+
+    pub fn is_cert_authority(&self) -> bool {
+        self.options.contains(&KeyOption::CertAuthority)
+    }
+
+    /// Whether this entry authorizes `key` itself. Certificate authority entries
+    /// only authorize certificates they signed, never the CA key directly.
+    pub fn matches(&self, key: &PublicKey) -> bool {
+        !self.is_cert_authority() && self.public_key == *key
+    }
+}
+
+impl fmt::Display for AuthorizedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref source) = self.source {
+            return write!(f, "{}", source);
+        }
+        if self.options.len() > 0 {
+            let options : Vec<String> = self.options.iter().map(|o| o.to_string()).collect();
+            write!(f, "{} ", options.join(","))?;
+        }
+        let line = self.public_key.to_openssh_line(&self.comment).map_err(|_| fmt::Error)?;
+        write!(f, "{}", line)
+    }
+}
+
+#[derive(Clone)]
+pub enum Entry {
+    Blank(String),
+    Comment(String),
+    Key(AuthorizedKey),
+    /// A line sshd would skip, such as an unsupported key type or bad options.
+    Invalid(String),
+}
+
+#[derive(Clone)]
+pub struct AuthorizedKeys {
+    pub entries: Vec<Entry>,
+}
+
+impl AuthorizedKeys {
+    /// Parses a whole file. Lines that fail to parse are kept as `Entry::Invalid`
+    /// rather than failing the file, matching sshd.
+    pub fn parse(text: &str) -> Self {
+        let entries = text.split('\n').map(|line| {
+            let trimmed = line.trim();
+            if trimmed.len() == 0 {
+                Entry::Blank(line.into())
+            } else if trimmed.starts_with('#') {
+                Entry::Comment(line.into())
+            } else {
+                match AuthorizedKey::parse(line) {
+                    Ok(key) => Entry::Key(key),
+                    Err(_) => Entry::Invalid(line.into()),
+                }
+            }
+        }).collect();
+        AuthorizedKeys{entries: entries}
+    }
+
+    pub fn keys(&self) -> Vec<&AuthorizedKey> {
+        self.entries.iter().filter_map(|entry| match *entry {
+            Entry::Key(ref key) => Some(key),
+            _ => None,
+        }).collect()
+    }
+
+    /// The first entry authorizing `key`, whose options then apply to the session.
+    pub fn find(&self, key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.keys().into_iter().find(|entry| entry.matches(key))
+    }
+}
+
+impl fmt::Display for AuthorizedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match *entry {
+                Entry::Blank(ref line) | Entry::Comment(ref line) | Entry::Invalid(ref line) => write!(f, "{}", line)?,
+                Entry::Key(ref key) => write!(f, "{}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ED25519_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK6HslpsJSnoarkkdo5GRYMRmbmjEs/Iwwl8G3J54QBO";
+    const ECDSA_KEY : &'static str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBNZKo1XjDtLaWgnNwz4Aw6/blSKHIYYI9h3zkg7GerHJ4CEmqeXMQm1YaNqO3OZFk7pF4NGHGowIQ3puDDIEhzijUfYjgPqZR4+PZvMlk+GgG/SJpOloRRVOD3O4iR5X7A==";
+
+    fn authorized_keys() -> String {
+        format!("# bastion users\n\
+\n\
+{} test@krypt.co\n\
+command=\"echo \\\"hi, there\\\"\",from=\"10.0.0.0/8,!10.1.*\",no-pty,No-X11-Forwarding  {}  ecdsa@krypt.co \n\
+cert-authority,principals=\"alice,bob\",expiry-time=\"20300101\" {}\n\
+restrict,permitopen=\"localhost:8080\",environment=\"LANG=C\" {} restricted\n\
+ssh-dss AAAAB3NzaC1kc3MAAACBAN unsupported\n", ED25519_KEY, ECDSA_KEY, ED25519_KEY, ED25519_KEY)
+    }
+
+    #[test]
+    fn authorized_keys_round_trip() {
+        let text = authorized_keys();
+        let file = AuthorizedKeys::parse(&text);
+        assert!(file.to_string() == text);
+        assert!(file.keys().len() == 4);
+        match file.entries[6] {
+            Entry::Invalid(_) => {},
+            _ => assert!("expected" == "Invalid"),
+        }
+    }
+
+    #[test]
+    fn options_parse() {
+        let file = AuthorizedKeys::parse(&authorized_keys());
+        let keys = file.keys();
+        assert!(keys[0].options.len() == 0);
+        assert!(keys[0].comment == "test@krypt.co");
+        assert!(keys[1].options == vec![
+            KeyOption::Command("echo \"hi, there\"".into()),
+            KeyOption::From("10.0.0.0/8,!10.1.*".into()),
+            KeyOption::Flag("no-pty".into()),
+            KeyOption::Flag("no-x11-forwarding".into()),
+        ]);
+        assert!(keys[1].comment == "ecdsa@krypt.co");
+        assert!(keys[2].is_cert_authority());
+        assert!(keys[2].options[1] == KeyOption::Principals("alice,bob".into()));
+        assert!(keys[3].options[2] == KeyOption::Environment("LANG=C".into()));
+
+        let mut edited = keys[1].clone();
+        edited.source = None;
+        assert!(AuthorizedKey::parse(&edited.to_string()).unwrap().options == keys[1].options);
+    }
+
+    #[test]
+    fn keys_match_presented_key() {
+        let file = AuthorizedKeys::parse(&authorized_keys());
+        let (ecdsa, _) = PublicKey::from_openssh_line(ECDSA_KEY).unwrap();
+        assert!(file.find(&ecdsa).unwrap().comment == "ecdsa@krypt.co");
+        let (ed25519, _) = PublicKey::from_openssh_line(ED25519_KEY).unwrap();
+        assert!(file.find(&ed25519).unwrap().options.len() == 0);
+
+        let ca_only = AuthorizedKeys::parse(&format!("cert-authority {}\n", ED25519_KEY));
+        assert!(ca_only.find(&ed25519).is_none());
+    }
+
+    #[test]
+    fn bad_options_fail() {
+        assert!(AuthorizedKey::parse(&format!("command=unquoted {}", ED25519_KEY)).is_err());
+        assert!(AuthorizedKey::parse(&format!("no-such-option {}", ED25519_KEY)).is_err());
+        assert!(AuthorizedKey::parse(&format!("command=\"unterminated {}", ED25519_KEY)).is_err());
+    }
+}
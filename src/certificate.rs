@@ -0,0 +1,287 @@
+use mpint::MPUint;
+use private_key::PrivateKey;
+use public_key::PublicKey;
+use rsa::RSA_TYPE;
+use ed25519::ED25519_TYPE;
+use serde_de::{Deserializer, Error};
+use serde_de::ErrorKind::*;
+use serde_ser::Serializer;
+use serde;
+use base64;
+
+use std::io::Cursor;
+
+//  OpenSSH certificates (PROTOCOL.certkeys): a public key with a serial, key ID,
+//  principals, validity window and options, signed by a certificate authority.
+
+pub const CERT_SUFFIX : &'static str = "-cert-v01@openssh.com";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertType {
+    User = 1,
+    Host = 2,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub nonce: Vec<u8>,
+    /// The certified key.
+    pub public_key: PublicKey,
+    pub serial: u64,
+    pub cert_type: CertType,
+    pub key_id: String,
+    /// Empty means valid for any principal.
+    pub principals: Vec<String>,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    /// Option names with their raw data, which for options taking a value is itself a string.
+    pub critical_options: Vec<(String, Vec<u8>)>,
+    pub extensions: Vec<(String, Vec<u8>)>,
+    pub reserved: Vec<u8>,
+    pub signature_key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+//  Reads a string holding concatenated wire-format values, such as the principals.
+fn read_packed<T>(bytes: &[u8]) -> Result<Vec<T>, Error> where T: for<'x> serde::Deserialize<'x> {
+    let mut cursor = Cursor::new(bytes);
+    let mut values = vec![];
+    while (cursor.position() as usize) < bytes.len() {
+        values.push(Deserializer::new(&mut cursor).next()?);
+    }
+    Ok(values)
+}
+
+fn write_packed<T: serde::Serialize>(values: &[T]) -> Result<Vec<u8>, Error> {
+    let mut ser = Serializer::new(Vec::new());
+    for value in values {
+        ser.write(value)?;
+    }
+    Ok(ser.into_inner())
+}
+
+impl Certificate {
+    /// A certificate for `public_key` with a random nonce, not yet signed.
+    pub fn new(public_key: PublicKey, cert_type: CertType, key_id: &str) -> Result<Self, Error> {
+        use rand::{OsRng, Rng};
+        let mut nonce = vec![0; 32];
+        OsRng::new()?.fill_bytes(&mut nonce);
+        Ok(Certificate{
+            nonce: nonce,
+            signature_key: public_key.clone(),
+            public_key: public_key,
+            serial: 0,
+            cert_type: cert_type,
+            key_id: key_id.into(),
+            principals: vec![],
+            valid_after: 0,
+            valid_before: ::std::u64::MAX,
+            critical_options: vec![],
+            extensions: vec![],
+            reserved: vec![],
+            signature: vec![],
+        })
+    }
+
+    pub fn key_type(&self) -> String {
+        format!("{}{}", self.public_key.key_type(), CERT_SUFFIX)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut de = Deserializer::new(Cursor::new(bytes));
+        let cert_type : String = de.next()?;
+        if !cert_type.ends_with(CERT_SUFFIX) {
+            return Err(Error{kind: UnsupportedAlgorithm(cert_type)});
+        }
+        let key_type = &cert_type[..cert_type.len() - CERT_SUFFIX.len()];
+        let nonce : Vec<u8> = de.next()?;
+
+        //  The key fields follow the nonce; re-prefix them with the plain key type.
+        let mut key = Serializer::new(Vec::new());
+        key.write(key_type)?;
+        match key_type {
+            ED25519_TYPE => key.write(&de.next::<Vec<u8>>()?)?,
+            RSA_TYPE => {
+                key.write(&de.next::<MPUint>()?)?;
+                key.write(&de.next::<MPUint>()?)?;
+            },
+            _ if key_type.starts_with("ecdsa-sha2-") => {
+                key.write(&de.next::<String>()?)?;
+                key.write(&de.next::<Vec<u8>>()?)?;
+            },
+            _ => return Err(Error{kind: UnsupportedAlgorithm(cert_type.clone())}),
+        }
+        let public_key = PublicKey::from_bytes(&key.into_inner())?;
+
+        let serial : u64 = de.next()?;
+        let cert_type = match de.next::<u32>()? {
+            1 => CertType::User,
+            2 => CertType::Host,
+            _ => return Err(Error{kind: InvalidFormat}),
+        };
+        let key_id : String = de.next()?;
+        let principals : Vec<u8> = de.next()?;
+        let valid_after : u64 = de.next()?;
+        let valid_before : u64 = de.next()?;
+        let critical_options : Vec<u8> = de.next()?;
+        let extensions : Vec<u8> = de.next()?;
+        let reserved : Vec<u8> = de.next()?;
+        let signature_key : Vec<u8> = de.next()?;
+        let signature : Vec<u8> = de.next()?;
+        let rest = de.into_inner();
+        if rest.position() as usize != bytes.len() {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(Certificate{
+            nonce: nonce,
+            public_key: public_key,
+            serial: serial,
+            cert_type: cert_type,
+            key_id: key_id,
+            principals: read_packed(&principals)?,
+            valid_after: valid_after,
+            valid_before: valid_before,
+            critical_options: read_packed(&critical_options)?,
+            extensions: read_packed(&extensions)?,
+            reserved: reserved,
+            signature_key: PublicKey::from_bytes(&signature_key)?,
+            signature: signature,
+        })
+    }
+
+    //  Everything the CA signs: the whole certificate up to the signature.
+    fn signed_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&self.key_type())?;
+        ser.write(&self.nonce)?;
+        let mut bytes = ser.into_inner();
+        //  The plain key blob less its leading type string.
+        let key = self.public_key.to_bytes()?;
+        bytes.extend_from_slice(&key[4 + self.public_key.key_type().len()..]);
+        let mut ser = Serializer::new(bytes);
+        ser.write(&self.serial)?;
+        ser.write(&(self.cert_type as u32))?;
+        ser.write(&self.key_id)?;
+        ser.write(&write_packed(&self.principals)?)?;
+        ser.write(&self.valid_after)?;
+        ser.write(&self.valid_before)?;
+        ser.write(&write_packed(&self.critical_options)?)?;
+        ser.write(&write_packed(&self.extensions)?)?;
+        ser.write(&self.reserved)?;
+        ser.write(&self.signature_key.to_bytes()?)?;
+        Ok(ser.into_inner())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(self.signed_bytes()?);
+        ser.write(&self.signature)?;
+        Ok(ser.into_inner())
+    }
+
+    /// Signs the certificate as `ca`, with the CA key's default signature algorithm.
+    pub fn sign(&mut self, ca: &PrivateKey) -> Result<(), Error> {
+        self.signature_key = ca.public_key();
+        self.signature = ca.sign(&self.signed_bytes()?)?;
+        Ok(())
+    }
+
+    /// Whether the signature is valid for the embedded CA key. Whether that CA is
+    /// trusted, and the validity window and principals, are for the caller to check.
+    pub fn verify_signature(&self) -> bool {
+        match self.signed_bytes() {
+            Ok(bytes) => self.signature_key.verify(&self.signature, &bytes),
+            _ => false,
+        }
+    }
+
+    /// Whether `time` (Unix seconds) lies within the validity window.
+    pub fn is_valid_at(&self, time: u64) -> bool {
+        self.valid_after <= time && time < self.valid_before
+    }
+
+    /// The value of a critical option such as `force-command` or `source-address`.
+    pub fn critical_option(&self, name: &str) -> Option<String> {
+        self.critical_options.iter()
+            .find(|option| option.0 == name)
+            .and_then(|option| ::serde_de::from_slice(&option.1).ok())
+    }
+
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|extension| extension.0 == name)
+    }
+
+    /// Parses a `-cert.pub` line, returning the certificate and its comment.
+    pub fn from_openssh_line(line: &str) -> Result<(Self, String), Error> {
+        let mut parts = line.trim().splitn(3, |c: char| c == ' ' || c == '\t');
+        let algorithm = parts.next().unwrap_or("");
+        let body = parts.next().unwrap_or("");
+        let comment = parts.next().unwrap_or("").trim();
+        let blob = base64::decode(body).map_err(|_| Error{kind: InvalidFormat})?;
+        let certificate = Certificate::from_bytes(&blob)?;
+        if certificate.key_type() != algorithm {
+            return Err(Error{kind: InvalidKey});
+        }
+        Ok((certificate, comment.into()))
+    }
+
+    pub fn to_openssh_line(&self, comment: &str) -> Result<String, Error> {
+        let mut line = format!("{} {}", self.key_type(), base64::encode(&self.to_bytes()?));
+        if comment.len() > 0 {
+            line.push(' ');
+            line.push_str(comment);
+        }
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::KeyType;
+
+    //  `ssh-keygen -s ca -I alice@krypt.co -n alice,deploy -z 42 -V 20200101000000Z:20300101000000Z
+    //  -O force-command=/bin/true -O source-address=10.0.0.0/8`
+    const USER_CERT : &'static str = "ecdsa-sha2-nistp384-cert-v01@openssh.com AAAAKGVjZHNhLXNoYTItbmlzdHAzODQtY2VydC12MDFAb3BlbnNzaC5jb20AAAAgQVqnmWxI7Qs07/M8wq1u9kfs+PVChfeGeyBkxrNGtE4AAAAIbmlzdHAzODQAAABhBNZKo1XjDtLaWgnNwz4Aw6/blSKHIYYI9h3zkg7GerHJ4CEmqeXMQm1YaNqO3OZFk7pF4NGHGowIQ3puDDIEhzijUfYjgPqZR4+PZvMlk+GgG/SJpOloRRVOD3O4iR5X7AAAAAAAAAAqAAAAAQAAAA5hbGljZUBrcnlwdC5jbwAAABMAAAAFYWxpY2UAAAAGZGVwbG95AAAAAF4L4QAAAAAAcNvYgAAAAEYAAAANZm9yY2UtY29tbWFuZAAAAA0AAAAJL2Jpbi90cnVlAAAADnNvdXJjZS1hZGRyZXNzAAAADgAAAAoxMC4wLjAuMC84AAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACAkltYfpwvA8k/nnjtXWZQ56PlsPqM1SWVJRWyx666LwgAAAFMAAAALc3NoLWVkMjU1MTkAAABA2nvN9gB6WB86rfH0oaTkLIYtSjK2yv+w7vHPpo8mwquJgzVIJNQ8jtic2HNGTn/UCNykITAHm+2E4fvZb+IpDw== ecdsa@krypt.co";
+    const CA_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICSW1h+nC8DyT+eeO1dZlDno+Ww+ozVJZUlFbLHrrovC ca@krypt.co";
+
+    #[test]
+    fn ssh_keygen_certificate_parses() {
+        let (cert, comment) = Certificate::from_openssh_line(USER_CERT).unwrap();
+        assert!(comment == "ecdsa@krypt.co");
+        assert!(cert.to_openssh_line(&comment).unwrap() == USER_CERT);
+        assert!(cert.verify_signature());
+        assert!(cert.signature_key == PublicKey::from_openssh_line(CA_KEY).unwrap().0);
+        assert!(cert.public_key.key_type() == "ecdsa-sha2-nistp384");
+        assert!(cert.serial == 42);
+        assert!(cert.cert_type == CertType::User);
+        assert!(cert.key_id == "alice@krypt.co");
+        assert!(cert.principals == vec!["alice", "deploy"]);
+        assert!(cert.is_valid_at(1577836800) && !cert.is_valid_at(1577836799) && !cert.is_valid_at(1893456000));
+        assert!(cert.critical_option("force-command") == Some("/bin/true".into()));
+        assert!(cert.critical_option("source-address") == Some("10.0.0.0/8".into()));
+        assert!(cert.has_extension("permit-pty"));
+        assert!(!cert.has_extension("no-such-extension"));
+    }
+
+    #[test]
+    fn tampered_certificate_fails() {
+        let (mut cert, _) = Certificate::from_openssh_line(USER_CERT).unwrap();
+        cert.principals.push("root".into());
+        assert!(!cert.verify_signature());
+    }
+
+    #[test]
+    fn signed_certificates_verify() {
+        for ca_type in &[KeyType::Ed25519, KeyType::EcdsaSha2Nistp256, KeyType::Rsa(2048)] {
+            let (ca, _) = PrivateKey::generate(*ca_type).unwrap();
+            let (key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+            let mut cert = Certificate::new(key.public_key(), CertType::Host, "host.example.com").unwrap();
+            cert.principals = vec!["host.example.com".into()];
+            cert.sign(&ca).unwrap();
+            let parsed = Certificate::from_bytes(&cert.to_bytes().unwrap()).unwrap();
+            assert!(parsed == cert);
+            assert!(parsed.verify_signature());
+            assert!(parsed.signature_key == ca.public_key());
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+//  Ciphers by their OpenSSH names, as used to protect openssh-key-v1 private sections.
+pub struct Cipher {
+    pub name: &'static str,
+    pub key_len: usize,
+    pub iv_len: usize,
+    pub block_size: usize,
+    pub auth_len: usize,
+}
+
+pub const NONE : Cipher = Cipher{name: "none", key_len: 0, iv_len: 0, block_size: 8, auth_len: 0};
+pub const AES256_CTR : Cipher = Cipher{name: "aes256-ctr", key_len: 32, iv_len: 16, block_size: 16, auth_len: 0};
+pub const AES256_GCM : Cipher = Cipher{name: "aes256-gcm@openssh.com", key_len: 32, iv_len: 12, block_size: 16, auth_len: 16};
+pub const CHACHA20_POLY1305 : Cipher = Cipher{name: "chacha20-poly1305@openssh.com", key_len: 64, iv_len: 0, block_size: 8, auth_len: 16};
+
+pub fn by_name(name: &str) -> Result<&'static Cipher, Error> {
+    for cipher in &[&NONE, &AES256_CTR, &AES256_GCM, &CHACHA20_POLY1305] {
+        if cipher.name == name {
+            return Ok(cipher);
+        }
+    }
+    Err(Error{kind: UnsupportedAlgorithm(name.into())})
+}
+
+impl Cipher {
+    /// Encrypts `data` in place, returning the authentication tag (empty for non-AEAD ciphers).
+    pub fn encrypt(&self, key: &[u8], iv: &[u8], data: &mut [u8]) -> Result<Vec<u8>, Error> {
+        self.check_lengths(key, iv, data)?;
+        match self.name {
+            "none" => Ok(vec![]),
+            "aes256-ctr" => {
+                aes256_ctr(key, iv, data);
+                Ok(vec![])
+            },
+            "aes256-gcm@openssh.com" => {
+                use ring::aead;
+                let key = aes256_gcm_key(key)?;
+                let nonce = aead::Nonce::try_assume_unique_for_key(iv).map_err(|_| Error{kind: InvalidLength})?;
+                let tag = key.seal_in_place_separate_tag(nonce, aead::Aad::empty(), data)
+                    .map_err(|_| Error{kind: Crypto})?;
+                Ok(tag.as_ref().to_vec())
+            },
+            _ => {
+                chacha20_poly1305_keystream(key, data);
+                Ok(poly1305_tag(&chacha20_poly1305_poly_key(key), data))
+            },
+        }
+    }
+
+    /// Authenticates and decrypts `data` in place; `tag` must be `auth_len` bytes.
+    pub fn decrypt(&self, key: &[u8], iv: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), Error> {
+        self.check_lengths(key, iv, data)?;
+        if tag.len() != self.auth_len {
+            return Err(Error{kind: InvalidLength});
+        }
+        match self.name {
+            "none" => Ok(()),
+            "aes256-ctr" => {
+                aes256_ctr(key, iv, data);
+                Ok(())
+            },
+            "aes256-gcm@openssh.com" => {
+                use ring::aead;
+                let key = aes256_gcm_key(key)?;
+                let nonce = aead::Nonce::try_assume_unique_for_key(iv).map_err(|_| Error{kind: InvalidLength})?;
+                let mut in_out = data.to_vec();
+                in_out.extend(tag);
+                let plaintext_len = key.open_in_place(nonce, aead::Aad::empty(), &mut in_out)
+                    .map_err(|_| Error{kind: Crypto})?.len();
+                data.copy_from_slice(&in_out[..plaintext_len]);
+                Ok(())
+            },
+            _ => {
+                let poly_key = chacha20_poly1305_poly_key(key);
+                if !constant_time_eq(&poly1305_tag(&poly_key, data), tag) {
+                    return Err(Error{kind: Crypto});
+                }
+                chacha20_poly1305_keystream(key, data);
+                Ok(())
+            },
+        }
+    }
+
+    fn check_lengths(&self, key: &[u8], iv: &[u8], data: &[u8]) -> Result<(), Error> {
+        if key.len() != self.key_len || iv.len() != self.iv_len || data.len() % self.block_size != 0 {
+            return Err(Error{kind: InvalidLength});
+        }
+        Ok(())
+    }
+}
+
+fn aes256_ctr(key: &[u8], iv: &[u8], data: &mut [u8]) {
+    use ctr::cipher::{KeyIvInit, StreamCipher};
+    let mut cipher = ctr::Ctr128BE::<aes::Aes256>::new(key.into(), iv.into());
+    cipher.apply_keystream(data);
+}
+
+fn aes256_gcm_key(key: &[u8]) -> Result<::ring::aead::LessSafeKey, Error> {
+    use ring::aead;
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, key).map_err(|_| Error{kind: InvalidKey})?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+//  chacha20-poly1305@openssh.com with sequence number zero and no length
+//  prefix: the first 32 bytes of the key drive both the Poly1305 key (block 0)
+//  and the payload keystream (blocks 1..).
+fn chacha20_poly1305_poly_key(key: &[u8]) -> Vec<u8> {
+    use chacha20::ChaCha20Legacy;
+    use chacha20::cipher::{KeyIvInit, StreamCipher};
+    let mut poly_key = vec![0; 32];
+    let mut cipher = ChaCha20Legacy::new(key[..32].into(), (&[0u8; 8]).into());
+    cipher.apply_keystream(&mut poly_key);
+    poly_key
+}
+
+fn chacha20_poly1305_keystream(key: &[u8], data: &mut [u8]) {
+    use chacha20::ChaCha20Legacy;
+    use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+    let mut cipher = ChaCha20Legacy::new(key[..32].into(), (&[0u8; 8]).into());
+    cipher.seek(64u32);
+    cipher.apply_keystream(data);
+}
+
+fn poly1305_tag(poly_key: &[u8], data: &[u8]) -> Vec<u8> {
+    use poly1305::Poly1305;
+    use poly1305::universal_hash::KeyInit;
+    Poly1305::new(poly_key.into()).compute_unpadded(data).to_vec()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ciphers_round_trip() {
+        for cipher in &[&NONE, &AES256_CTR, &AES256_GCM, &CHACHA20_POLY1305] {
+            let key = vec![7; cipher.key_len];
+            let iv = vec![9; cipher.iv_len];
+            let plaintext : Vec<u8> = (0..64).collect();
+            let mut data = plaintext.clone();
+            let tag = cipher.encrypt(&key, &iv, &mut data).unwrap();
+            assert!(tag.len() == cipher.auth_len);
+            assert!(cipher.name == "none" || data != plaintext);
+            cipher.decrypt(&key, &iv, &mut data, &tag).unwrap();
+            assert!(data == plaintext);
+        }
+    }
+
+    #[test]
+    fn tampered_aead_fails() {
+        for cipher in &[&AES256_GCM, &CHACHA20_POLY1305] {
+            let key = vec![7; cipher.key_len];
+            let iv = vec![9; cipher.iv_len];
+            let mut data = vec![0; 32];
+            let tag = cipher.encrypt(&key, &iv, &mut data).unwrap();
+            data[0] ^= 1;
+            assert!(cipher.decrypt(&key, &iv, &mut data, &tag).is_err());
+        }
+    }
+}
@@ -1,16 +1,209 @@
 use byteorder::{ByteOrder,BigEndian};
+use mpint::MPUint;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+pub const INTEGER_TAG : u8 = 0x02;
+pub const BIT_STRING_TAG : u8 = 0x03;
+pub const OCTET_STRING_TAG : u8 = 0x04;
+pub const NULL_TAG : u8 = 0x05;
+pub const OID_TAG : u8 = 0x06;
+pub const SEQUENCE_TAG : u8 = 0x30;
+pub const CONTEXT_0_TAG : u8 = 0xa0;
+pub const CONTEXT_1_TAG : u8 = 0xa1;
 
 pub fn encode_length_octet(len: usize) -> Vec<u8> {
-    if len <= 127 { 
+    if len <= 127 {
             return vec![len as u8];
     }
-    let num_len_octets = (len + 255) / 256;
+    let mut num_len_octets = 1;
+    while num_len_octets < 8 && (len >> (8 * num_len_octets)) > 0 {
+        num_len_octets += 1;
+    }
     let mut len_octets = vec![num_len_octets as u8 | 0x80];
     len_octets.extend(&vec![0; num_len_octets]);
     BigEndian::write_uint(&mut len_octets.as_mut_slice().split_first_mut().unwrap().1, len as u64, num_len_octets);
     len_octets
 }
 
+pub fn encode_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der_out = vec![tag];
+    der_out.extend(encode_length_octet(content.len()));
+    der_out.extend(content);
+    der_out
+}
+
+//  Reads definite-length DER with single-byte tags, which covers every
+//  structure in PKCS#1, PKCS#8 and SEC1 private keys.
+pub struct Reader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Reader{input: input}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input.len() == 0
+    }
+
+    pub fn peek_tag(&self) -> Option<u8> {
+        self.input.first().cloned()
+    }
+
+    pub fn read_tlv(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        if self.input.len() < 2 || self.input[0] != tag {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let (len, header_len) = match self.input[1] {
+            len @ 0..=0x7f => (len as usize, 2),
+            0x81..=0x88 => {
+                let num_len_octets = (self.input[1] & 0x7f) as usize;
+                if self.input.len() < 2 + num_len_octets || self.input[2] == 0 {
+                    return Err(Error{kind: InvalidFormat});
+                }
+                let len = BigEndian::read_uint(&self.input[2..2 + num_len_octets], num_len_octets) as usize;
+                if len <= 127 {
+                    return Err(Error{kind: InvalidFormat});
+                }
+                (len, 2 + num_len_octets)
+            },
+            _ => return Err(Error{kind: InvalidFormat}),
+        };
+        if self.input.len() - header_len < len {
+            return Err(Error{kind: InvalidLength});
+        }
+        let content = &self.input[header_len..header_len + len];
+        self.input = &self.input[header_len + len..];
+        Ok(content)
+    }
+
+    pub fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek_tag() == Some(tag) {
+            Ok(Some(self.read_tlv(tag)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn read_sequence(&mut self) -> Result<Reader<'a>, Error> {
+        Ok(Reader::new(self.read_tlv(SEQUENCE_TAG)?))
+    }
+
+    /// Reads a non-negative INTEGER.
+    pub fn read_integer(&mut self) -> Result<MPUint, Error> {
+        let content = self.read_tlv(INTEGER_TAG)?;
+        if content.len() == 0 || (content[0] & 0x80) == 0x80 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        if content.len() > 1 && content[0] == 0 && (content[1] & 0x80) == 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(MPUint::from_be_bytes(content))
+    }
+
+    pub fn read_small_integer(&mut self) -> Result<u64, Error> {
+        let integer = self.read_integer()?;
+        if integer.be_bytes.len() > 8 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(integer.be_bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    pub fn read_octet_string(&mut self) -> Result<&'a [u8], Error> {
+        self.read_tlv(OCTET_STRING_TAG)
+    }
+
+    /// Reads a BIT STRING with no unused bits, as used for keys.
+    pub fn read_bit_string(&mut self) -> Result<&'a [u8], Error> {
+        let content = self.read_tlv(BIT_STRING_TAG)?;
+        if content.len() == 0 || content[0] != 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(&content[1..])
+    }
+
+    pub fn read_oid(&mut self) -> Result<&'a [u8], Error> {
+        self.read_tlv(OID_TAG)
+    }
+
+    pub fn read_null(&mut self) -> Result<(), Error> {
+        if self.read_tlv(NULL_TAG)?.len() != 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(), Error> {
+        if !self.is_empty() {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(())
+    }
+}
+
+pub struct Writer {
+    output: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer{output: vec![]}
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.output
+    }
+
+    pub fn write_tlv(&mut self, tag: u8, content: &[u8]) {
+        self.output.extend(encode_tlv(tag, content));
+    }
+
+    pub fn write_raw(&mut self, der: &[u8]) {
+        self.output.extend(der);
+    }
+
+    pub fn write_sequence<F: FnOnce(&mut Writer)>(&mut self, write_content: F) {
+        self.write_constructed(SEQUENCE_TAG, write_content);
+    }
+
+    pub fn write_constructed<F: FnOnce(&mut Writer)>(&mut self, tag: u8, write_content: F) {
+        let mut content = Writer::new();
+        write_content(&mut content);
+        self.write_tlv(tag, &content.output);
+    }
+
+    pub fn write_integer(&mut self, integer: &MPUint) {
+        let der = MPUint::from_be_bytes(&integer.be_bytes).to_der();
+        self.output.extend(der);
+    }
+
+    pub fn write_small_integer(&mut self, integer: u64) {
+        let mut be_bytes = vec![0; 8];
+        BigEndian::write_u64(&mut be_bytes, integer);
+        self.write_integer(&MPUint::from_be_bytes(&be_bytes));
+    }
+
+    pub fn write_octet_string(&mut self, bytes: &[u8]) {
+        self.write_tlv(OCTET_STRING_TAG, bytes);
+    }
+
+    pub fn write_bit_string(&mut self, bytes: &[u8]) {
+        let mut content = vec![0];
+        content.extend(bytes);
+        self.write_tlv(BIT_STRING_TAG, &content);
+    }
+
+    pub fn write_oid(&mut self, oid: &[u8]) {
+        self.write_tlv(OID_TAG, oid);
+    }
+
+    pub fn write_null(&mut self) {
+        self.write_tlv(NULL_TAG, &[]);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -20,6 +213,42 @@ mod test {
         assert!(encode_length_octet(1) == vec![1]);
         assert!(encode_length_octet(127) == vec![127]);
         assert!(encode_length_octet(128) == vec![0x81, 128]);
+        assert!(encode_length_octet(255) == vec![0x81, 255]);
+        assert!(encode_length_octet(256) == vec![0x82, 1, 0]);
         assert!(encode_length_octet(512) == vec![0x82, 2, 0]);
+        assert!(encode_length_octet(1190) == vec![0x82, 4, 0xa6]);
+        assert!(encode_length_octet(65536) == vec![0x83, 1, 0, 0]);
+    }
+
+    #[test]
+    fn reader_round_trips_writer() {
+        let mut writer = Writer::new();
+        writer.write_sequence(|w| {
+            w.write_small_integer(1);
+            w.write_integer(&MPUint{be_bytes: vec![0x80; 200]});
+            w.write_octet_string(b"octets");
+            w.write_constructed(CONTEXT_1_TAG, |w| w.write_bit_string(b"bits"));
+            w.write_null();
+        });
+        let der = writer.into_inner();
+        let mut reader = Reader::new(&der);
+        let mut sequence = reader.read_sequence().unwrap();
+        reader.finish().unwrap();
+        assert!(sequence.read_small_integer().unwrap() == 1);
+        assert!(sequence.read_integer().unwrap().be_bytes == vec![0x80; 200]);
+        assert!(sequence.read_octet_string().unwrap() == b"octets");
+        assert!(sequence.read_optional(CONTEXT_0_TAG).unwrap().is_none());
+        let mut context = Reader::new(sequence.read_optional(CONTEXT_1_TAG).unwrap().unwrap());
+        assert!(context.read_bit_string().unwrap() == b"bits");
+        sequence.read_null().unwrap();
+        sequence.finish().unwrap();
+    }
+
+    #[test]
+    fn reader_rejects_malformed() {
+        assert!(Reader::new(&[0x02, 0x01, 0x80]).read_integer().is_err());
+        assert!(Reader::new(&[0x02, 0x02, 0x00, 0x01]).read_integer().is_err());
+        assert!(Reader::new(&[0x04, 0x81, 0x01, 0x00]).read_octet_string().is_err());
+        assert!(Reader::new(&[0x04, 0x05, 0x00]).read_octet_string().is_err());
     }
 }
@@ -1,24 +1,108 @@
 use mpint::*;
 use der::*;
-#[allow(unused_imports)]
 use ssh::Signature;
 use serde_de::Error;
 use serde_de::ErrorKind::*;
+use serde_ser;
+use rand::Rng;
+use ring::signature::{EcdsaSigningAlgorithm, EcdsaVerificationAlgorithm};
 
-#[derive(Deserialize)]
-#[allow(dead_code)]
+pub const NISTP256 : &'static str = "nistp256";
+pub const NISTP384 : &'static str = "nistp384";
+
+struct CurveParams {
+    signing: &'static EcdsaSigningAlgorithm,
+    verification: &'static EcdsaVerificationAlgorithm,
+    scalar_len: usize,
+}
+
+fn curve_params(curve: &str) -> Result<CurveParams, Error> {
+    use ring::signature;
+    match curve {
+        NISTP256 => Ok(CurveParams{
+            signing: &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            verification: &signature::ECDSA_P256_SHA256_ASN1,
+            scalar_len: 32,
+        }),
+        NISTP384 => Ok(CurveParams{
+            signing: &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+            verification: &signature::ECDSA_P384_SHA384_ASN1,
+            scalar_len: 48,
+        }),
+        _ => Err(Error{kind: UnsupportedAlgorithm(curve.into())}),
+    }
+}
+
+//  DER encodings of the named curve OIDs (prime256v1 and secp384r1).
+pub const NISTP256_OID : &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+pub const NISTP384_OID : &'static [u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+pub fn curve_oid(curve: &str) -> Result<&'static [u8], Error> {
+    match curve {
+        NISTP256 => Ok(NISTP256_OID),
+        NISTP384 => Ok(NISTP384_OID),
+        _ => Err(Error{kind: UnsupportedAlgorithm(curve.into())}),
+    }
+}
+
+pub fn curve_for_oid(oid: &[u8]) -> Result<&'static str, Error> {
+    if oid == NISTP256_OID {
+        Ok(NISTP256)
+    } else if oid == NISTP384_OID {
+        Ok(NISTP384)
+    } else {
+        Err(Error{kind: UnsupportedAlgorithm("unknown curve".into())})
+    }
+}
+
+//  Uncompressed public point for a private scalar, or an error if the scalar is not in [1, n).
+fn public_key_for_scalar(curve: &str, scalar: &[u8]) -> Result<Vec<u8>, Error> {
+    match curve {
+        NISTP256 => {
+            use p256::elliptic_curve::sec1::ToEncodedPoint;
+            let sk = p256::SecretKey::from_slice(scalar).map_err(|_| Error{kind: InvalidKey})?;
+            Ok(sk.public_key().to_encoded_point(false).as_bytes().to_vec())
+        },
+        NISTP384 => {
+            use p384::elliptic_curve::sec1::ToEncodedPoint;
+            let sk = p384::SecretKey::from_slice(scalar).map_err(|_| Error{kind: InvalidKey})?;
+            Ok(sk.public_key().to_encoded_point(false).as_bytes().to_vec())
+        },
+        _ => Err(Error{kind: UnsupportedAlgorithm(curve.into())}),
+    }
+}
+
+pub fn key_type_for_curve(curve: &str) -> String {
+    format!("ecdsa-sha2-{}", curve)
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct ECDSAPublicKey {
-    _type: String,
-    curve: String,
+    pub _type: String,
+    pub curve: String,
     pub public_key: Vec<u8>,
 }
 
+impl ECDSAPublicKey {
+    pub fn verify(&self, signature: &ECCurvePoint, message: &[u8]) -> bool {
+        use ring::signature;
+        if self._type != key_type_for_curve(&self.curve) {
+            return false;
+        }
+        let params = match curve_params(&self.curve) {
+            Ok(params) => params,
+            _ => return false,
+        };
+        let public_key = signature::UnparsedPublicKey::new(params.verification, &self.public_key);
+        public_key.verify(message, &signature.to_der()).is_ok()
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ECDSASha2Nistp256PublicKey {
     x: MPUint,
     y: MPUint,
 }
-const SEQUENCE_TAG : u8 = 0x30;
 impl ECDSASha2Nistp256PublicKey {
     pub fn to_der(&self) -> Vec<u8> {
         let mut der_out = vec![SEQUENCE_TAG];
@@ -53,10 +137,10 @@ impl ECDSASha2Nistp256PublicKey {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ECCurvePoint {
-    x: MPUint,
-    y: MPUint,
+    pub x: MPUint,
+    pub y: MPUint,
 }
 
 impl ECCurvePoint {
@@ -71,6 +155,129 @@ impl ECCurvePoint {
 
 }
 
+#[derive(Clone)]
+pub struct ECDSAPrivateKey {
+    pub curve: String,
+    pub public_key: Vec<u8>,
+    pub private_key: MPUint,
+}
+
+impl ECDSAPrivateKey {
+    pub fn generate<R: Rng>(curve: &str, rng: &mut R) -> Result<Self, Error> {
+        let params = curve_params(curve)?;
+        let mut scalar = vec![0; params.scalar_len];
+        //  Rejection-sample until the scalar falls in [1, n).
+        loop {
+            rng.fill_bytes(&mut scalar);
+            let public_key = match public_key_for_scalar(curve, &scalar) {
+                Ok(public_key) => public_key,
+                _ => continue,
+            };
+            return Ok(ECDSAPrivateKey{
+                curve: curve.into(),
+                public_key: public_key,
+                private_key: MPUint::from_be_bytes(&scalar),
+            });
+        }
+    }
+
+    /// SEC1 ECPrivateKey. PKCS#8 wraps it without the curve parameters, which
+    /// it carries in the algorithm identifier instead.
+    pub fn to_sec1_der(&self, include_curve: bool) -> Result<Vec<u8>, Error> {
+        let params = curve_params(&self.curve)?;
+        let oid = curve_oid(&self.curve)?;
+        let mut writer = Writer::new();
+        writer.write_sequence(|w| {
+            w.write_small_integer(1);
+            w.write_octet_string(&self.private_key.padded_to_at_least(params.scalar_len));
+            if include_curve {
+                w.write_constructed(CONTEXT_0_TAG, |w| w.write_oid(oid));
+            }
+            w.write_constructed(CONTEXT_1_TAG, |w| w.write_bit_string(&self.public_key));
+        });
+        Ok(writer.into_inner())
+    }
+
+    /// Parses a SEC1 ECPrivateKey; `curve` must be given when the key omits its parameters.
+    pub fn from_sec1_der(der: &[u8], curve: Option<&str>) -> Result<Self, Error> {
+        let mut reader = Reader::new(der);
+        let mut sequence = reader.read_sequence()?;
+        reader.finish()?;
+        if sequence.read_small_integer()? != 1 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let scalar = sequence.read_octet_string()?;
+        let embedded_curve = match sequence.read_optional(CONTEXT_0_TAG)? {
+            Some(params) => {
+                let mut params = Reader::new(params);
+                let curve = curve_for_oid(params.read_oid()?)?;
+                params.finish()?;
+                Some(curve)
+            },
+            None => None,
+        };
+        let curve = match (embedded_curve, curve) {
+            (Some(embedded), Some(expected)) if embedded != expected => return Err(Error{kind: InvalidKey}),
+            (Some(curve), _) | (None, Some(curve)) => curve,
+            (None, None) => return Err(Error{kind: InvalidFormat}),
+        };
+        let derived_public_key = public_key_for_scalar(curve, scalar)?;
+        if let Some(public_key) = sequence.read_optional(CONTEXT_1_TAG)? {
+            let mut public_key = Reader::new(public_key);
+            if public_key.read_bit_string()? != &derived_public_key[..] {
+                return Err(Error{kind: InvalidKey});
+            }
+            public_key.finish()?;
+        }
+        sequence.finish()?;
+        Ok(ECDSAPrivateKey{
+            curve: curve.into(),
+            public_key: derived_public_key,
+            private_key: MPUint::from_be_bytes(scalar),
+        })
+    }
+
+    /// Builds a key from its private scalar, deriving the public point.
+    pub fn from_scalar(curve: &str, scalar: &MPUint) -> Result<Self, Error> {
+        let params = curve_params(curve)?;
+        Ok(ECDSAPrivateKey{
+            curve: curve.into(),
+            public_key: public_key_for_scalar(curve, &scalar.padded_to_at_least(params.scalar_len))?,
+            private_key: MPUint::from_be_bytes(&scalar.be_bytes),
+        })
+    }
+
+    pub fn public_key(&self) -> ECDSAPublicKey {
+        ECDSAPublicKey{
+            _type: key_type_for_curve(&self.curve),
+            curve: self.curve.clone(),
+            public_key: self.public_key.clone(),
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        use ring::signature::EcdsaKeyPair;
+        use ring::rand::SystemRandom;
+        let params = curve_params(&self.curve)?;
+        let rng = SystemRandom::new();
+        let key_pair = EcdsaKeyPair::from_private_key_and_public_key(
+            params.signing,
+            &self.private_key.padded_to_at_least(params.scalar_len),
+            &self.public_key,
+            &rng).map_err(|_| Error{kind: InvalidKey})?;
+        let fixed = key_pair.sign(&rng, message).map_err(|_| Error{kind: Crypto})?;
+        let (r, s) = fixed.as_ref().split_at(params.scalar_len);
+        let point = ECCurvePoint{
+            x: MPUint::from_be_bytes(r),
+            y: MPUint::from_be_bytes(s),
+        };
+        Ok(Signature{
+            _type: key_type_for_curve(&self.curve),
+            blob: serde_ser::to_vec(&point)?,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::base64;
@@ -91,5 +298,20 @@ mod test {
         let message_bytes = base64::decode("uq2Iv1L7fiubcl62XhClsJQWZ4s0zfW7qCj97vTaemA=").unwrap();
 
         assert!(ecdsa_pubkey_point.verify(&ecdsa_signature, &message_bytes));
+        assert!(ecdsa_pubkey.verify(&ecdsa_signature, &message_bytes));
+    }
+
+    #[test]
+    fn ecdsa_signs_and_verifies() {
+        use rand::{SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for curve in &[NISTP256, NISTP384] {
+            let private_key = ECDSAPrivateKey::generate(curve, &mut rng).unwrap();
+            let signature = private_key.sign(b"message").unwrap();
+            assert!(signature._type == key_type_for_curve(curve));
+            let point : ECCurvePoint = serde_de::from_slice(&signature.blob).unwrap();
+            assert!(private_key.public_key().verify(&point, b"message"));
+            assert!(!private_key.public_key().verify(&point, b"other message"));
+        }
     }
 }
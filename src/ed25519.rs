@@ -1,7 +1,13 @@
-#[derive(Deserialize)]
+use rand::Rng;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+pub const ED25519_TYPE : &'static str = "ssh-ed25519";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Ed25519PublicKey {
-    _type: String,
-    public_key: Vec<u8>,
+    pub _type: String,
+    pub public_key: Vec<u8>,
 }
 
 impl Ed25519PublicKey {
@@ -13,10 +19,51 @@ impl Ed25519PublicKey {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Ed25519Signature {
-    _type: String,
-    signature: Vec<u8>,
+    pub _type: String,
+    pub signature: Vec<u8>,
+}
+
+//  OpenSSH keeps the 32-byte seed; ring expands it into the signing key on demand.
+#[derive(Clone)]
+pub struct Ed25519PrivateKey {
+    pub seed: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl Ed25519PrivateKey {
+    pub fn generate<R: Rng>(rng: &mut R) -> Result<Self, Error> {
+        let mut seed = vec![0; 32];
+        rng.fill_bytes(&mut seed);
+        Ed25519PrivateKey::from_seed(&seed)
+    }
+
+    pub fn from_seed(seed: &[u8]) -> Result<Self, Error> {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed).map_err(|_| Error{kind: InvalidKey})?;
+        Ok(Ed25519PrivateKey{
+            seed: seed.to_vec(),
+            public_key: key_pair.public_key().as_ref().to_vec(),
+        })
+    }
+
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        Ed25519PublicKey{
+            _type: ED25519_TYPE.into(),
+            public_key: self.public_key.clone(),
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Ed25519Signature, Error> {
+        use ring::signature::Ed25519KeyPair;
+        let key_pair = Ed25519KeyPair::from_seed_and_public_key(&self.seed, &self.public_key)
+            .map_err(|_| Error{kind: InvalidKey})?;
+        Ok(Ed25519Signature{
+            _type: ED25519_TYPE.into(),
+            signature: key_pair.sign(message).as_ref().to_vec(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -37,4 +84,14 @@ mod test {
 
         assert!(pubkey.verify(&sig, &message_bytes));
     }
+
+    #[test]
+    fn ed25519_signs_and_verifies() {
+        use rand::{SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let private_key = Ed25519PrivateKey::generate(&mut rng).unwrap();
+        let sig = private_key.sign(b"message").unwrap();
+        assert!(private_key.public_key().verify(&sig, b"message"));
+        assert!(!private_key.public_key().verify(&sig, b"other message"));
+    }
 }
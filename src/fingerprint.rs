@@ -0,0 +1,160 @@
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use base64;
+use hex;
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlg {
+    Sha256,
+    Md5,
+}
+
+impl HashAlg {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlg::Sha256 => "SHA256",
+            HashAlg::Md5 => "MD5",
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            HashAlg::Sha256 => {
+                use ring::digest;
+                digest::digest(&digest::SHA256, data).as_ref().to_vec()
+            },
+            HashAlg::Md5 => {
+                use md5::{Md5, Digest};
+                Md5::digest(data).to_vec()
+            },
+        }
+    }
+}
+
+//  A public key fingerprint as printed by `ssh-keygen -l`: `SHA256:` followed by
+//  unpadded base64, or `MD5:` followed by colon-separated hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub hash_alg: HashAlg,
+    pub digest: Vec<u8>,
+}
+
+impl Fingerprint {
+    pub fn new(hash_alg: HashAlg, public_key_bytes: &[u8]) -> Self {
+        Fingerprint{
+            hash_alg: hash_alg,
+            digest: hash_alg.digest(public_key_bytes),
+        }
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.hash_alg {
+            HashAlg::Sha256 => write!(f, "SHA256:{}", base64::encode(&self.digest).trim_end_matches('=')),
+            HashAlg::Md5 => {
+                let pairs : Vec<String> = self.digest.iter().map(|b| hex::encode(&[*b])).collect();
+                write!(f, "MD5:{}", pairs.join(":"))
+            },
+        }
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = Error;
+
+    /// Parses either form; the `MD5:` prefix is optional, as older ssh-keygen omitted it.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        let (hash_alg, digest) = if s.starts_with("SHA256:") {
+            let mut body = s["SHA256:".len()..].to_string();
+            while body.len() % 4 != 0 {
+                body.push('=');
+            }
+            (HashAlg::Sha256, base64::decode(&body).map_err(|_| Error{kind: InvalidFormat})?)
+        } else {
+            let body = if s.starts_with("MD5:") { &s["MD5:".len()..] } else { s };
+            if body.split(':').any(|pair| pair.len() != 2) {
+                return Err(Error{kind: InvalidFormat});
+            }
+            (HashAlg::Md5, hex::decode(&body.replace(":", ""))?)
+        };
+        let expected_len = match hash_alg {
+            HashAlg::Sha256 => 32,
+            HashAlg::Md5 => 16,
+        };
+        if digest.len() != expected_len {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(Fingerprint{
+            hash_alg: hash_alg,
+            digest: digest,
+        })
+    }
+}
+
+const FIELD_WIDTH : usize = 17;
+const FIELD_HEIGHT : usize = 9;
+const AUGMENTATION : &'static [u8] = b" .o+=*BOX@%&#/^SE";
+
+/// OpenSSH's "drunken bishop" visualisation of a fingerprint, framed with the
+/// key description (e.g. `ED25519 256`) above and the hash name below.
+pub fn randomart(fingerprint: &Fingerprint, title: &str) -> String {
+    let start = AUGMENTATION.len() - 2;
+    let end = AUGMENTATION.len() - 1;
+    let mut field = [[0usize; FIELD_HEIGHT]; FIELD_WIDTH];
+    let (mut x, mut y) = (FIELD_WIDTH / 2, FIELD_HEIGHT / 2);
+    for byte in &fingerprint.digest {
+        let mut input = *byte;
+        for _ in 0..4 {
+            x = if input & 0x1 != 0 { (x + 1).min(FIELD_WIDTH - 1) } else { x.saturating_sub(1) };
+            y = if input & 0x2 != 0 { (y + 1).min(FIELD_HEIGHT - 1) } else { y.saturating_sub(1) };
+            if field[x][y] < start - 1 {
+                field[x][y] += 1;
+            }
+            input >>= 2;
+        }
+    }
+    field[FIELD_WIDTH / 2][FIELD_HEIGHT / 2] = start;
+    field[x][y] = end;
+
+    let mut out = border(&format!("[{}]", title));
+    out.push('\n');
+    for y in 0..FIELD_HEIGHT {
+        out.push('|');
+        for x in 0..FIELD_WIDTH {
+            out.push(AUGMENTATION[field[x][y]] as char);
+        }
+        out.push_str("|\n");
+    }
+    out.push_str(&border(&format!("[{}]", fingerprint.hash_alg.name())));
+    out
+}
+
+//  A border line with `label` centred, biased left as in OpenSSH.
+fn border(label: &str) -> String {
+    let label = if label.len() > FIELD_WIDTH - 1 { "" } else { label };
+    let left = (FIELD_WIDTH - label.len()) / 2;
+    format!("+{}{}{}+", "-".repeat(left), label, "-".repeat(FIELD_WIDTH - left - label.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints_parse_and_print() {
+        for text in &["SHA256:J5FnSrIEM2V0e22jItR4gnnNFGpuqcsaNaAtuptsar0", "MD5:5b:c0:bd:03:c3:2f:7c:1f:b8:85:27:f1:af:d9:88:5a"] {
+            let fingerprint : Fingerprint = text.parse().unwrap();
+            assert!(fingerprint.to_string() == *text);
+        }
+        let bare : Fingerprint = "5b:c0:bd:03:c3:2f:7c:1f:b8:85:27:f1:af:d9:88:5a".parse().unwrap();
+        assert!(bare.hash_alg == HashAlg::Md5);
+        assert!("SHA256:J5FnSrIEM2V0e22jItR4gnnNFGpuqcsaNaAtupts".parse::<Fingerprint>().is_err());
+        assert!("MD5:5b:c0:bd".parse::<Fingerprint>().is_err());
+        assert!("MD5:5bc0:bd:03:c3:2f:7c:1f:b8:85:27:f1:af:d9:88:5a".parse::<Fingerprint>().is_err());
+    }
+}
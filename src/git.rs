@@ -0,0 +1,238 @@
+use allowed_signers::AllowedSigners;
+use fingerprint::{Fingerprint, HashAlg};
+use host_key_store::unix_time;
+use sshsig::SshSig;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+//  SSH signatures on git commits and tags (`gpg.format = ssh`). Git signs the raw
+//  object without its signature, with the SSHSIG namespace "git".
+
+pub const GIT_NAMESPACE : &'static str = "git";
+const SIGNATURE_HEADERS : &'static [&'static str] = &["gpgsig", "gpgsig-sha256"];
+const SIGNATURE_BEGIN : &'static str = "-----BEGIN SSH SIGNATURE-----";
+
+pub struct SignedObject {
+    /// The object as git hashes it for signing.
+    pub payload: Vec<u8>,
+    /// The armored signature.
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitSigner {
+    /// The matching allowed_signers entry's principals, comma-separated as
+    /// `ssh-keygen -Y find-principals` reports them. These are patterns, such as
+    /// `*@example.com`, rather than the identity of whoever signed.
+    pub principal_patterns: String,
+    /// For a signature made with a certificate, its principals that the entry
+    /// allows; these are concrete identities vouched for by the CA.
+    pub certified_principals: Vec<String>,
+    pub fingerprint: Fingerprint,
+}
+
+//  Splits at line ends, keeping the newline with each line.
+fn lines(object: &[u8]) -> Vec<&[u8]> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, byte) in object.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&object[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < object.len() {
+        lines.push(&object[start..]);
+    }
+    lines
+}
+
+/// Separates the signature from a raw commit or tag object, as git does. Commits
+/// carry it in a `gpgsig` header (`gpgsig-sha256` in SHA-256 repositories) with
+/// space-indented continuation lines; both headers are dropped from the payload.
+/// Tags carry it after the message, from the last line starting a signature; a
+/// commit without a signature header is unsigned, whatever its message holds.
+pub fn split_signature(object: &[u8]) -> Result<SignedObject, Error> {
+    let mut payload = vec![];
+    let mut signatures : Vec<(usize, Vec<u8>)> = vec![];
+    let mut in_signature = false;
+    let mut in_header = true;
+    let is_commit = object.starts_with(b"tree ");
+    for line in lines(object) {
+        if !in_header {
+            payload.extend_from_slice(line);
+            continue;
+        }
+        if in_signature && line.starts_with(b" ") {
+            signatures.last_mut().unwrap().1.extend_from_slice(&line[1..]);
+            continue;
+        }
+        in_signature = false;
+        let header = SIGNATURE_HEADERS.iter().position(|name| {
+            line.starts_with(name.as_bytes()) && line.get(name.len()) == Some(&b' ')
+        });
+        if let Some(header) = header {
+            in_signature = true;
+            signatures.push((header, line[SIGNATURE_HEADERS[header].len() + 1..].to_vec()));
+        } else {
+            in_header = line != b"\n";
+            payload.extend_from_slice(line);
+        }
+    }
+
+    if signatures.len() > 0 {
+        signatures.sort_by_key(|signature| signature.0);
+        let signature = String::from_utf8(signatures.swap_remove(0).1)?;
+        return Ok(SignedObject{
+            payload: payload,
+            signature: signature,
+        });
+    }
+    if is_commit {
+        return Err(Error{kind: InvalidFormat});
+    }
+
+    let mut start = None;
+    let mut offset = 0;
+    for line in lines(object) {
+        if line.starts_with(SIGNATURE_BEGIN.as_bytes()) {
+            start = Some(offset);
+        }
+        offset += line.len();
+    }
+    match start {
+        Some(start) => Ok(SignedObject{
+            payload: object[..start].to_vec(),
+            signature: String::from_utf8(object[start..].to_vec())?,
+        }),
+        None => Err(Error{kind: InvalidFormat}),
+    }
+}
+
+/// The committer or tagger timestamp, which git checks `valid-after` and
+/// `valid-before` against.
+pub fn signer_time(payload: &[u8]) -> Option<u64> {
+    for line in lines(payload) {
+        if line == b"\n" {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with("committer ") || line.starts_with("tagger ") {
+            return line.rfind('>')
+                .and_then(|i| line[i + 1..].split_whitespace().next())
+                .and_then(|time| time.parse().ok());
+        }
+    }
+    None
+}
+
+/// Verifies the signature on a raw commit or tag object against `allowed_signers`,
+/// like `git verify-commit` with `gpg.ssh.allowedSignersFile`. Returns `None` if
+/// the signature is invalid or no allowed_signers entry covers its key.
+pub fn verify_object(object: &[u8], allowed_signers: &AllowedSigners) -> Result<Option<GitSigner>, Error> {
+    let signed = split_signature(object)?;
+    let signature = SshSig::from_pem(&signed.signature)?;
+    let time = signer_time(&signed.payload).unwrap_or_else(unix_time);
+    let signer = match allowed_signers.find_signers(&signature, GIT_NAMESPACE, time).into_iter().next() {
+        Some(signer) => signer,
+        None => return Ok(None),
+    };
+    if !signature.verify_reader(GIT_NAMESPACE, &signed.payload[..])? {
+        return Ok(None);
+    }
+    Ok(Some(GitSigner{
+        principal_patterns: signer.principals.join(","),
+        certified_principals: match signature.certificate {
+            Some(ref certificate) => signer.certified_principals(certificate),
+            None => vec![],
+        },
+        fingerprint: signature.public_key.fingerprint(HashAlg::Sha256)?,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COMMIT : &'static str = "tree c49897f29f9819a0ab6850d7e22443508a1a29d5
+author Test <test@krypt.co> 1700000000 +0000
+committer Test <test@krypt.co> 1700000000 +0000
+gpgsig -----BEGIN SSH SIGNATURE-----
+ U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgroeyWmwlKehquSR2jkZFgxGZua
+ MSz8jDCXwbcnnhAE4AAAADZ2l0AAAAAAAAAAZzaGE1MTIAAABTAAAAC3NzaC1lZDI1NTE5
+ AAAAQHmjyooiU5wgHV+WfG5WLcBtAH+A+F0BCo9yAoa310HuQW0h2LEwW/12yCVZAvXGbF
+ LsJHPkWXBNtfwc3KQlgg8=
+ -----END SSH SIGNATURE-----
+
+Signed commit
+";
+
+    const TAG : &'static str = "object 72f813fc41ca7b230979c8e1318760f7b95aef7a
+type commit
+tag v1
+tagger Test <test@krypt.co> 1700000100 +0000
+
+Signed tag
+-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgroeyWmwlKehquSR2jkZFgxGZua
+MSz8jDCXwbcnnhAE4AAAADZ2l0AAAAAAAAAAZzaGE1MTIAAABTAAAAC3NzaC1lZDI1NTE5
+AAAAQPv9lLoWSppMGK+l7o5x8BhureRPmGTwJOSKSXrJaX5j684YR6JumKITBnhoc6/5JL
+7tP2zXdBhZV9YkYhJ1GAw=
+-----END SSH SIGNATURE-----
+";
+
+    const SIGNERS : &'static str = "test@krypt.co namespaces=\"git\",valid-before=\"20240101Z\" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK6HslpsJSnoarkkdo5GRYMRmbmjEs/Iwwl8G3J54QBO\n";
+
+    #[test]
+    fn commit_signature_splits() {
+        let signed = split_signature(COMMIT.as_bytes()).unwrap();
+        assert!(signed.payload == b"tree c49897f29f9819a0ab6850d7e22443508a1a29d5
+author Test <test@krypt.co> 1700000000 +0000
+committer Test <test@krypt.co> 1700000000 +0000
+
+Signed commit
+".to_vec());
+        assert!(signed.signature.starts_with("-----BEGIN SSH SIGNATURE-----\nU1NI"));
+        assert!(signed.signature.ends_with("\n-----END SSH SIGNATURE-----\n"));
+        assert!(signer_time(&signed.payload) == Some(1700000000));
+    }
+
+    #[test]
+    fn git_signatures_verify() {
+        let signers = AllowedSigners::parse(SIGNERS);
+        for object in &[COMMIT, TAG] {
+            let signer = verify_object(object.as_bytes(), &signers).unwrap().unwrap();
+            assert!(signer.principal_patterns == "test@krypt.co");
+            assert!(signer.certified_principals.len() == 0);
+            assert!(signer.fingerprint.to_string() == "SHA256:J5FnSrIEM2V0e22jItR4gnnNFGpuqcsaNaAtuptsar0");
+            let tampered = object.replace("Signed", "Forged");
+            assert!(verify_object(tampered.as_bytes(), &signers).unwrap().is_none());
+        }
+        //  The committer time falls outside the key's validity.
+        let expired = AllowedSigners::parse(&SIGNERS.replace("20240101Z", "20230101Z"));
+        assert!(verify_object(COMMIT.as_bytes(), &expired).unwrap().is_none());
+        let file_only = AllowedSigners::parse(&SIGNERS.replace("\"git\"", "\"file\""));
+        assert!(verify_object(TAG.as_bytes(), &file_only).unwrap().is_none());
+    }
+
+    #[test]
+    fn principal_patterns_are_reported() {
+        let signers = AllowedSigners::parse(&SIGNERS.replace("test@krypt.co", "*@krypt.co,!mallory@krypt.co"));
+        let signer = verify_object(COMMIT.as_bytes(), &signers).unwrap().unwrap();
+        assert!(signer.principal_patterns == "*@krypt.co,!mallory@krypt.co");
+        assert!(signer.certified_principals.len() == 0);
+    }
+
+    #[test]
+    fn unsigned_object_fails() {
+        //  A commit whose message holds a signature is still unsigned.
+        let commit = COMMIT.replace("gpgsig ", "message ").replace("\n ", "\n");
+        let commit = commit.replacen("\nmessage ", "\n\n", 1);
+        for object in &[b"tree c49897f29f9819a0ab6850d7e22443508a1a29d5\n\nUnsigned\n".to_vec(), commit.into_bytes()] {
+            match split_signature(object) {
+                Err(Error{kind: InvalidFormat}) => {},
+                _ => assert!("expected" == "InvalidFormat"),
+            }
+        }
+    }
+}
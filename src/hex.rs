@@ -0,0 +1,34 @@
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn encode_upper(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Decodes hex of either case.
+pub fn decode(hex: &str) -> Result<Vec<u8>, Error> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(Error{kind: InvalidFormat});
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| Error{kind: InvalidFormat}))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips() {
+        assert!(encode(&[0x00, 0xab, 0x7f]) == "00ab7f");
+        assert!(encode_upper(&[0x00, 0xab, 0x7f]) == "00AB7F");
+        assert!(decode("00aB7f").unwrap() == vec![0x00, 0xab, 0x7f]);
+        assert!(decode("0").is_err());
+        assert!(decode("zz").is_err());
+    }
+}
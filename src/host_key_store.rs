@@ -0,0 +1,423 @@
+use known_hosts::*;
+use public_key::PublicKey;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+use hex;
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+//  Trust-on-first-use host key pinning. Keys are tracked per host and key type,
+//  so a host may have one key of each type; a different key of a known type is a
+//  change that must be accepted explicitly.
+
+const FIRST_SEEN : &'static str = "first-seen=";
+const LAST_SEEN : &'static str = "last-seen=";
+
+#[derive(Clone)]
+pub struct HostKeyRecord {
+    pub public_key: PublicKey,
+    /// Seconds since the Unix epoch; `None` for keys recorded by other tools.
+    pub first_seen: Option<u64>,
+    pub last_seen: Option<u64>,
+}
+
+pub enum Trust {
+    /// The key was not known for its type and has now been recorded.
+    FirstUse,
+    /// The key matches the pinned key.
+    Known,
+    /// The host has a different pinned key of this type, which is not replaced.
+    Changed(Vec<HostKeyRecord>),
+    Revoked,
+}
+
+pub trait HostKeyStore {
+    fn check(&self, host: &str, port: u16, key: &PublicKey) -> Result<Check, Error>;
+
+    /// Every key pinned for the host, of any type.
+    fn records(&self, host: &str, port: u16) -> Result<Vec<HostKeyRecord>, Error>;
+
+    /// Pins `key` for the host, or updates its last-seen time if already pinned.
+    /// Never adds an entry when one already matches.
+    fn record(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<(), Error>;
+
+    /// Pins `key` in place of any keys of the same type, accepting a rotation.
+    fn replace(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<(), Error>;
+
+    fn verify(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<Trust, Error> {
+        match self.check(host, port, key)? {
+            Check::Revoked => Ok(Trust::Revoked),
+            Check::Match => {
+                self.record(host, port, key, now)?;
+                Ok(Trust::Known)
+            },
+            Check::Mismatch => {
+                let previous = self.records(host, port)?.into_iter()
+                    .filter(|record| record.public_key.key_type() == key.key_type())
+                    .collect();
+                Ok(Trust::Changed(previous))
+            },
+            Check::Unknown => {
+                self.record(host, port, key, now)?;
+                Ok(Trust::FirstUse)
+            },
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[derive(Default)]
+pub struct MemoryHostKeyStore {
+    pub hosts: Vec<(String, HostKeyRecord)>,
+    pub revoked: Vec<PublicKey>,
+}
+
+impl MemoryHostKeyStore {
+    pub fn new() -> Self {
+        MemoryHostKeyStore{
+            hosts: vec![],
+            revoked: vec![],
+        }
+    }
+}
+
+impl HostKeyStore for MemoryHostKeyStore {
+    fn check(&self, host: &str, port: u16, key: &PublicKey) -> Result<Check, Error> {
+        if self.revoked.contains(key) {
+            return Ok(Check::Revoked);
+        }
+        let records = self.records(host, port)?;
+        if records.iter().any(|record| record.public_key == *key) {
+            Ok(Check::Match)
+        } else if records.iter().any(|record| record.public_key.key_type() == key.key_type()) {
+            Ok(Check::Mismatch)
+        } else {
+            Ok(Check::Unknown)
+        }
+    }
+
+    fn records(&self, host: &str, port: u16) -> Result<Vec<HostKeyRecord>, Error> {
+        let name = host_name(host, port);
+        Ok(self.hosts.iter().filter(|entry| entry.0 == name).map(|entry| entry.1.clone()).collect())
+    }
+
+    fn record(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<(), Error> {
+        let name = host_name(host, port);
+        for entry in self.hosts.iter_mut() {
+            if entry.0 == name && entry.1.public_key == *key {
+                entry.1.last_seen = Some(now);
+                return Ok(());
+            }
+        }
+        self.hosts.push((name, HostKeyRecord{
+            public_key: key.clone(),
+            first_seen: Some(now),
+            last_seen: Some(now),
+        }));
+        Ok(())
+    }
+
+    fn replace(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<(), Error> {
+        let name = host_name(host, port);
+        self.hosts.retain(|entry| entry.0 != name || entry.1.public_key.key_type() != key.key_type());
+        self.record(host, port, key, now)
+    }
+}
+
+//  A known_hosts file, compatible with the ssh client, that keeps the audit trail
+//  as `first-seen=` and `last-seen=` tokens in the comments of entries it adds.
+//  Entries written by other tools or by hand are left as they are. The file is
+//  rewritten through a temporary file on every change.
+pub struct FileHostKeyStore {
+    pub path: PathBuf,
+    pub known_hosts: KnownHosts,
+    /// Whether new host names are hashed, like `HashKnownHosts yes`.
+    pub hash_hosts: bool,
+}
+
+impl FileHostKeyStore {
+    /// Opens the store at `path`; a missing file is an empty store.
+    pub fn open<P: AsRef<Path>>(path: P, hash_hosts: bool) -> Result<Self, Error> {
+        let text = match fs::read_to_string(path.as_ref()) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error{kind: Io(e)}),
+        };
+        Ok(FileHostKeyStore{
+            path: path.as_ref().to_path_buf(),
+            known_hosts: KnownHosts::parse(&text),
+            hash_hosts: hash_hosts,
+        })
+    }
+
+    //  Writes a uniquely named file beside the old one, flushed to disk, and renames
+    //  it into place, so that readers and concurrent savers each see a whole file.
+    //  It takes the old file's permissions; a new file is only the user's.
+    fn save(&self) -> Result<(), Error> {
+        use rand::{OsRng, Rng};
+        let permissions = match fs::metadata(&self.path) {
+            Ok(metadata) => Some(metadata.permissions()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error{kind: Io(e)}),
+        };
+        let mut suffix = [0; 8];
+        OsRng::new()?.fill_bytes(&mut suffix);
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(format!(".{}.tmp", hex::encode(&suffix)));
+        let written = write_new(Path::new(&temp_path), self.known_hosts.to_string().as_bytes(), permissions)
+            .and_then(|_| fs::rename(&temp_path, &self.path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        Ok(written?)
+    }
+
+    //  Plain entries for the host that name only it, which are safe to edit or remove.
+    fn own_entry(entry: &KnownHost, host: &str, port: u16) -> bool {
+        entry.marker.is_none() && entry.hosts.len() == 1 && entry.matches_host(host, port)
+    }
+}
+
+fn write_new(path: &Path, contents: &[u8], permissions: Option<fs::Permissions>) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions)?;
+    }
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+fn audit_from_comment(comment: &str) -> (Option<u64>, Option<u64>) {
+    let find = |prefix: &str| comment.split_whitespace()
+        .find(|token| token.starts_with(prefix))
+        .and_then(|token| token[prefix.len()..].parse().ok());
+    (find(FIRST_SEEN), find(LAST_SEEN))
+}
+
+//  Sets the audit tokens in `comment`, keeping the rest of it as written.
+fn comment_with_audit(comment: &str, first_seen: u64, last_seen: u64) -> String {
+    let mut comment = comment.to_string();
+    for &(prefix, value) in &[(FIRST_SEEN, first_seen), (LAST_SEEN, last_seen)] {
+        let token = format!("{}{}", prefix, value);
+        let existing = comment.split_whitespace().find(|t| t.starts_with(prefix))
+            .map(|t| (t.as_ptr() as usize - comment.as_ptr() as usize, t.len()));
+        match existing {
+            Some((at, len)) => comment.replace_range(at..at + len, &token),
+            None if comment.len() == 0 => comment = token,
+            None => {
+                comment.push(' ');
+                comment.push_str(&token);
+            },
+        }
+    }
+    comment
+}
+
+//  Swaps the comment at the end of an entry's source line, keeping its spacing.
+fn source_with_comment(source: &str, comment: &str, new_comment: &str) -> Option<String> {
+    let end = source.trim_end().len();
+    if comment.len() == 0 || !source[..end].ends_with(comment) {
+        return None;
+    }
+    Some(format!("{}{}{}", &source[..end - comment.len()], new_comment, &source[end..]))
+}
+
+impl HostKeyStore for FileHostKeyStore {
+    fn check(&self, host: &str, port: u16, key: &PublicKey) -> Result<Check, Error> {
+        Ok(self.known_hosts.check(host, port, key))
+    }
+
+    fn records(&self, host: &str, port: u16) -> Result<Vec<HostKeyRecord>, Error> {
+        Ok(self.known_hosts.hosts().into_iter()
+            .filter(|entry| entry.marker.is_none() && entry.matches_host(host, port))
+            .map(|entry| {
+                let (first_seen, last_seen) = audit_from_comment(&entry.comment);
+                HostKeyRecord{
+                    public_key: entry.public_key.clone(),
+                    first_seen: first_seen,
+                    last_seen: last_seen,
+                }
+            })
+            .collect())
+    }
+
+    //  Only the first matching entry is updated, and only if it carries audit tokens;
+    //  a match on any entry, such as a wildcard or hashed line, adds nothing.
+    fn record(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<(), Error> {
+        let matched = self.known_hosts.entries.iter_mut().filter_map(|entry| match *entry {
+            Entry::Host(ref mut entry) => Some(entry),
+            _ => None,
+        }).find(|entry| entry.marker.is_none() && entry.public_key == *key && entry.matches_host(host, port));
+        if let Some(entry) = matched {
+            let (first_seen, last_seen) = audit_from_comment(&entry.comment);
+            match last_seen {
+                Some(last_seen) if last_seen != now => {},
+                _ => return Ok(()),
+            }
+            let comment = comment_with_audit(&entry.comment, first_seen.unwrap_or(now), now);
+            entry.source = entry.source.take().and_then(|source| source_with_comment(&source, &entry.comment, &comment));
+            entry.comment = comment;
+        } else {
+            self.known_hosts.add(host, port, key, self.hash_hosts)?;
+            let added = self.known_hosts.entries.iter_mut().rev().filter_map(|entry| match *entry {
+                Entry::Host(ref mut entry) => Some(entry),
+                _ => None,
+            }).next();
+            match added {
+                Some(entry) => entry.comment = comment_with_audit("", now, now),
+                None => return Err(Error{kind: InvalidFormat}),
+            }
+        }
+        self.save()
+    }
+
+    fn replace(&mut self, host: &str, port: u16, key: &PublicKey, now: u64) -> Result<(), Error> {
+        self.known_hosts.entries.retain(|entry| match *entry {
+            Entry::Host(ref entry) => !(FileHostKeyStore::own_entry(entry, host, port) &&
+                                        entry.public_key.key_type() == key.key_type()),
+            _ => true,
+        });
+        self.record(host, port, key, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::{KeyType, PrivateKey};
+
+    fn key(key_type: KeyType) -> PublicKey {
+        PrivateKey::generate(key_type).unwrap().0.public_key()
+    }
+
+    fn trust_on_first_use<S: HostKeyStore>(store: &mut S) {
+        let ed25519 = key(KeyType::Ed25519);
+        let ecdsa = key(KeyType::EcdsaSha2Nistp256);
+        let rotated = key(KeyType::Ed25519);
+
+        match store.verify("host.example.com", 22, &ed25519, 100).unwrap() {
+            Trust::FirstUse => {},
+            _ => assert!("expected" == "FirstUse"),
+        }
+        match store.verify("host.example.com", 22, &ecdsa, 110).unwrap() {
+            Trust::FirstUse => {},
+            _ => assert!("expected" == "FirstUse"),
+        }
+        match store.verify("host.example.com", 22, &ed25519, 200).unwrap() {
+            Trust::Known => {},
+            _ => assert!("expected" == "Known"),
+        }
+        match store.verify("host.example.com", 22, &rotated, 300).unwrap() {
+            Trust::Changed(previous) => {
+                assert!(previous.len() == 1);
+                assert!(previous[0].public_key == ed25519);
+                assert!(previous[0].first_seen == Some(100));
+                assert!(previous[0].last_seen == Some(200));
+            },
+            _ => assert!("expected" == "Changed"),
+        }
+        match store.verify("host.example.com", 2222, &rotated, 300).unwrap() {
+            Trust::FirstUse => {},
+            _ => assert!("expected" == "FirstUse"),
+        }
+
+        store.replace("host.example.com", 22, &rotated, 400).unwrap();
+        match store.verify("host.example.com", 22, &rotated, 500).unwrap() {
+            Trust::Known => {},
+            _ => assert!("expected" == "Known"),
+        }
+        let records = store.records("host.example.com", 22).unwrap();
+        assert!(records.len() == 2);
+        assert!(records.iter().any(|r| r.public_key == ecdsa && r.first_seen == Some(110)));
+        assert!(records.iter().any(|r| r.public_key == rotated && r.first_seen == Some(400) && r.last_seen == Some(500)));
+    }
+
+    #[test]
+    fn memory_store_pins_keys() {
+        let mut store = MemoryHostKeyStore::new();
+        trust_on_first_use(&mut store);
+        let revoked = key(KeyType::Ed25519);
+        store.revoked.push(revoked.clone());
+        match store.verify("other.example.com", 22, &revoked, 0).unwrap() {
+            Trust::Revoked => {},
+            _ => assert!("expected" == "Revoked"),
+        }
+    }
+
+    #[test]
+    fn file_store_pins_keys() {
+        use rand::{OsRng, Rng};
+        let path = ::std::env::temp_dir().join(format!("known_hosts_{}", OsRng::new().unwrap().next_u32()));
+        fs::write(&path, "# existing\n").unwrap();
+        {
+            let mut store = FileHostKeyStore::open(&path, true).unwrap();
+            trust_on_first_use(&mut store);
+        }
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# existing\n|1|"));
+        assert!(text.contains(" first-seen=400 last-seen=500\n"));
+        let store = FileHostKeyStore::open(&path, true).unwrap();
+        assert!(store.records("host.example.com", 22).unwrap().len() == 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_saves_keep_permissions() {
+        use rand::{OsRng, Rng};
+        use std::os::unix::fs::PermissionsExt;
+        let dir = ::std::env::temp_dir().join(format!("known_hosts_dir_{}", OsRng::new().unwrap().next_u32()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("known_hosts");
+        {
+            let mut store = FileHostKeyStore::open(&path, false).unwrap();
+            store.record("new.example.com", 22, &key(KeyType::Ed25519), 400).unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().permissions().mode() & 0o777 == 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        {
+            let mut store = FileHostKeyStore::open(&path, false).unwrap();
+            store.record("other.example.com", 22, &key(KeyType::Ed25519), 500).unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().permissions().mode() & 0o777 == 0o640);
+        assert!(fs::read_dir(&dir).unwrap().count() == 1);
+        assert!(FileHostKeyStore::open(&path, false).unwrap().known_hosts.entries.len() == 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_keeps_matching_lines() {
+        use rand::{OsRng, Rng};
+        let path = ::std::env::temp_dir().join(format!("known_hosts_{}", OsRng::new().unwrap().next_u32()));
+        let ed25519 = key(KeyType::Ed25519);
+        let ecdsa = key(KeyType::EcdsaSha2Nistp256);
+        let text = format!("host.example.com,alias  {}   my  key\n*.example.net {}\nother.example.com\t{}  note  first-seen=5   last-seen=6\n",
+                           ed25519.to_openssh_line("").unwrap(), ed25519.to_openssh_line("").unwrap(),
+                           ecdsa.to_openssh_line("").unwrap());
+        fs::write(&path, &text).unwrap();
+        let mut store = FileHostKeyStore::open(&path, false).unwrap();
+        for &(host, key) in &[("host.example.com", &ed25519), ("alias", &ed25519), ("www.example.net", &ed25519)] {
+            match store.verify(host, 22, key, 100).unwrap() {
+                Trust::Known => {},
+                _ => assert!("expected" == "Known"),
+            }
+        }
+        assert!(fs::read_to_string(&path).unwrap() == text);
+
+        match store.verify("other.example.com", 22, &ecdsa, 100).unwrap() {
+            Trust::Known => {},
+            _ => assert!("expected" == "Known"),
+        }
+        let updated = fs::read_to_string(&path).unwrap();
+        assert!(updated == text.replace("last-seen=6", "last-seen=100"));
+        assert!(store.records("other.example.com", 22).unwrap()[0].first_seen == Some(5));
+        fs::remove_file(&path).unwrap();
+    }
+}
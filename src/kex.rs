@@ -0,0 +1,198 @@
+use mpint::MPUint;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use serde_ser::Serializer;
+use userauth::verify_algorithm_signature;
+
+use ring::digest;
+
+//  The exchange hash H (RFC 4253 section 8, RFC 5656 section 4, RFC 8731) hashes both
+//  sides' identification strings and KEXINIT payloads, the server's host key, the
+//  ephemeral public values and the shared secret. The server signs H, and the first H
+//  of a connection is its session identifier.
+
+pub struct KexAlgorithm {
+    pub name: &'static str,
+    pub hash: &'static digest::Algorithm,
+    /// Finite field Diffie-Hellman, whose public values are mpints rather than strings.
+    pub dh_group: bool,
+    /// The length of each public value for curve25519; points and integers vary.
+    pub public_len: Option<usize>,
+}
+
+pub static KEX_ALGORITHMS : &'static [KexAlgorithm] = &[
+    KexAlgorithm{name: "curve25519-sha256", hash: &digest::SHA256, dh_group: false, public_len: Some(32)},
+    KexAlgorithm{name: "curve25519-sha256@libssh.org", hash: &digest::SHA256, dh_group: false, public_len: Some(32)},
+    KexAlgorithm{name: "ecdh-sha2-nistp256", hash: &digest::SHA256, dh_group: false, public_len: Some(65)},
+    KexAlgorithm{name: "ecdh-sha2-nistp384", hash: &digest::SHA384, dh_group: false, public_len: Some(97)},
+    KexAlgorithm{name: "ecdh-sha2-nistp521", hash: &digest::SHA512, dh_group: false, public_len: Some(133)},
+    KexAlgorithm{name: "diffie-hellman-group14-sha1", hash: &digest::SHA1_FOR_LEGACY_USE_ONLY, dh_group: true, public_len: None},
+    KexAlgorithm{name: "diffie-hellman-group14-sha256", hash: &digest::SHA256, dh_group: true, public_len: None},
+    KexAlgorithm{name: "diffie-hellman-group16-sha512", hash: &digest::SHA512, dh_group: true, public_len: None},
+    KexAlgorithm{name: "diffie-hellman-group18-sha512", hash: &digest::SHA512, dh_group: true, public_len: None},
+];
+
+pub fn kex_by_name(name: &str) -> Result<&'static KexAlgorithm, Error> {
+    KEX_ALGORITHMS.iter().find(|kex| kex.name == name).ok_or(Error{kind: UnsupportedAlgorithm(name.into())})
+}
+
+/// What both sides sent during a key exchange. Identification strings are without
+/// their CR LF and KEXINITs are whole payloads, message number included. Public values
+/// are as sent for the elliptic curve methods and big-endian integers for Diffie-Hellman,
+/// and the shared secret K is a big-endian integer (for curve25519, the X25519 output).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexTranscript {
+    pub algorithm: String,
+    pub client_identification: String,
+    pub server_identification: String,
+    pub client_kexinit: Vec<u8>,
+    pub server_kexinit: Vec<u8>,
+    pub host_key: Vec<u8>,
+    pub client_public: Vec<u8>,
+    pub server_public: Vec<u8>,
+    pub shared_secret: Vec<u8>,
+}
+
+impl KexTranscript {
+    /// Computes H with the method's hash, failing with `InvalidLength` for public values
+    /// of the wrong size and `Crypto` for a zero shared secret.
+    pub fn exchange_hash(&self) -> Result<Vec<u8>, Error> {
+        let kex = kex_by_name(&self.algorithm)?;
+        if let Some(public_len) = kex.public_len {
+            if self.client_public.len() != public_len || self.server_public.len() != public_len {
+                return Err(Error{kind: InvalidLength});
+            }
+        }
+        if self.shared_secret.iter().all(|b| *b == 0) {
+            return Err(Error{kind: Crypto});
+        }
+
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&self.client_identification)?;
+        ser.write(&self.server_identification)?;
+        ser.write(&self.client_kexinit)?;
+        ser.write(&self.server_kexinit)?;
+        ser.write(&self.host_key)?;
+        if kex.dh_group {
+            ser.write(&MPUint::from_be_bytes(&self.client_public))?;
+            ser.write(&MPUint::from_be_bytes(&self.server_public))?;
+        } else {
+            ser.write(&self.client_public)?;
+            ser.write(&self.server_public)?;
+        }
+        ser.write(&MPUint::from_be_bytes(&self.shared_secret))?;
+        Ok(digest::digest(kex.hash, &ser.into_inner()).as_ref().to_vec())
+    }
+
+    /// Verifies the server's signature over H with the negotiated host key algorithm,
+    /// returning H. A certified host key is checked against the key it certifies; whether
+    /// the key or its certificate authority is trusted is up to the caller.
+    pub fn verify_host_signature(&self, host_key_algorithm: &str, signature: &[u8]) -> Result<Vec<u8>, Error> {
+        let exchange_hash = self.exchange_hash()?;
+        if !verify_algorithm_signature(host_key_algorithm, &self.host_key, signature, &exchange_hash) {
+            return Err(Error{kind: Crypto});
+        }
+        Ok(exchange_hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex;
+
+    //  An OpenSSH 9.2 client's curve25519-sha256 exchange with an ssh-ed25519 host key;
+    //  the client accepted the signature over H.
+    fn captured_curve25519() -> KexTranscript {
+        KexTranscript{
+            algorithm: "curve25519-sha256".into(),
+            client_identification: "SSH-2.0-OpenSSH_9.2p1 Debian-2+deb12u6".into(),
+            server_identification: "SSH-2.0-krypton".into(),
+            client_kexinit: hex::decode("1471e1bdb4568c9e0732f40553e503b93500000039637572766532353531392d7368613235362c6578742d\
+                696e666f2d632c6b65782d7374726963742d632d763030406f70656e7373682e636f6d0000000b7373682d656432353531390000000a61\
+                65733132382d6374720000000a6165733132382d6374720000000d686d61632d736861322d3235360000000d686d61632d736861322d32\
+                35360000001a6e6f6e652c7a6c6962406f70656e7373682e636f6d2c7a6c69620000001a6e6f6e652c7a6c6962406f70656e7373682e63\
+                6f6d2c7a6c696200000000000000000000000000").unwrap(),
+            server_kexinit: hex::decode("14052464de1b63c0c2373b3f7904e6dfb800000011637572766532353531392d7368613235360000000b73\
+                73682d656432353531390000000a6165733132382d6374720000000a6165733132382d6374720000000d686d61632d736861322d323536\
+                0000000d686d61632d736861322d323536000000046e6f6e65000000046e6f6e6500000000000000000000000000").unwrap(),
+            host_key: hex::decode("0000000b7373682d6564323535313900000020730eee2fcb620ac36703ce80ae8f7aa9d01c3eb6fee1ad4438620d638358a6ed").unwrap(),
+            client_public: hex::decode("67dc8cc2074618d3db663df8e488a9e7c01770044069e6a77f1127d5901e7f58").unwrap(),
+            server_public: hex::decode("9571d8813df2d8db3858cbc12b9cc84c085cfe7fd4f6d812c28a03b18a948f03").unwrap(),
+            shared_secret: hex::decode("fdc90f5c5471a3163ed413f4338f7f2297e27de2bdb9ca5917267092277ed009").unwrap(),
+        }
+    }
+
+    const CURVE25519_H : &'static str = "d40361ddd6538e24971a1af0ac6be51c0c4ac0a4ffaccb39265c54675673ad93";
+    const CURVE25519_SIGNATURE : &'static str = "0000000b7373682d6564323535313900000040cf1f53aa714d978c2a604fba2879b895d85317a5dd27630850fc90\
+        c37ff81bae1cb2c7c0d40a6c6f52119082126d299383f72cf22d94f06efd42381f32864a01";
+
+    #[test]
+    fn captured_exchanges_verify() {
+        let transcript = captured_curve25519();
+        let signature = hex::decode(CURVE25519_SIGNATURE).unwrap();
+        assert!(hex::encode(&transcript.exchange_hash().unwrap()) == CURVE25519_H);
+        assert!(hex::encode(&transcript.verify_host_signature("ssh-ed25519", &signature).unwrap()) == CURVE25519_H);
+
+        //  The same client's ecdh-sha2-nistp256 exchange.
+        let transcript = KexTranscript{
+            algorithm: "ecdh-sha2-nistp256".into(),
+            client_kexinit: hex::decode("14a3404a24b9f0b0b18b32183d8e6f4b6c0000003a656364682d736861322d6e697374703235362c657874\
+                2d696e666f2d632c6b65782d7374726963742d632d763030406f70656e7373682e636f6d0000000b7373682d656432353531390000000a\
+                6165733132382d6374720000000a6165733132382d6374720000000d686d61632d736861322d3235360000000d686d61632d736861322d\
+                3235360000001a6e6f6e652c7a6c6962406f70656e7373682e636f6d2c7a6c69620000001a6e6f6e652c7a6c6962406f70656e7373682e\
+                636f6d2c7a6c696200000000000000000000000000").unwrap(),
+            server_kexinit: hex::decode("141481e960f516923ff27a73b06501edb400000012656364682d736861322d6e697374703235360000000b\
+                7373682d656432353531390000000a6165733132382d6374720000000a6165733132382d6374720000000d686d61632d736861322d3235\
+                360000000d686d61632d736861322d323536000000046e6f6e65000000046e6f6e6500000000000000000000000000").unwrap(),
+            host_key: hex::decode("0000000b7373682d6564323535313900000020cb25c2993392e1b7261ebba472682480e132a9d36ec5846eb69c3fbbe17d9339").unwrap(),
+            client_public: hex::decode("043030a629a4465ed273028bc0a61eab9615d07ae53f0919533b7bff137ed43431fb158e355a27753d7e369fa00bcd\
+                71a50e734f376ef93cffc013073a35609b3a").unwrap(),
+            server_public: hex::decode("04996f388e520856cb041a26aee03b27026e38499fe7e0fd77a4c00f3c4fed23c96349aa1aa56e9d33e41ca053a2\
+                59f1ffe651523cad381c9d2d41ce8bfdf2d7e2").unwrap(),
+            shared_secret: hex::decode("342684d55177f3f469219b5bd6e87f454ccd8a06595e4eb45eabc42108079e87").unwrap(),
+            ..transcript
+        };
+        let signature = hex::decode("0000000b7373682d6564323535313900000040994af4ba64056296537b42dc67edcf6ee8d2a034d8d4ccfda6acb4bd5d\
+            35b66d6cc7c3a6e514c8859fd064b8c9b74eba27b60e737c671ca9aa6b4d0308163b02").unwrap();
+        assert!(hex::encode(&transcript.verify_host_signature("ssh-ed25519", &signature).unwrap())
+            == "d7c40513a3e77c8a84a8a37b6f0aaf18d4b40b29ad08a6a3c06db8a60ea6639c");
+    }
+
+    #[test]
+    fn dh_values_are_mpints() {
+        let transcript = KexTranscript{
+            algorithm: "diffie-hellman-group16-sha512".into(),
+            client_identification: "SSH-2.0-client".into(),
+            server_identification: "SSH-2.0-server".into(),
+            client_kexinit: vec![20],
+            server_kexinit: vec![20, 1],
+            host_key: hex::decode("0000000b7373682d65643235353139").unwrap(),
+            client_public: vec![0, 0x80, 1],
+            server_public: vec![0x7f, 2],
+            shared_secret: vec![0xff; 3],
+        };
+        assert!(hex::encode(&transcript.exchange_hash().unwrap()) == "dc65031025e6f0da5274c47e8ab0a4d052ffa777b696f6289794d50e6099c83122e19be0aadf7cd68d690cc25c8b10cf8ae855a932afa307feddcd3d34c0e306");
+        let transcript = KexTranscript{algorithm: "diffie-hellman-group14-sha1".into(), ..transcript};
+        assert!(hex::encode(&transcript.exchange_hash().unwrap()) == "d65bdbdb42608371150a0df87db79907c03c9657");
+    }
+
+    #[test]
+    fn bad_exchanges_fail() {
+        let signature = hex::decode(CURVE25519_SIGNATURE).unwrap();
+        let mut transcript = captured_curve25519();
+        transcript.server_kexinit[1] ^= 1;
+        match transcript.verify_host_signature("ssh-ed25519", &signature) {
+            Err(Error{kind: Crypto}) => {},
+            _ => assert!("expected" == "Crypto"),
+        }
+        assert!(captured_curve25519().verify_host_signature("rsa-sha2-256", &signature).is_err());
+        let transcript = KexTranscript{client_public: vec![0; 31], ..captured_curve25519()};
+        match transcript.exchange_hash() {
+            Err(Error{kind: InvalidLength}) => {},
+            _ => assert!("expected" == "InvalidLength"),
+        }
+        assert!(KexTranscript{shared_secret: vec![0; 32], ..captured_curve25519()}.exchange_hash().is_err());
+        assert!(KexTranscript{algorithm: "diffie-hellman-group1-sha1".into(), ..captured_curve25519()}.exchange_hash().is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use public_key::PublicKey;
+use pattern;
+use cipher;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use base64;
+
+use std::fmt;
+
+//  The ssh client's known_hosts format: `[@marker] hosts key [comment]`, where
+//  hosts is a comma-separated pattern list or a single `|1|salt|hmac` hash.
+
+const HASH_MAGIC : &'static str = "|1|";
+const HASH_SALT_LEN : usize = 20;
+pub const DEFAULT_PORT : u16 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// A host name or wildcard pattern, possibly `[host]:port`.
+    Pattern{negated: bool, pattern: String},
+    /// HMAC-SHA1 of the host name keyed by the salt, as written by `ssh-keygen -H`.
+    Hashed{salt: Vec<u8>, hash: Vec<u8>},
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Result<HostPattern, Error> {
+        if pattern.starts_with(HASH_MAGIC) {
+            let mut parts = pattern[HASH_MAGIC.len()..].splitn(2, '|');
+            let salt = base64::decode(parts.next().unwrap_or("")).map_err(|_| Error{kind: InvalidFormat})?;
+            let hash = base64::decode(parts.next().unwrap_or("")).map_err(|_| Error{kind: InvalidFormat})?;
+            if salt.len() != HASH_SALT_LEN || hash.len() != HASH_SALT_LEN {
+                return Err(Error{kind: InvalidFormat});
+            }
+            return Ok(HostPattern::Hashed{salt: salt, hash: hash});
+        }
+        if pattern.len() == 0 || pattern == "!" {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(HostPattern::Pattern{
+            negated: pattern.starts_with('!'),
+            pattern: pattern.trim_start_matches('!').into(),
+        })
+    }
+
+    /// Hashes `host`, formatted as by `host_name`, with a random salt.
+    pub fn hashed(host: &str) -> Result<HostPattern, Error> {
+        use rand::{OsRng, Rng};
+        let mut salt = vec![0; HASH_SALT_LEN];
+        OsRng::new()?.fill_bytes(&mut salt);
+        let hash = hash_host(&salt, host);
+        Ok(HostPattern::Hashed{salt: salt, hash: hash})
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HostPattern::Pattern{negated, ref pattern} => write!(f, "{}{}", if negated { "!" } else { "" }, pattern),
+            HostPattern::Hashed{ref salt, ref hash} => write!(f, "{}{}|{}", HASH_MAGIC, base64::encode(salt), base64::encode(hash)),
+        }
+    }
+}
+
+fn hash_host(salt: &[u8], host: &str) -> Vec<u8> {
+    use ring::hmac;
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, salt), host.as_bytes()).as_ref().to_vec()
+}
+
+/// The name a host is recorded under: bare for port 22, else `[host]:port`.
+pub fn host_name(host: &str, port: u16) -> String {
+    let host = host.to_lowercase();
+    if port == DEFAULT_PORT {
+        host
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+#[derive(Clone)]
+pub struct KnownHost {
+    pub marker: Option<Marker>,
+    pub hosts: Vec<HostPattern>,
+    pub public_key: PublicKey,
+    pub comment: String,
+    /// The line as read, written back verbatim; set to `None` after editing
+    /// the fields to render them instead.
+    pub source: Option<String>,
+}
+
+impl KnownHost {
+    pub fn new(marker: Option<Marker>, hosts: Vec<HostPattern>, public_key: PublicKey) -> Self {
+        KnownHost{
+            marker: marker,
+            hosts: hosts,
+            public_key: public_key,
+            comment: String::new(),
+            source: None,
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let mut rest = line.trim();
+        let marker = if rest.starts_with('@') {
+            let (marker, after) = split_field(rest);
+            rest = after;
+            match marker {
+                "@cert-authority" => Some(Marker::CertAuthority),
+                "@revoked" => Some(Marker::Revoked),
+                _ => return Err(Error{kind: InvalidFormat}),
+            }
+        } else {
+            None
+        };
+        let (hosts, key) = split_field(rest);
+        let hosts = hosts.split(',').map(HostPattern::parse).collect::<Result<Vec<_>, _>>()?;
+        let (public_key, comment) = PublicKey::from_openssh_line(key)?;
+        Ok(KnownHost{
+            marker: marker,
+            hosts: hosts,
+            public_key: public_key,
+            comment: comment,
+            source: Some(line.into()),
+        })
+    }
+
+    /// Whether the host patterns match `host` on `port`; negated patterns veto.
+    pub fn matches_host(&self, host: &str, port: u16) -> bool {
+        let name = host_name(host, port);
+        let mut matched = false;
+        for pattern in &self.hosts {
+            match *pattern {
+                HostPattern::Hashed{ref salt, ref hash} => {
+                    if cipher::constant_time_eq(&hash_host(salt, &name), hash) {
+                        matched = true;
+                    }
+                },
+                HostPattern::Pattern{negated, ref pattern} => {
+                    match pattern::matches_list(&name, Some(pattern.as_str())) {
+                        Some(true) if negated => return false,
+                        Some(true) => matched = true,
+                        _ => {},
+                    }
+                },
+            }
+        }
+        matched
+    }
+}
+
+fn split_field(s: &str) -> (&str, &str) {
+    match s.find(|c: char| c == ' ' || c == '\t') {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+impl fmt::Display for KnownHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref source) = self.source {
+            return write!(f, "{}", source);
+        }
+        match self.marker {
+            Some(Marker::CertAuthority) => write!(f, "@cert-authority ")?,
+            Some(Marker::Revoked) => write!(f, "@revoked ")?,
+            None => {},
+        }
+        let hosts : Vec<String> = self.hosts.iter().map(|h| h.to_string()).collect();
+        let line = self.public_key.to_openssh_line(&self.comment).map_err(|_| fmt::Error)?;
+        write!(f, "{} {}", hosts.join(","), line)
+    }
+}
+
+#[derive(Clone)]
+pub enum Entry {
+    Blank(String),
+    Comment(String),
+    Host(KnownHost),
+    /// A line the ssh client would skip, such as an unsupported key type.
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// The key is recorded for the host.
+    Match,
+    /// The host has a different key of the same type: possible man-in-the-middle.
+    Mismatch,
+    /// The key is marked `@revoked`.
+    Revoked,
+    /// Nothing is recorded for the host with this key type.
+    Unknown,
+}
+
+#[derive(Clone)]
+pub struct KnownHosts {
+    pub entries: Vec<Entry>,
+}
+
+impl KnownHosts {
+    pub fn parse(text: &str) -> Self {
+        let entries = text.split('\n').map(|line| {
+            let trimmed = line.trim();
+            if trimmed.len() == 0 {
+                Entry::Blank(line.into())
+            } else if trimmed.starts_with('#') {
+                Entry::Comment(line.into())
+            } else {
+                match KnownHost::parse(line) {
+                    Ok(host) => Entry::Host(host),
+                    Err(_) => Entry::Invalid(line.into()),
+                }
+            }
+        }).collect();
+        KnownHosts{entries: entries}
+    }
+
+    pub fn hosts(&self) -> Vec<&KnownHost> {
+        self.entries.iter().filter_map(|entry| match *entry {
+            Entry::Host(ref host) => Some(host),
+            _ => None,
+        }).collect()
+    }
+
+    /// Looks up a host key like the ssh client: a revocation anywhere wins, then
+    /// an exact match, and a recorded key of the same type means the key changed.
+    pub fn check(&self, host: &str, port: u16, key: &PublicKey) -> Check {
+        let matching : Vec<&KnownHost> = self.hosts().into_iter()
+            .filter(|entry| entry.matches_host(host, port))
+            .collect();
+        if matching.iter().any(|entry| entry.marker == Some(Marker::Revoked) && entry.public_key == *key) {
+            return Check::Revoked;
+        }
+        let plain = matching.iter().filter(|entry| entry.marker.is_none());
+        if plain.clone().any(|entry| entry.public_key == *key) {
+            return Check::Match;
+        }
+        if plain.clone().any(|entry| entry.public_key.key_type() == key.key_type()) {
+            return Check::Mismatch;
+        }
+        Check::Unknown
+    }
+
+    /// CA keys trusted to sign host certificates for `host`.
+    pub fn cert_authorities(&self, host: &str, port: u16) -> Vec<&PublicKey> {
+        self.hosts().into_iter()
+            .filter(|entry| entry.marker == Some(Marker::CertAuthority) && entry.matches_host(host, port))
+            .map(|entry| &entry.public_key)
+            .collect()
+    }
+
+    /// Appends an entry for `host`, hashing the name if `hash` is set.
+    pub fn add(&mut self, host: &str, port: u16, key: &PublicKey, hash: bool) -> Result<(), Error> {
+        let name = host_name(host, port);
+        let pattern = if hash {
+            HostPattern::hashed(&name)?
+        } else {
+            HostPattern::Pattern{negated: false, pattern: name}
+        };
+        let entry = Entry::Host(KnownHost::new(None, vec![pattern], key.clone()));
+        //  Keep a trailing newline at the end of the file.
+        match self.entries.last() {
+            Some(&Entry::Blank(ref line)) if line.len() == 0 => {
+                let at = self.entries.len() - 1;
+                self.entries.insert(at, entry);
+            },
+            _ => {
+                self.entries.push(entry);
+                self.entries.push(Entry::Blank(String::new()));
+            },
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KnownHosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match *entry {
+                Entry::Blank(ref line) | Entry::Comment(ref line) | Entry::Invalid(ref line) => write!(f, "{}", line)?,
+                Entry::Host(ref host) => write!(f, "{}", host)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ED25519_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK6HslpsJSnoarkkdo5GRYMRmbmjEs/Iwwl8G3J54QBO";
+    const OTHER_ED25519_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK4WjSfJ9SmETrpAjw7+0znqMsHTXzY/b6AXCRoQzzuI";
+    const ECDSA_KEY : &'static str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBNZKo1XjDtLaWgnNwz4Aw6/blSKHIYYI9h3zkg7GerHJ4CEmqeXMQm1YaNqO3OZFk7pF4NGHGowIQ3puDDIEhzijUfYjgPqZR4+PZvMlk+GgG/SJpOloRRVOD3O4iR5X7A==";
+
+    fn key(line: &str) -> PublicKey {
+        PublicKey::from_openssh_line(line).unwrap().0
+    }
+
+    //  The hashed line was written by `ssh-keygen -H` for `hashed.example.com`.
+    fn known_hosts() -> String {
+        format!("# known hosts\n\
+github.com,192.0.2.1 {}\n\
+*.example.com,!bad.example.com {} wildcard\n\
+[git.example.org]:2222 {}\n\
+|1|NTCelfsO/NetuHhD+GWI4yf7X9w=|rECkDaD0I6XKUayZYkqMKzlpMjg= {}\n\
+@revoked * {}\n\
+@cert-authority *.corp.example.com {}\n\
+bad.example.com ssh-dss AAAAB3NzaC1kc3MAAACBAN\n", ED25519_KEY, ECDSA_KEY, ED25519_KEY, ED25519_KEY, OTHER_ED25519_KEY, ECDSA_KEY)
+    }
+
+    #[test]
+    fn known_hosts_round_trip() {
+        let text = known_hosts();
+        let file = KnownHosts::parse(&text);
+        assert!(file.to_string() == text);
+        assert!(file.hosts().len() == 6);
+        match file.entries[7] {
+            Entry::Invalid(_) => {},
+            _ => assert!("expected" == "Invalid"),
+        }
+    }
+
+    #[test]
+    fn hosts_are_checked() {
+        let file = KnownHosts::parse(&known_hosts());
+        assert!(file.check("github.com", 22, &key(ED25519_KEY)) == Check::Match);
+        assert!(file.check("GitHub.com", 22, &key(ED25519_KEY)) == Check::Match);
+        assert!(file.check("192.0.2.1", 22, &key(ED25519_KEY)) == Check::Match);
+        assert!(file.check("github.com", 2222, &key(ED25519_KEY)) == Check::Unknown);
+        assert!(file.check("github.com", 22, &key(ECDSA_KEY)) == Check::Unknown);
+        assert!(file.check("www.example.com", 22, &key(ECDSA_KEY)) == Check::Match);
+        assert!(file.check("bad.example.com", 22, &key(ECDSA_KEY)) == Check::Unknown);
+        assert!(file.check("git.example.org", 2222, &key(ED25519_KEY)) == Check::Match);
+        assert!(file.check("git.example.org", 22, &key(ED25519_KEY)) == Check::Unknown);
+        assert!(file.check("hashed.example.com", 22, &key(ED25519_KEY)) == Check::Match);
+        assert!(file.check("github.com", 22, &key(OTHER_ED25519_KEY)) == Check::Revoked);
+        assert!(file.cert_authorities("host.corp.example.com", 22).len() == 1);
+        assert!(file.cert_authorities("github.com", 22).len() == 0);
+    }
+
+    #[test]
+    fn changed_key_is_a_mismatch() {
+        let file = KnownHosts::parse(&format!("github.com {}\n", ED25519_KEY));
+        let (private_key, _) = ::private_key::PrivateKey::generate(::private_key::KeyType::Ed25519).unwrap();
+        assert!(file.check("github.com", 22, &private_key.public_key()) == Check::Mismatch);
+    }
+
+    #[test]
+    fn added_hosts_are_found() {
+        let mut file = KnownHosts::parse("# known hosts\n");
+        file.add("plain.example.com", 22, &key(ED25519_KEY), false).unwrap();
+        file.add("hashed.example.com", 2222, &key(ECDSA_KEY), true).unwrap();
+        let text = file.to_string();
+        assert!(text.starts_with(&format!("# known hosts\nplain.example.com {}\n|1|", ED25519_KEY)));
+        assert!(text.ends_with("\n"));
+        let file = KnownHosts::parse(&text);
+        assert!(file.check("plain.example.com", 22, &key(ED25519_KEY)) == Check::Match);
+        assert!(file.check("hashed.example.com", 2222, &key(ECDSA_KEY)) == Check::Match);
+        assert!(file.check("hashed.example.com", 22, &key(ECDSA_KEY)) == Check::Unknown);
+    }
+}
@@ -0,0 +1,557 @@
+use certificate::Certificate;
+use host_key_store::unix_time;
+use private_key::PrivateKey;
+use public_key::PublicKey;
+use serde_de::{Deserializer, Error};
+use serde_de::ErrorKind::*;
+use serde_ser::Serializer;
+use serde;
+
+use ring::digest;
+use std::io::Cursor;
+
+//  OpenSSH key revocation lists (PROTOCOL.krl), as made by `ssh-keygen -k`: a
+//  header followed by sections revoking certificates by serial or key ID per CA,
+//  and plain keys by blob or hash, optionally followed by signatures.
+
+pub const KRL_MAGIC : &'static [u8] = b"SSHKRL\n\0";
+const KRL_FORMAT_VERSION : u32 = 1;
+
+const SECTION_CERTIFICATES : u8 = 1;
+const SECTION_EXPLICIT_KEY : u8 = 2;
+const SECTION_FINGERPRINT_SHA1 : u8 = 3;
+const SECTION_SIGNATURE : u8 = 4;
+const SECTION_FINGERPRINT_SHA256 : u8 = 5;
+
+const SECTION_CERT_SERIAL_LIST : u8 = 0x20;
+const SECTION_CERT_SERIAL_RANGE : u8 = 0x21;
+const SECTION_CERT_SERIAL_BITMAP : u8 = 0x22;
+const SECTION_CERT_KEY_ID : u8 = 0x23;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct CertificateRevocations {
+    /// The issuing CA; `None` matches certificates from any CA by key ID, and
+    /// may not revoke serials.
+    pub ca_key: Option<PublicKey>,
+    /// Inclusive serial ranges. Serial zero cannot be revoked, as it is what
+    /// certificates carry when the CA did not assign one.
+    pub serials: Vec<(u64, u64)>,
+    pub key_ids: Vec<String>,
+}
+
+impl CertificateRevocations {
+    fn revokes(&self, certificate: &Certificate) -> bool {
+        if let Some(ref ca_key) = self.ca_key {
+            if *ca_key != certificate.signature_key {
+                return false;
+            }
+        }
+        if self.key_ids.contains(&certificate.key_id) {
+            return true;
+        }
+        self.ca_key.is_some() && certificate.serial != 0 &&
+            self.serials.iter().any(|&(lo, hi)| lo <= certificate.serial && certificate.serial <= hi)
+    }
+
+    //  Sorts and merges overlapping or adjacent ranges.
+    fn normalize(&mut self) {
+        self.serials.sort();
+        let mut merged : Vec<(u64, u64)> = vec![];
+        for &(lo, hi) in &self.serials {
+            match merged.last_mut() {
+                Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+                _ => merged.push((lo, hi)),
+            }
+        }
+        self.serials = merged;
+        self.key_ids.sort();
+        self.key_ids.dedup();
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(data);
+        let ca_key : Vec<u8> = next(&mut cursor)?;
+        let _reserved : Vec<u8> = next(&mut cursor)?;
+        let mut revocations = CertificateRevocations{
+            ca_key: if ca_key.len() == 0 { None } else { Some(PublicKey::from_bytes(&ca_key)?) },
+            serials: vec![],
+            key_ids: vec![],
+        };
+        while (cursor.position() as usize) < data.len() {
+            let section_type : u8 = next(&mut cursor)?;
+            let section : Vec<u8> = next(&mut cursor)?;
+            let mut section_cursor = Cursor::new(&section[..]);
+            let remaining = |cursor: &Cursor<&[u8]>| (cursor.position() as usize) < section.len();
+            //  A section for any CA may only revoke by key ID.
+            if revocations.ca_key.is_none() && section_type != SECTION_CERT_KEY_ID {
+                return Err(Error{kind: InvalidFormat});
+            }
+            match section_type {
+                SECTION_CERT_SERIAL_LIST => while remaining(&section_cursor) {
+                    let serial : u64 = next(&mut section_cursor)?;
+                    revocations.serials.push((serial, serial));
+                },
+                SECTION_CERT_SERIAL_RANGE => {
+                    let lo : u64 = next(&mut section_cursor)?;
+                    let hi : u64 = next(&mut section_cursor)?;
+                    if lo > hi {
+                        return Err(Error{kind: InvalidFormat});
+                    }
+                    revocations.serials.push((lo, hi));
+                },
+                SECTION_CERT_SERIAL_BITMAP => {
+                    //  Bit i of the mpint, counting from the least significant, revokes offset + i.
+                    //  Runs of set bits are kept as one range each.
+                    let offset : u64 = next(&mut section_cursor)?;
+                    let bitmap : Vec<u8> = next(&mut section_cursor)?;
+                    let mut run : Option<(u64, u64)> = None;
+                    for (i, byte) in bitmap.iter().rev().enumerate() {
+                        for bit in 0..8 {
+                            if byte & (1 << bit) != 0 {
+                                let serial = offset.checked_add((i * 8 + bit) as u64).ok_or(Error{kind: InvalidFormat})?;
+                                run = match run {
+                                    Some((lo, hi)) if hi + 1 == serial => Some((lo, serial)),
+                                    Some(previous) => {
+                                        revocations.serials.push(previous);
+                                        Some((serial, serial))
+                                    },
+                                    None => Some((serial, serial)),
+                                };
+                            }
+                        }
+                    }
+                    revocations.serials.extend(run);
+                },
+                SECTION_CERT_KEY_ID => while remaining(&section_cursor) {
+                    revocations.key_ids.push(next(&mut section_cursor)?);
+                },
+                _ => return Err(Error{kind: UnsupportedType}),
+            }
+            if remaining(&section_cursor) {
+                return Err(Error{kind: InvalidFormat});
+            }
+        }
+        if revocations.serials.iter().any(|&(lo, _)| lo == 0) {
+            return Err(Error{kind: InvalidFormat});
+        }
+        revocations.normalize();
+        Ok(revocations)
+    }
+
+    //  Single serials go in one list section and runs in range sections, rather
+    //  than picking the smallest encoding as ssh-keygen does.
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        if self.ca_key.is_none() && self.serials.len() > 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let mut ser = Serializer::new(Vec::new());
+        match self.ca_key {
+            Some(ref ca_key) => ser.write(&ca_key.to_bytes()?)?,
+            None => ser.write(&b""[..])?,
+        }
+        ser.write(&b""[..])?;
+        let singles : Vec<u64> = self.serials.iter().filter(|r| r.0 == r.1).map(|r| r.0).collect();
+        if singles.len() > 0 {
+            ser.write(&SECTION_CERT_SERIAL_LIST)?;
+            ser.write(&packed(&singles)?)?;
+        }
+        for &(lo, hi) in self.serials.iter().filter(|r| r.0 != r.1) {
+            ser.write(&SECTION_CERT_SERIAL_RANGE)?;
+            ser.write(&packed(&[lo, hi])?)?;
+        }
+        if self.key_ids.len() > 0 {
+            ser.write(&SECTION_CERT_KEY_ID)?;
+            ser.write(&packed(&self.key_ids)?)?;
+        }
+        Ok(ser.into_inner())
+    }
+}
+
+fn next<T>(cursor: &mut Cursor<&[u8]>) -> Result<T, Error> where T: for<'x> serde::Deserialize<'x> {
+    Deserializer::new(cursor).next()
+}
+
+fn packed<T: serde::Serialize>(values: &[T]) -> Result<Vec<u8>, Error> {
+    let mut ser = Serializer::new(Vec::new());
+    for value in values {
+        ser.write(value)?;
+    }
+    Ok(ser.into_inner())
+}
+
+fn sha1(blob: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, blob).as_ref().to_vec()
+}
+
+fn sha256(blob: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, blob).as_ref().to_vec()
+}
+
+#[derive(Clone)]
+pub struct Krl {
+    pub version: u64,
+    /// Unix seconds.
+    pub generated_date: u64,
+    pub flags: u64,
+    pub comment: String,
+    pub certificates: Vec<CertificateRevocations>,
+    /// Plain keys revoked by their full blob.
+    pub keys: Vec<PublicKey>,
+    /// SHA-1 and SHA-256 hashes of revoked plain key blobs.
+    pub sha1_hashes: Vec<Vec<u8>>,
+    pub sha256_hashes: Vec<Vec<u8>>,
+    /// Keys whose signatures over the KRL were verified when it was parsed.
+    pub signed_by: Vec<PublicKey>,
+}
+
+impl Krl {
+    pub fn new(version: u64, comment: &str) -> Self {
+        Krl{
+            version: version,
+            generated_date: unix_time(),
+            flags: 0,
+            comment: comment.into(),
+            certificates: vec![],
+            keys: vec![],
+            sha1_hashes: vec![],
+            sha256_hashes: vec![],
+            signed_by: vec![],
+        }
+    }
+
+    fn certificate_revocations(&mut self, ca_key: Option<&PublicKey>) -> &mut CertificateRevocations {
+        let position = self.certificates.iter().position(|c| c.ca_key.as_ref() == ca_key);
+        let position = match position {
+            Some(position) => position,
+            None => {
+                self.certificates.push(CertificateRevocations{
+                    ca_key: ca_key.cloned(),
+                    serials: vec![],
+                    key_ids: vec![],
+                });
+                self.certificates.len() - 1
+            },
+        };
+        &mut self.certificates[position]
+    }
+
+    pub fn revoke_key(&mut self, key: &PublicKey) {
+        if !self.keys.contains(key) {
+            self.keys.push(key.clone());
+        }
+    }
+
+    pub fn revoke_key_sha1(&mut self, key: &PublicKey) -> Result<(), Error> {
+        self.sha1_hashes.push(sha1(&key.to_bytes()?));
+        Ok(())
+    }
+
+    pub fn revoke_key_sha256(&mut self, key: &PublicKey) -> Result<(), Error> {
+        self.sha256_hashes.push(sha256(&key.to_bytes()?));
+        Ok(())
+    }
+
+    /// Revokes serials `lo` to `hi` inclusive of certificates issued by `ca_key`.
+    pub fn revoke_serials(&mut self, ca_key: &PublicKey, lo: u64, hi: u64) -> Result<(), Error> {
+        if lo == 0 || lo > hi {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let revocations = self.certificate_revocations(Some(ca_key));
+        revocations.serials.push((lo, hi));
+        revocations.normalize();
+        Ok(())
+    }
+
+    /// Revokes certificates by key ID, from `ca_key` or from any CA if `None`.
+    pub fn revoke_key_id(&mut self, ca_key: Option<&PublicKey>, key_id: &str) {
+        let revocations = self.certificate_revocations(ca_key);
+        revocations.key_ids.push(key_id.into());
+        revocations.normalize();
+    }
+
+    pub fn is_revoked(&self, key: &PublicKey) -> bool {
+        let blob = match key.to_bytes() {
+            Ok(blob) => blob,
+            _ => return true,
+        };
+        self.keys.contains(key) ||
+            self.sha1_hashes.contains(&sha1(&blob)) ||
+            self.sha256_hashes.contains(&sha256(&blob))
+    }
+
+    /// Like sshd, a certificate is revoked if its key or its CA's key is, as well
+    /// as by serial or key ID.
+    pub fn is_certificate_revoked(&self, certificate: &Certificate) -> bool {
+        self.is_revoked(&certificate.public_key) ||
+            self.is_revoked(&certificate.signature_key) ||
+            self.certificates.iter().any(|revocations| revocations.revokes(certificate))
+    }
+
+    /// Parses a binary KRL, verifying any signatures. Whether the signing keys
+    /// are trusted is for the caller to check against `signed_by`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if !bytes.starts_with(KRL_MAGIC) {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(KRL_MAGIC.len() as u64);
+        let format_version : u32 = next(&mut cursor)?;
+        if format_version != KRL_FORMAT_VERSION {
+            return Err(Error{kind: UnsupportedType});
+        }
+        let mut krl = Krl::new(next(&mut cursor)?, "");
+        krl.generated_date = next(&mut cursor)?;
+        krl.flags = next(&mut cursor)?;
+        let _reserved : Vec<u8> = next(&mut cursor)?;
+        krl.comment = next(&mut cursor)?;
+
+        while (cursor.position() as usize) < bytes.len() {
+            let section_type : u8 = next(&mut cursor)?;
+            let data : Vec<u8> = next(&mut cursor)?;
+            if section_type != SECTION_SIGNATURE && krl.signed_by.len() > 0 {
+                return Err(Error{kind: InvalidFormat});
+            }
+            match section_type {
+                SECTION_CERTIFICATES => krl.certificates.push(CertificateRevocations::parse(&data)?),
+                SECTION_EXPLICIT_KEY => {
+                    let mut section = Cursor::new(&data[..]);
+                    while (section.position() as usize) < data.len() {
+                        let blob : Vec<u8> = next(&mut section)?;
+                        krl.keys.push(PublicKey::from_bytes(&blob)?);
+                    }
+                },
+                SECTION_FINGERPRINT_SHA1 | SECTION_FINGERPRINT_SHA256 => {
+                    let (hashes, len) = if section_type == SECTION_FINGERPRINT_SHA1 {
+                        (&mut krl.sha1_hashes, 20)
+                    } else {
+                        (&mut krl.sha256_hashes, 32)
+                    };
+                    let mut section = Cursor::new(&data[..]);
+                    while (section.position() as usize) < data.len() {
+                        let hash : Vec<u8> = next(&mut section)?;
+                        if hash.len() != len {
+                            return Err(Error{kind: InvalidFormat});
+                        }
+                        hashes.push(hash);
+                    }
+                },
+                SECTION_SIGNATURE => {
+                    //  The signature covers everything before it, up to and including the signing key.
+                    let key = PublicKey::from_bytes(&data)?;
+                    let signed_len = cursor.position() as usize;
+                    let signature : Vec<u8> = next(&mut cursor)?;
+                    if !key.verify(&signature, &bytes[..signed_len]) {
+                        return Err(Error{kind: Crypto});
+                    }
+                    krl.signed_by.push(key);
+                },
+                _ => return Err(Error{kind: UnsupportedType}),
+            }
+        }
+        Ok(krl)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.to_bytes_signed(&[])
+    }
+
+    /// Serializes the KRL, signed by each of `signing_keys` in turn.
+    pub fn to_bytes_signed(&self, signing_keys: &[&PrivateKey]) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(KRL_MAGIC.to_vec());
+        ser.write(&KRL_FORMAT_VERSION)?;
+        ser.write(&self.version)?;
+        ser.write(&self.generated_date)?;
+        ser.write(&self.flags)?;
+        ser.write(&b""[..])?;
+        ser.write(&self.comment)?;
+
+        for revocations in &self.certificates {
+            ser.write(&SECTION_CERTIFICATES)?;
+            ser.write(&revocations.to_bytes()?)?;
+        }
+        //  ssh-keygen rejects KRLs whose keys and hashes are not in ascending order.
+        let blobs = self.keys.iter().map(|key| key.to_bytes()).collect::<Result<Vec<_>, _>>()?;
+        let sections = vec![(SECTION_EXPLICIT_KEY, blobs),
+                            (SECTION_FINGERPRINT_SHA1, self.sha1_hashes.clone()),
+                            (SECTION_FINGERPRINT_SHA256, self.sha256_hashes.clone())];
+        for (section_type, mut blobs) in sections {
+            if blobs.len() == 0 {
+                continue;
+            }
+            blobs.sort();
+            blobs.dedup();
+            ser.write(&section_type)?;
+            ser.write(&packed(&blobs)?)?;
+        }
+
+        let mut bytes = ser.into_inner();
+        for signing_key in signing_keys {
+            let mut ser = Serializer::new(bytes);
+            ser.write(&SECTION_SIGNATURE)?;
+            ser.write(&signing_key.public_key().to_bytes()?)?;
+            let signed = ser.into_inner();
+            let signature = signing_key.sign(&signed)?;
+            let mut ser = Serializer::new(signed);
+            ser.write(&signature)?;
+            bytes = ser.into_inner();
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use certificate::CertType;
+    use private_key::KeyType;
+    use base64;
+
+    //  `ssh-keygen -k -s ca.pub -z 7` revoking serials 1-5, 42, 100, 102 and 104 and key ID
+    //  bob@krypt.co, then updated with two explicit keys. The serials are encoded as a bitmap.
+    const KRL : &'static str = "U1NIS1JMCgAAAAABAAAAAAAAAAcAAAAAatXBDQAAAAAAAAAAAAAAAAAAAAABAAAAbwAAADMAAAALc3NoLWVkMjU1MTkAAAAgJJbWH6cLwPJP5547V1mUOej5bD6jNUllSUVsseuui8IAAAAAIgAAABoAAAAAAAAAAQAAAA4AqAAAAAAAAAIAAAAAHyMAAAAQAAAADGJvYkBrcnlwdC5jbwIAAABuAAAAMwAAAAtzc2gtZWQyNTUxOQAAACCuh7JabCUp6Gq5JHaORkWDEZm5oxLPyMMJfBtyeeEATgAAADMAAAALc3NoLWVkMjU1MTkAAAAgwLniMjP77+40CoDu6UrmhLhTnuYAHuIwIzSlC00H0po=";
+    //  `ssh-keygen -k` revoking RSA_KEY by SHA-256 fingerprint and REVOKED_KEY by SHA-1.
+    const HASH_KRL : &'static str = "U1NIS1JMCgAAAAABAAAAAAAAAAAAAAAAatXBEQAAAAAAAAAAAAAAAAAAAAADAAAAGAAAABTbT48/WLJn6aDilPE47UsPbbRXvAUAAAAkAAAAILETdJQzhJxcx0FrN0hwQfKQzoTdHYww0ot/hlzwTKdC";
+
+    const CA_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICSW1h+nC8DyT+eeO1dZlDno+Ww+ozVJZUlFbLHrrovC";
+    const ED25519_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK6HslpsJSnoarkkdo5GRYMRmbmjEs/Iwwl8G3J54QBO";
+    const REVOKED_KEY : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMC54jIz++/uNAqA7ulK5oS4U57mAB7iMCM0pQtNB9Ka";
+    const RSA_KEY : &'static str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDt0Ur4ZRYhdH9s+YSlhKvmOp1JnRqDUkMmRcJSWt8nKncLDI+UckdJg1FOQenIXTHzj/J//wwWDMWmdDbMyi6HdE2fTxGocc/WSAlrdpfxJoGi4uMsnpr0ohp/ieFOPpZfF6aXuXMCVWRaXq8S0d1M7i0xIq1gh3DSU9ixa6kVZt7haEzPRNFaF74UcI+86Qq63K6mjLGs8F2pY8XTFj1JyvcN8guQ2opKPuaVlWvDAyGoIMu2G2sYljNsSQkBoqJmL4HXTwPRI1LIoLPEPJfClFV4dxwuVN0rjYxrCML3+Qh4O5Ny0s5R2swvZ17xZaquMh/dr/wTbowZ5/XrifYt";
+    //  Serial 42 from CA_KEY, with key ID alice@krypt.co.
+    const USER_CERT : &'static str = "ecdsa-sha2-nistp384-cert-v01@openssh.com AAAAKGVjZHNhLXNoYTItbmlzdHAzODQtY2VydC12MDFAb3BlbnNzaC5jb20AAAAgQVqnmWxI7Qs07/M8wq1u9kfs+PVChfeGeyBkxrNGtE4AAAAIbmlzdHAzODQAAABhBNZKo1XjDtLaWgnNwz4Aw6/blSKHIYYI9h3zkg7GerHJ4CEmqeXMQm1YaNqO3OZFk7pF4NGHGowIQ3puDDIEhzijUfYjgPqZR4+PZvMlk+GgG/SJpOloRRVOD3O4iR5X7AAAAAAAAAAqAAAAAQAAAA5hbGljZUBrcnlwdC5jbwAAABMAAAAFYWxpY2UAAAAGZGVwbG95AAAAAF4L4QAAAAAAcNvYgAAAAEYAAAANZm9yY2UtY29tbWFuZAAAAA0AAAAJL2Jpbi90cnVlAAAADnNvdXJjZS1hZGRyZXNzAAAADgAAAAoxMC4wLjAuMC84AAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACAkltYfpwvA8k/nnjtXWZQ56PlsPqM1SWVJRWyx666LwgAAAFMAAAALc3NoLWVkMjU1MTkAAABA2nvN9gB6WB86rfH0oaTkLIYtSjK2yv+w7vHPpo8mwquJgzVIJNQ8jtic2HNGTn/UCNykITAHm+2E4fvZb+IpDw==";
+
+    fn key(line: &str) -> PublicKey {
+        PublicKey::from_openssh_line(line).unwrap().0
+    }
+
+    #[test]
+    fn ssh_keygen_krl_parses() {
+        let krl = Krl::from_bytes(&base64::decode(KRL).unwrap()).unwrap();
+        assert!(krl.version == 7);
+        assert!(krl.certificates.len() == 1);
+        assert!(krl.certificates[0].ca_key == Some(key(CA_KEY)));
+        assert!(krl.certificates[0].serials == vec![(1, 5), (42, 42), (100, 100), (102, 102), (104, 104)]);
+        assert!(krl.certificates[0].key_ids == vec!["bob@krypt.co"]);
+        assert!(krl.is_revoked(&key(ED25519_KEY)));
+        assert!(krl.is_revoked(&key(REVOKED_KEY)));
+        assert!(!krl.is_revoked(&key(RSA_KEY)));
+
+        let (mut cert, _) = Certificate::from_openssh_line(USER_CERT).unwrap();
+        assert!(krl.is_certificate_revoked(&cert));
+        cert.serial = 43;
+        assert!(!krl.is_certificate_revoked(&cert));
+        cert.key_id = "bob@krypt.co".into();
+        assert!(krl.is_certificate_revoked(&cert));
+        cert.serial = 0;
+        cert.key_id = "carol@krypt.co".into();
+        assert!(!krl.is_certificate_revoked(&cert));
+        cert.public_key = key(REVOKED_KEY);
+        assert!(krl.is_certificate_revoked(&cert));
+
+        let hash_krl = Krl::from_bytes(&base64::decode(HASH_KRL).unwrap()).unwrap();
+        assert!(hash_krl.is_revoked(&key(RSA_KEY)));
+        assert!(hash_krl.is_revoked(&key(REVOKED_KEY)));
+        assert!(!hash_krl.is_revoked(&key(ED25519_KEY)));
+    }
+
+    #[test]
+    fn built_krl_round_trips() {
+        let (ca, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (other_ca, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (signer, _) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        let mut krl = Krl::new(3, "compromised laptop");
+        krl.revoke_key(&key(ED25519_KEY));
+        krl.revoke_key(&key(RSA_KEY));
+        krl.revoke_key_sha1(&key(REVOKED_KEY)).unwrap();
+        krl.revoke_serials(&ca.public_key(), 10, 20).unwrap();
+        krl.revoke_serials(&ca.public_key(), 21, 21).unwrap();
+        krl.revoke_serials(&ca.public_key(), 30, 30).unwrap();
+        krl.revoke_key_id(None, "stolen");
+        assert!(krl.revoke_serials(&ca.public_key(), 0, 5).is_err());
+        assert!(krl.certificates[0].serials == vec![(10, 21), (30, 30)]);
+
+        let parsed = Krl::from_bytes(&krl.to_bytes_signed(&[&signer]).unwrap()).unwrap();
+        assert!(parsed.version == 3 && parsed.comment == "compromised laptop");
+        assert!(parsed.signed_by == vec![signer.public_key()]);
+        assert!(parsed.certificates == krl.certificates);
+        assert!(parsed.is_revoked(&key(RSA_KEY)) && parsed.is_revoked(&key(REVOKED_KEY)));
+
+        let (user, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let mut cert = Certificate::new(user.public_key(), CertType::User, "alice").unwrap();
+        cert.serial = 21;
+        cert.sign(&ca).unwrap();
+        assert!(parsed.is_certificate_revoked(&cert));
+        cert.sign(&other_ca).unwrap();
+        assert!(!parsed.is_certificate_revoked(&cert));
+        cert.key_id = "stolen".into();
+        assert!(parsed.is_certificate_revoked(&cert));
+    }
+
+    //  An unsigned KRL holding one certificate section with the given CA and subsection.
+    fn certificate_krl(ca_key: &[u8], section_type: u8, section: &[u8]) -> Vec<u8> {
+        let mut inner = Serializer::new(Vec::new());
+        inner.write(&ca_key).unwrap();
+        inner.write(&b""[..]).unwrap();
+        inner.write(&section_type).unwrap();
+        inner.write(&section).unwrap();
+        let mut bytes = Krl::new(1, "").to_bytes().unwrap();
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&SECTION_CERTIFICATES).unwrap();
+        ser.write(&inner.into_inner()).unwrap();
+        bytes.extend(ser.into_inner());
+        bytes
+    }
+
+    #[test]
+    fn invalid_certificate_sections_fail() {
+        let ca_key = key(CA_KEY).to_bytes().unwrap();
+        let serials = packed(&[5u64, 6u64]).unwrap();
+        assert!(Krl::from_bytes(&certificate_krl(&ca_key, SECTION_CERT_SERIAL_LIST, &serials)).is_ok());
+        assert!(Krl::from_bytes(&certificate_krl(&[], SECTION_CERT_KEY_ID, &packed(&["bob"]).unwrap())).is_ok());
+
+        let mut bitmap = Serializer::new(Vec::new());
+        bitmap.write(&(u64::max_value() - 3)).unwrap();
+        bitmap.write(&vec![0x80u8, 0x01]).unwrap();
+        let bitmap = bitmap.into_inner();
+        for bytes in &[
+            certificate_krl(&ca_key, SECTION_CERT_SERIAL_BITMAP, &bitmap),
+            certificate_krl(&[], SECTION_CERT_SERIAL_LIST, &serials),
+            certificate_krl(&[], SECTION_CERT_SERIAL_RANGE, &serials),
+        ] {
+            match Krl::from_bytes(bytes) {
+                Err(Error{kind: InvalidFormat}) => {},
+                _ => assert!("expected" == "InvalidFormat"),
+            }
+        }
+
+        let mut krl = Krl::new(1, "");
+        krl.certificates.push(CertificateRevocations{ca_key: None, serials: vec![(5, 5)], key_ids: vec![]});
+        assert!(krl.to_bytes().is_err());
+    }
+
+    #[test]
+    fn bitmap_runs_parse_as_ranges() {
+        //  Serials 10 to 10 + 64K - 1, then 10 + 64K + 1.
+        let mut bits = vec![0xffu8; 8192];
+        bits.insert(0, 0x02);
+        let mut bitmap = Serializer::new(Vec::new());
+        bitmap.write(&10u64).unwrap();
+        bitmap.write(&bits).unwrap();
+        let ca_key = key(CA_KEY).to_bytes().unwrap();
+        let krl = Krl::from_bytes(&certificate_krl(&ca_key, SECTION_CERT_SERIAL_BITMAP, &bitmap.into_inner())).unwrap();
+        assert!(krl.certificates[0].serials == vec![(10, 10 + 65535), (10 + 65537, 10 + 65537)]);
+    }
+
+    #[test]
+    fn bad_signature_fails() {
+        let (signer, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let mut krl = Krl::new(1, "");
+        krl.revoke_key(&key(ED25519_KEY));
+        let mut bytes = krl.to_bytes_signed(&[&signer]).unwrap();
+        let at = bytes.len() - 1;
+        bytes[at] ^= 1;
+        match Krl::from_bytes(&bytes) {
+            Err(Error{kind: Crypto}) => {},
+            _ => assert!("expected" == "Crypto"),
+        }
+    }
+}
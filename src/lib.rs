@@ -6,15 +6,60 @@ extern crate byteorder;
 extern crate ring;
 extern crate base64;
 extern crate libc;
+extern crate rand;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_traits;
+extern crate p256;
+extern crate p384;
+extern crate aes;
+extern crate ctr;
+extern crate chacha20;
+extern crate poly1305;
+extern crate bcrypt_pbkdf;
+extern crate cbc;
+extern crate des;
+extern crate md5;
+extern crate argon2;
 
 pub mod der;
 pub mod serde_de;
+pub mod serde_ser;
 pub mod mpint;
 pub mod ecdsa;
 pub mod ssh;
 pub mod ed25519;
 pub mod rsa;
 pub mod dss;
+pub mod private_key;
+pub mod public_key;
+pub mod certificate;
+pub mod krl;
+pub mod fingerprint;
+pub mod sshsig;
+pub mod allowed_signers;
+pub mod git;
+pub mod authorized_keys;
+pub mod known_hosts;
+pub mod host_key_store;
+pub mod pattern;
+pub mod rfc4716;
+pub mod pem;
+pub mod hex;
+pub mod cipher;
+pub mod openssh_key;
+pub mod pkcs;
+pub mod ppk;
+pub mod agent;
+pub mod agent_constraints;
+#[cfg(unix)]
+pub mod agent_server;
+#[cfg(unix)]
+pub mod agent_client;
+pub mod userauth;
+pub mod packet;
+pub mod transport;
+pub mod kex;
 
 #[no_mangle]
     pub extern "C" fn kr_verify_signature(
@@ -70,14 +115,9 @@ pub fn verify_signature(pubkey: &[u8], sig: &[u8], msg: &[u8]) -> bool {
                 _ => return false,
             }
         },
-        "ecdsa-sha2-nistp256" => {
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" => {
             let pk = match serde_de::from_slice::<ECDSAPublicKey>(pubkey) {
-                Ok(pk_wrapper) => {
-                    match ECDSASha2Nistp256PublicKey::x962_uncompressed(&pk_wrapper.public_key) {
-                        Ok(pk) => pk,
-                        _ => return false,
-                    }
-                },
+                Ok(pk) => pk,
                 _ => return false,
             };
             let sig = match serde_de::from_slice::<Signature>(sig) {
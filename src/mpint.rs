@@ -5,11 +5,12 @@ use std::convert::AsRef;
 
 use serde::de;
 use serde::de::{Deserialize,Deserializer};
+use serde::ser::{Serialize,Serializer};
 
 //  Non-negative multi-precision integers in the SSH wire protocol begin with a \x00 byte to
 //  distinguish from negative. Some libraries like ring do not expect a leading \x00 byte,
 //  causing length checks to fail.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MPUint {
     pub be_bytes: Vec<u8>,
 }
@@ -37,6 +38,19 @@ impl MPUint {
         der_out.extend(&self.be_bytes);
         der_out
     }
+    pub fn from_be_bytes(bytes: &[u8]) -> MPUint {
+        let first_nonzero = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        MPUint{be_bytes: bytes[first_nonzero..].to_vec()}
+    }
+    pub fn to_wire_bytes(&self) -> Vec<u8> {
+        let stripped = MPUint::from_be_bytes(&self.be_bytes).be_bytes;
+        let mut wire = vec![];
+        if stripped.len() > 0 && (stripped[0] & 0x80) == 0x80 {
+            wire.push(0x00);
+        }
+        wire.extend(stripped);
+        wire
+    }
     pub fn padded_to_at_least(&self, n_bytes: usize) -> Vec<u8> {
         if n_bytes <= self.be_bytes.len() {
             self.be_bytes.clone()
@@ -71,6 +85,13 @@ impl<'x> Deserialize<'x> for MPUint {
         }
 }
 
+impl Serialize for MPUint {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+            serializer.serialize_bytes(&self.to_wire_bytes())
+        }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -82,4 +103,11 @@ mod test {
         assert!(MPUint{be_bytes: vec![128]}.to_der() == vec![0x02, 0x02, 0x00, 0x80]);
         assert!(MPUint{be_bytes: vec![1, 0]}.to_der() == vec![0x02, 0x02, 0x01, 0x00]);
     }
+
+    #[test]
+    fn mpuint_wire_works() {
+        assert!(MPUint{be_bytes: vec![]}.to_wire_bytes() == vec![]);
+        assert!(MPUint{be_bytes: vec![0, 0, 0x7f]}.to_wire_bytes() == vec![0x7f]);
+        assert!(MPUint{be_bytes: vec![0x80, 0x01]}.to_wire_bytes() == vec![0x00, 0x80, 0x01]);
+    }
 }
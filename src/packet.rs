@@ -0,0 +1,299 @@
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use transport::{PacketCipher, Plain};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::{OsRng, Rng};
+use std::io::{Read, Write};
+
+//  The SSH binary packet protocol (RFC 4253 section 6): a u32 packet length, a
+//  padding length byte, the payload, and random padding bringing the packet to a
+//  multiple of the cipher block size. Each direction numbers its packets, and after
+//  key exchange protects them with a `transport::PacketCipher`.
+
+pub const MIN_BLOCK_SIZE : usize = 8;
+pub const MIN_PADDING : usize = 4;
+/// The largest packet accepted or sent, counting its length field, as in OpenSSH.
+pub const MAX_PACKET_LEN : usize = 256 * 1024;
+/// Identification lines are at most this long, with their CR LF.
+pub const MAX_IDENTIFICATION_LEN : usize = 255;
+//  Lines a server may send before its identification, as OpenSSH allows.
+const MAX_PRE_IDENTIFICATION_LINES : usize = 1024;
+
+/// Padding for a payload so that the length field, padding length byte, payload and
+/// padding fill whole blocks, leaving out a length field sent in the clear.
+pub fn padding_len(payload_len: usize, block_size: usize, length_in_clear: bool) -> usize {
+    let unpadded = if length_in_clear { 1 + payload_len } else { 4 + 1 + payload_len };
+    let mut padding = block_size - unpadded % block_size;
+    if padding < MIN_PADDING {
+        padding += block_size;
+    }
+    padding
+}
+
+//  Reads a line ending in LF one byte at a time, so nothing after it is consumed.
+fn read_line<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut line = vec![];
+    loop {
+        let byte = reader.read_u8()?;
+        if byte == b'\n' {
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Ok(line);
+        }
+        line.push(byte);
+        if line.len() > MAX_IDENTIFICATION_LEN {
+            return Err(Error{kind: InvalidLength});
+        }
+    }
+}
+
+pub struct PacketReader<R> {
+    reader: R,
+    sequence_number: u32,
+    block_size: usize,
+    max_packet_len: usize,
+    cipher: Box<dyn PacketCipher + Send>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R) -> Self {
+        PacketReader{
+            reader: reader,
+            sequence_number: 0,
+            block_size: MIN_BLOCK_SIZE,
+            max_packet_len: MAX_PACKET_LEN,
+            cipher: Box::new(Plain),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// The sequence number of the next packet.
+    pub fn sequence_number(&self) -> u32 {
+        self.sequence_number
+    }
+
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size.max(MIN_BLOCK_SIZE);
+    }
+
+    pub fn set_max_packet_len(&mut self, max_packet_len: usize) {
+        self.max_packet_len = max_packet_len;
+    }
+
+    /// Protects packets read from now on, as after SSH_MSG_NEWKEYS.
+    pub fn set_cipher(&mut self, cipher: Box<dyn PacketCipher + Send>) {
+        self.cipher = cipher;
+    }
+
+    /// Reads the peer's identification string (V_C or V_S), without its line
+    /// ending, skipping any lines a server sends before it. Only SSH 2.0 is accepted.
+    pub fn read_identification(&mut self) -> Result<String, Error> {
+        for _ in 0..MAX_PRE_IDENTIFICATION_LINES {
+            let line = read_line(&mut self.reader)?;
+            if !line.starts_with(b"SSH-") {
+                continue;
+            }
+            let line = String::from_utf8(line)?;
+            if !line.starts_with("SSH-2.0-") && !line.starts_with("SSH-1.99-") {
+                return Err(Error{kind: UnsupportedAlgorithm(line)});
+            }
+            return Ok(line);
+        }
+        Err(Error{kind: InvalidFormat})
+    }
+
+    /// Reads one packet, returning its payload.
+    pub fn read_packet(&mut self) -> Result<Vec<u8>, Error> {
+        let header_len = self.cipher.header_len();
+        let mut packet = vec![0; header_len];
+        self.reader.read_exact(&mut packet)?;
+        let packet_len = self.cipher.open_length(self.sequence_number, &mut packet)?;
+        if packet_len < 1 + MIN_PADDING || 4 + packet_len > self.max_packet_len || 4 + packet_len < header_len {
+            return Err(Error{kind: InvalidLength});
+        }
+        let aligned_len = if self.cipher.length_in_clear() { packet_len } else { 4 + packet_len };
+        if aligned_len % self.block_size.max(self.cipher.block_size()) != 0 {
+            return Err(Error{kind: InvalidLength});
+        }
+        packet.resize(4 + packet_len, 0);
+        self.reader.read_exact(&mut packet[header_len..])?;
+        let mut tag = vec![0; self.cipher.tag_len()];
+        self.reader.read_exact(&mut tag)?;
+        self.cipher.open(self.sequence_number, &mut packet, &tag)?;
+
+        let padding = packet[4] as usize;
+        if padding < MIN_PADDING || padding + 1 > packet_len {
+            return Err(Error{kind: InvalidFormat});
+        }
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        Ok(packet[5..4 + packet_len - padding].to_vec())
+    }
+}
+
+pub struct PacketWriter<W> {
+    writer: W,
+    sequence_number: u32,
+    block_size: usize,
+    rng: OsRng,
+    cipher: Box<dyn PacketCipher + Send>,
+}
+
+impl<W: Write> PacketWriter<W> {
+    pub fn new(writer: W) -> Result<Self, Error> {
+        Ok(PacketWriter{
+            writer: writer,
+            sequence_number: 0,
+            block_size: MIN_BLOCK_SIZE,
+            rng: OsRng::new()?,
+            cipher: Box::new(Plain),
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// The sequence number of the next packet.
+    pub fn sequence_number(&self) -> u32 {
+        self.sequence_number
+    }
+
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size.max(MIN_BLOCK_SIZE);
+    }
+
+    /// Protects packets written from now on, as after SSH_MSG_NEWKEYS.
+    pub fn set_cipher(&mut self, cipher: Box<dyn PacketCipher + Send>) {
+        self.cipher = cipher;
+    }
+
+    /// Sends our identification string, such as `SSH-2.0-krypton`, with CR LF.
+    pub fn write_identification(&mut self, identification: &str) -> Result<(), Error> {
+        if !identification.starts_with("SSH-2.0-") || identification.len() + 2 > MAX_IDENTIFICATION_LEN
+            || identification.contains(|c| c == '\r' || c == '\n') {
+            return Err(Error{kind: InvalidFormat});
+        }
+        self.writer.write_all(identification.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Frames and sends one payload with random padding.
+    pub fn write_packet(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let padding = padding_len(payload.len(), self.block_size.max(self.cipher.block_size()), self.cipher.length_in_clear());
+        let packet_len = 1 + payload.len() + padding;
+        if 4 + packet_len > MAX_PACKET_LEN {
+            return Err(Error{kind: InvalidLength});
+        }
+        let mut packet = Vec::with_capacity(4 + packet_len);
+        packet.write_u32::<BigEndian>(packet_len as u32)?;
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        let mut random = vec![0; padding];
+        self.rng.fill_bytes(&mut random);
+        packet.extend_from_slice(&random);
+        self.cipher.seal(self.sequence_number, &mut packet)?;
+        self.writer.write_all(&packet)?;
+        self.writer.flush()?;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex;
+    use transport::{new_packet_cipher, CIPHERS, MACS};
+
+    //  OpenSSH 9.2's identification and KEXINIT, as sent to a server.
+    const CLIENT_HANDSHAKE : &'static str = "5353482d322e302d4f70656e5353485f392e3270312044656269616e2d322b646562313275360d0a000000ec\
+        0714752f687c1f8779fe5afe67a6d45a572e00000039637572766532353531392d7368613235362c6578742d\
+        696e666f2d632c6b65782d7374726963742d632d763030406f70656e7373682e636f6d0000000b7373682d65\
+        6432353531390000000a6165733132382d6374720000000a6165733132382d6374720000000d686d61632d73\
+        6861322d3235360000000d686d61632d736861322d3235360000001a6e6f6e652c7a6c6962406f70656e7373\
+        682e636f6d2c7a6c69620000001a6e6f6e652c7a6c6962406f70656e7373682e636f6d2c7a6c696200000000\
+        00000000000000000000000000000000";
+
+    #[test]
+    fn captured_handshake_decodes() {
+        let handshake = hex::decode(CLIENT_HANDSHAKE).unwrap();
+        let mut reader = PacketReader::new(&handshake[..]);
+        assert!(reader.read_identification().unwrap() == "SSH-2.0-OpenSSH_9.2p1 Debian-2+deb12u6");
+        let payload = reader.read_packet().unwrap();
+        assert!(payload.len() == 0xec - 1 - 7);
+        assert!(payload[0] == 20);
+        assert!(&payload[17..21] == &[0, 0, 0, 0x39]);
+        assert!(reader.sequence_number() == 1);
+        assert!(reader.into_inner().len() == 0);
+    }
+
+    #[test]
+    fn packets_round_trip_aligned() {
+        let mut writer = PacketWriter::new(vec![]).unwrap();
+        writer.set_block_size(16);
+        writer.write_identification("SSH-2.0-krypton").unwrap();
+        for len in 0..40 {
+            writer.write_packet(&vec![len as u8; len]).unwrap();
+        }
+        assert!(writer.sequence_number() == 40);
+        let written = writer.into_inner();
+
+        let mut reader = PacketReader::new(&written[..]);
+        reader.set_block_size(16);
+        assert!(reader.read_identification().unwrap() == "SSH-2.0-krypton");
+        for len in 0..40 {
+            assert!(reader.read_packet().unwrap() == vec![len as u8; len]);
+        }
+        assert!(padding_len(11, 8, false) == 8 && padding_len(12, 8, false) == 7 && padding_len(3, 8, false) == 8);
+        assert!(padding_len(11, 8, true) == 4 && padding_len(12, 16, true) == 19);
+    }
+
+    #[test]
+    fn protected_packets_round_trip() {
+        for cipher in CIPHERS {
+            for mac in MACS {
+                let key = vec![1; cipher.key_len];
+                let iv = vec![2; cipher.iv_len];
+                let mac_key = vec![3; mac.key_len];
+                let mut writer = PacketWriter::new(vec![]).unwrap();
+                writer.write_packet(b"before").unwrap();
+                writer.set_cipher(new_packet_cipher(cipher.name, &key, &iv, mac.name, &mac_key).unwrap());
+                for len in 0..40 {
+                    writer.write_packet(&vec![len as u8; len]).unwrap();
+                }
+                let written = writer.into_inner();
+
+                let mut reader = PacketReader::new(&written[..]);
+                assert!(reader.read_packet().unwrap() == b"before");
+                reader.set_cipher(new_packet_cipher(cipher.name, &key, &iv, mac.name, &mac_key).unwrap());
+                for len in 0..40 {
+                    assert!(reader.read_packet().unwrap() == vec![len as u8; len]);
+                }
+                assert!(reader.sequence_number() == 41 && reader.into_inner().len() == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_packets_fail() {
+        //  Unaligned, too short on padding, and too long.
+        for packet in &[&[0, 0, 0, 5, 4, 0, 0, 0, 0][..], &[0, 0, 0, 12, 3, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]] {
+            assert!(PacketReader::new(*packet).read_packet().is_err());
+        }
+        match PacketReader::new(&[0, 0x10, 0, 4][..]).read_packet() {
+            Err(Error{kind: InvalidLength}) => {},
+            _ => assert!("expected" == "InvalidLength"),
+        }
+        let mut writer = PacketWriter::new(vec![]).unwrap();
+        assert!(writer.write_packet(&vec![0; MAX_PACKET_LEN]).is_err());
+        assert!(writer.write_identification("SSH-1.5-old").is_err());
+        assert!(PacketReader::new(&b"banner\r\nSSH-1.5-old\r\n"[..]).read_identification().is_err());
+    }
+}
@@ -0,0 +1,62 @@
+//  OpenSSH's pattern matching (match.c), used for host names in known_hosts and
+//  principals in allowed_signers: `*` matches any run of characters, `?` any one
+//  character, and a leading `!` in a pattern list negates.
+
+/// Whether `s` matches a single wildcard `pattern`.
+pub fn matches(s: &str, pattern: &str) -> bool {
+    let s : Vec<char> = s.chars().collect();
+    let pattern : Vec<char> = pattern.chars().collect();
+    matches_chars(&s, &pattern)
+}
+
+fn matches_chars(s: &[char], pattern: &[char]) -> bool {
+    match pattern.first() {
+        None => s.len() == 0,
+        Some(&'*') => (0..s.len() + 1).any(|i| matches_chars(&s[i..], &pattern[1..])),
+        Some(&'?') => s.len() > 0 && matches_chars(&s[1..], &pattern[1..]),
+        Some(c) => s.first() == Some(c) && matches_chars(&s[1..], &pattern[1..]),
+    }
+}
+
+/// Matches `s` against patterns: `Some(true)` on a positive match, `Some(false)`
+/// if any negated pattern matches (which overrides positive matches), and `None`
+/// if nothing matches. Comparison is case-insensitive, as for host names.
+pub fn matches_list<'a, I: IntoIterator<Item = &'a str>>(s: &str, patterns: I) -> Option<bool> {
+    let s = s.to_lowercase();
+    matches_list_exact(&s, patterns.into_iter().map(|p| p.to_lowercase()))
+}
+
+/// As `matches_list`, but case-sensitive, as for principals and namespaces.
+pub fn matches_list_exact<I: IntoIterator>(s: &str, patterns: I) -> Option<bool> where I::Item: AsRef<str> {
+    let mut result = None;
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        if pattern.starts_with('!') {
+            if matches(s, &pattern[1..]) {
+                return Some(false);
+            }
+        } else if matches(s, pattern) {
+            result = Some(true);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patterns_match() {
+        assert!(matches("example.com", "example.com"));
+        assert!(matches("www.example.com", "*.example.com"));
+        assert!(!matches("example.com", "*.example.com"));
+        assert!(matches("host1", "host?"));
+        assert!(!matches("host10", "host?"));
+        assert!(matches("", "*"));
+        assert!(matches_list("WWW.Example.com", "*.example.com,!bad.example.com".split(',')) == Some(true));
+        assert!(matches_list("bad.example.com", "*.example.com,!bad.example.com".split(',')) == Some(false));
+        assert!(matches_list("other.org", "*.example.com".split(',')) == None);
+        assert!(matches_list_exact("Alice@example.com", "alice@example.com".split(',')) == None);
+    }
+}
@@ -0,0 +1,123 @@
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+//  ASCII armor shared by the OpenSSH, PEM and SSH signature file formats:
+//  a BEGIN line, base64 body wrapped at a fixed width, and an END line.
+
+pub fn begin_line(label: &str) -> String {
+    format!("-----BEGIN {}-----", label)
+}
+
+pub fn end_line(label: &str) -> String {
+    format!("-----END {}-----", label)
+}
+
+/// Returns the label of the first BEGIN line in `text`.
+pub fn label(text: &str) -> Option<&str> {
+    for line in text.lines().map(|l| l.trim()) {
+        if line.starts_with("-----BEGIN ") && line.ends_with("-----") && line.len() >= 16 {
+            return Some(&line[11..line.len() - 5]);
+        }
+    }
+    None
+}
+
+pub fn encode(label: &str, bytes: &[u8], line_width: usize) -> String {
+    encode_with_headers(label, &[], bytes, line_width)
+}
+
+//  RFC 1421 style headers, e.g. `Proc-Type` and `DEK-Info` on legacy encrypted keys,
+//  separated from the body by a blank line.
+pub fn encode_with_headers(label: &str, headers: &[(&str, &str)], bytes: &[u8], line_width: usize) -> String {
+    use base64;
+    let body = base64::encode(bytes);
+    let mut out = begin_line(label);
+    out.push('\n');
+    for &(name, value) in headers {
+        out.push_str(&format!("{}: {}\n", name, value));
+    }
+    if headers.len() > 0 {
+        out.push('\n');
+    }
+    for line in body.as_bytes().chunks(line_width) {
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push('\n');
+    }
+    out.push_str(&end_line(label));
+    out.push('\n');
+    out
+}
+
+/// Returns the lines strictly between the BEGIN and END markers for `label`.
+pub fn armored_lines<'a>(label: &str, text: &'a str) -> Result<Vec<&'a str>, Error> {
+    let begin = begin_line(label);
+    let end = end_line(label);
+    let mut lines = text.lines().map(|l| l.trim());
+    if !lines.any(|l| l == begin) {
+        return Err(Error{kind: InvalidFormat});
+    }
+    let mut body = vec![];
+    for line in lines {
+        if line == end {
+            return Ok(body);
+        }
+        body.push(line);
+    }
+    Err(Error{kind: InvalidFormat})
+}
+
+pub fn decode(label: &str, text: &str) -> Result<Vec<u8>, Error> {
+    decode_base64_lines(&armored_lines(label, text)?)
+}
+
+pub fn decode_with_headers(label: &str, text: &str) -> Result<(Vec<(String, String)>, Vec<u8>), Error> {
+    let lines = armored_lines(label, text)?;
+    let mut headers = vec![];
+    let mut body_start = 0;
+    if lines.iter().any(|l| l.contains(':')) {
+        for (i, line) in lines.iter().enumerate() {
+            if line.len() == 0 {
+                body_start = i + 1;
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+                _ => return Err(Error{kind: InvalidFormat}),
+            }
+        }
+    }
+    Ok((headers, decode_base64_lines(&lines[body_start..])?))
+}
+
+pub fn decode_base64_lines(lines: &[&str]) -> Result<Vec<u8>, Error> {
+    use base64;
+    let body : String = lines.concat();
+    base64::decode(&body).map_err(|_| Error{kind: InvalidFormat})
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pem_round_trips() {
+        let bytes : Vec<u8> = (0..100).collect();
+        let armored = encode("TEST BLOB", &bytes, 64);
+        assert!(armored.starts_with("-----BEGIN TEST BLOB-----\n"));
+        assert!(armored.ends_with("\n-----END TEST BLOB-----\n"));
+        assert!(armored.lines().all(|l| l.len() <= 64 || l.starts_with("-----")));
+        assert!(decode("TEST BLOB", &armored).unwrap() == bytes);
+        assert!(decode("OTHER BLOB", &armored).is_err());
+        assert!(label(&armored) == Some("TEST BLOB"));
+    }
+
+    #[test]
+    fn pem_headers_round_trip() {
+        let armored = encode_with_headers("TEST BLOB", &[("Proc-Type", "4,ENCRYPTED"), ("DEK-Info", "AES-128-CBC,00")], b"body", 64);
+        assert!(armored == "-----BEGIN TEST BLOB-----\nProc-Type: 4,ENCRYPTED\nDEK-Info: AES-128-CBC,00\n\nYm9keQ==\n-----END TEST BLOB-----\n");
+        let (headers, body) = decode_with_headers("TEST BLOB", &armored).unwrap();
+        assert!(headers == vec![("Proc-Type".to_string(), "4,ENCRYPTED".to_string()), ("DEK-Info".to_string(), "AES-128-CBC,00".to_string())]);
+        assert!(body == b"body");
+    }
+}
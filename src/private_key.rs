@@ -0,0 +1,218 @@
+use ed25519::*;
+use ecdsa::*;
+use rsa::*;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use serde_de::Deserializer;
+use serde_ser;
+use serde_ser::Serializer;
+use mpint::MPUint;
+use public_key::PublicKey;
+use rand::{OsRng, Rng};
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Ed25519,
+    EcdsaSha2Nistp256,
+    EcdsaSha2Nistp384,
+    Rsa(usize),
+}
+
+#[derive(Clone)]
+pub enum PrivateKey {
+    Ed25519(Ed25519PrivateKey),
+    ECDSA(ECDSAPrivateKey),
+    RSA(RSAPrivateKey),
+}
+
+impl PrivateKey {
+    /// Generates a key with the operating system RNG, returning it with its wire-format public key.
+    pub fn generate(key_type: KeyType) -> Result<(PrivateKey, Vec<u8>), Error> {
+        let mut rng = OsRng::new()?;
+        PrivateKey::generate_with_rng(key_type, &mut rng)
+    }
+
+    pub fn generate_with_rng<R: Rng>(key_type: KeyType, rng: &mut R) -> Result<(PrivateKey, Vec<u8>), Error> {
+        let private_key = match key_type {
+            KeyType::Ed25519 => PrivateKey::Ed25519(Ed25519PrivateKey::generate(rng)?),
+            KeyType::EcdsaSha2Nistp256 => PrivateKey::ECDSA(ECDSAPrivateKey::generate(NISTP256, rng)?),
+            KeyType::EcdsaSha2Nistp384 => PrivateKey::ECDSA(ECDSAPrivateKey::generate(NISTP384, rng)?),
+            KeyType::Rsa(bits) => PrivateKey::RSA(RSAPrivateKey::generate(bits, rng)?),
+        };
+        let public_key = private_key.public_key_bytes()?;
+        Ok((private_key, public_key))
+    }
+
+    pub fn key_type(&self) -> String {
+        match *self {
+            PrivateKey::Ed25519(_) => ED25519_TYPE.into(),
+            PrivateKey::ECDSA(ref sk) => key_type_for_curve(&sk.curve),
+            PrivateKey::RSA(_) => RSA_TYPE.into(),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match *self {
+            PrivateKey::Ed25519(ref sk) => PublicKey::Ed25519(sk.public_key()),
+            PrivateKey::ECDSA(ref sk) => PublicKey::ECDSA(sk.public_key()),
+            PrivateKey::RSA(ref sk) => PublicKey::RSA(sk.public_key()),
+        }
+    }
+
+    pub fn public_key_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.public_key().to_bytes()
+    }
+
+    /// Reads the key type and algorithm-specific private fields, as laid out in
+    /// openssh-key-v1 private sections and ssh-agent ADD_IDENTITY requests.
+    pub fn deserialize_openssh<R: Read>(de: &mut Deserializer<R>) -> Result<PrivateKey, Error> {
+        let key_type : String = de.next()?;
+        match key_type.as_ref() {
+            ED25519_TYPE => {
+                let public_key : Vec<u8> = de.next()?;
+                let private_key : Vec<u8> = de.next()?;
+                if public_key.len() != 32 || private_key.len() != 64 || private_key[32..] != public_key[..] {
+                    return Err(Error{kind: InvalidKey});
+                }
+                Ok(PrivateKey::Ed25519(Ed25519PrivateKey{
+                    seed: private_key[..32].to_vec(),
+                    public_key: public_key,
+                }))
+            },
+            RSA_TYPE => {
+                let private_key = RSAPrivateKey{
+                    modulus: de.next()?,
+                    public_exponent: de.next()?,
+                    private_exponent: de.next()?,
+                    iqmp: de.next()?,
+                    p: de.next()?,
+                    q: de.next()?,
+                };
+                private_key.validate()?;
+                Ok(PrivateKey::RSA(private_key))
+            },
+            _ if key_type.starts_with("ecdsa-sha2-") => {
+                let curve : String = de.next()?;
+                let public_key : Vec<u8> = de.next()?;
+                let private_key : MPUint = de.next()?;
+                if key_type != key_type_for_curve(&curve) {
+                    return Err(Error{kind: InvalidKey});
+                }
+                Ok(PrivateKey::ECDSA(ECDSAPrivateKey{
+                    curve: curve,
+                    public_key: public_key,
+                    private_key: private_key,
+                }))
+            },
+            _ => Err(Error{kind: UnsupportedAlgorithm(key_type)}),
+        }
+    }
+
+    pub fn serialize_openssh<W: Write>(&self, ser: &mut Serializer<W>) -> Result<(), Error> {
+        ser.write(&self.key_type())?;
+        match *self {
+            PrivateKey::Ed25519(ref sk) => {
+                let mut private_key = sk.seed.clone();
+                private_key.extend(&sk.public_key);
+                ser.write(&sk.public_key)?;
+                ser.write(&private_key)
+            },
+            PrivateKey::ECDSA(ref sk) => {
+                ser.write(&sk.curve)?;
+                ser.write(&sk.public_key)?;
+                ser.write(&sk.private_key)
+            },
+            PrivateKey::RSA(ref sk) => {
+                ser.write(&sk.modulus)?;
+                ser.write(&sk.public_exponent)?;
+                ser.write(&sk.private_exponent)?;
+                ser.write(&sk.iqmp)?;
+                ser.write(&sk.p)?;
+                ser.write(&sk.q)
+            },
+        }
+    }
+
+    /// Signs with the key's default signature algorithm; RSA keys use `rsa-sha2-512`.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let algorithm = match *self {
+            PrivateKey::RSA(_) => RSA_SHA2_512.into(),
+            _ => self.key_type(),
+        };
+        self.sign_with_algorithm(message, &algorithm)
+    }
+
+    /// Signs producing a wire-format signature blob for the given SSH signature algorithm.
+    pub fn sign_with_algorithm(&self, message: &[u8], algorithm: &str) -> Result<Vec<u8>, Error> {
+        match *self {
+            PrivateKey::RSA(ref sk) => serde_ser::to_vec(&sk.sign(message, algorithm)?),
+            _ if algorithm != self.key_type() => Err(Error{kind: UnsupportedAlgorithm(algorithm.into())}),
+            PrivateKey::Ed25519(ref sk) => serde_ser::to_vec(&sk.sign(message)?),
+            PrivateKey::ECDSA(ref sk) => serde_ser::to_vec(&sk.sign(message)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::verify_signature;
+    use rand::{SeedableRng, XorShiftRng};
+
+    #[test]
+    fn generated_keys_sign_and_verify() {
+        for key_type in &[KeyType::Ed25519, KeyType::EcdsaSha2Nistp256, KeyType::EcdsaSha2Nistp384, KeyType::Rsa(2048)] {
+            let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+            let (private_key, public_key) = PrivateKey::generate_with_rng(*key_type, &mut rng).unwrap();
+            let signature = private_key.sign(b"session id").unwrap();
+            assert!(verify_signature(&public_key, &signature, b"session id"));
+            assert!(!verify_signature(&public_key, &signature, b"other session id"));
+        }
+    }
+
+    #[test]
+    fn generation_is_deterministic_for_rng() {
+        let (_, first) = PrivateKey::generate_with_rng(KeyType::Ed25519, &mut XorShiftRng::from_seed([1, 2, 3, 4])).unwrap();
+        let (_, second) = PrivateKey::generate_with_rng(KeyType::Ed25519, &mut XorShiftRng::from_seed([1, 2, 3, 4])).unwrap();
+        let (_, third) = PrivateKey::generate_with_rng(KeyType::Ed25519, &mut XorShiftRng::from_seed([4, 3, 2, 1])).unwrap();
+        assert!(first == second);
+        assert!(first != third);
+    }
+
+    #[test]
+    fn mismatched_algorithm_fails() {
+        let (private_key, _) = PrivateKey::generate_with_rng(KeyType::Ed25519, &mut XorShiftRng::from_seed([1, 2, 3, 4])).unwrap();
+        assert!(private_key.sign_with_algorithm(b"msg", "rsa-sha2-256").is_err());
+    }
+
+    #[test]
+    fn inconsistent_rsa_fields_fail() {
+        let (private_key, _) = PrivateKey::generate_with_rng(KeyType::Rsa(2048), &mut XorShiftRng::from_seed([5, 6, 7, 8])).unwrap();
+        let key = match private_key {
+            PrivateKey::RSA(key) => key,
+            _ => unreachable!(),
+        };
+        let zero = MPUint{be_bytes: vec![]};
+        let one = MPUint{be_bytes: vec![1]};
+        for corrupt in &[
+            RSAPrivateKey{p: zero.clone(), ..key.clone()},
+            RSAPrivateKey{q: one.clone(), ..key.clone()},
+            RSAPrivateKey{p: key.q.clone(), q: key.p.clone(), ..key.clone()},
+            RSAPrivateKey{iqmp: one.clone(), ..key.clone()},
+            RSAPrivateKey{private_exponent: one.clone(), ..key.clone()},
+        ] {
+            let mut ser = Serializer::new(Vec::new());
+            PrivateKey::RSA(corrupt.clone()).serialize_openssh(&mut ser).unwrap();
+            match PrivateKey::deserialize_openssh(&mut Deserializer::new(&ser.into_inner()[..])) {
+                Err(Error{kind: InvalidKey}) => {},
+                _ => assert!("expected" == "InvalidKey"),
+            }
+        }
+        assert!(RSAPrivateKey{p: zero, ..key.clone()}.sign(b"msg", "rsa-sha2-256").is_err());
+
+        let mut ser = Serializer::new(Vec::new());
+        PrivateKey::RSA(key).serialize_openssh(&mut ser).unwrap();
+        assert!(PrivateKey::deserialize_openssh(&mut Deserializer::new(&ser.into_inner()[..])).is_ok());
+    }
+}
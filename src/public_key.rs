@@ -0,0 +1,243 @@
+use ed25519::*;
+use ecdsa::*;
+use rsa::*;
+use ssh::PublicKeyHeader;
+use serde_de;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use serde_ser;
+use base64;
+use fingerprint::{Fingerprint, HashAlg};
+use fingerprint;
+use mpint::MPUint;
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(Ed25519PublicKey),
+    ECDSA(ECDSAPublicKey),
+    RSA(RSAPublicKey),
+}
+
+impl PublicKey {
+    /// Parses a wire-format public key blob, rejecting trailing or non-canonical data.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let header : PublicKeyHeader = serde_de::from_slice(bytes)?;
+        let key = match header._type.as_ref() {
+            ED25519_TYPE => {
+                let key : Ed25519PublicKey = serde_de::from_slice(bytes)?;
+                if key.public_key.len() != 32 {
+                    return Err(Error{kind: InvalidKey});
+                }
+                PublicKey::Ed25519(key)
+            },
+            RSA_TYPE => PublicKey::RSA(serde_de::from_slice(bytes)?),
+            _ if header._type.starts_with("ecdsa-sha2-") => {
+                let key : ECDSAPublicKey = serde_de::from_slice(bytes)?;
+                curve_oid(&key.curve)?;
+                if key._type != key_type_for_curve(&key.curve) {
+                    return Err(Error{kind: InvalidKey});
+                }
+                PublicKey::ECDSA(key)
+            },
+            _ => return Err(Error{kind: UnsupportedAlgorithm(header._type)}),
+        };
+        if key.to_bytes()? != bytes {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(key)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        match *self {
+            PublicKey::Ed25519(ref pk) => serde_ser::to_vec(pk),
+            PublicKey::ECDSA(ref pk) => serde_ser::to_vec(pk),
+            PublicKey::RSA(ref pk) => serde_ser::to_vec(pk),
+        }
+    }
+
+    pub fn key_type(&self) -> &str {
+        match *self {
+            PublicKey::Ed25519(ref pk) => &pk._type,
+            PublicKey::ECDSA(ref pk) => &pk._type,
+            PublicKey::RSA(ref pk) => &pk._type,
+        }
+    }
+
+    /// Key size in bits, as reported by `ssh-keygen -l`.
+    pub fn bits(&self) -> usize {
+        match *self {
+            PublicKey::Ed25519(_) => 256,
+            PublicKey::ECDSA(ref pk) => match pk.curve.as_ref() {
+                NISTP384 => 384,
+                _ => 256,
+            },
+            PublicKey::RSA(ref pk) => {
+                let modulus = MPUint::from_be_bytes(&pk.modulus.be_bytes).be_bytes;
+                match modulus.first() {
+                    Some(first) => modulus.len() * 8 - first.leading_zeros() as usize,
+                    None => 0,
+                }
+            },
+        }
+    }
+
+    pub fn fingerprint(&self, hash_alg: HashAlg) -> Result<Fingerprint, Error> {
+        Ok(Fingerprint::new(hash_alg, &self.to_bytes()?))
+    }
+
+    /// The randomart shown by `ssh-keygen -lv`.
+    pub fn randomart(&self, hash_alg: HashAlg) -> Result<String, Error> {
+        let name = match *self {
+            PublicKey::Ed25519(_) => "ED25519",
+            PublicKey::ECDSA(_) => "ECDSA",
+            PublicKey::RSA(_) => "RSA",
+        };
+        Ok(fingerprint::randomart(&self.fingerprint(hash_alg)?, &format!("{} {}", name, self.bits())))
+    }
+
+    /// Verifies a wire-format signature blob over `message`.
+    pub fn verify(&self, signature: &[u8], message: &[u8]) -> bool {
+        match self.to_bytes() {
+            Ok(bytes) => ::verify_signature(&bytes, signature, message),
+            _ => false,
+        }
+    }
+
+    /// Parses `<algorithm> <base64 blob> [comment]` as found in `.pub` files,
+    /// returning the key and its (possibly empty) comment.
+    pub fn from_openssh_line(line: &str) -> Result<(Self, String), Error> {
+        let line = line.trim();
+        let mut parts = line.splitn(2, |c: char| c == ' ' || c == '\t');
+        let algorithm = parts.next().unwrap_or("");
+        let rest = parts.next().unwrap_or("").trim_start();
+        let mut parts = rest.splitn(2, |c: char| c == ' ' || c == '\t');
+        let body = parts.next().unwrap_or("");
+        let comment = parts.next().unwrap_or("").trim();
+        if algorithm.len() == 0 || body.len() == 0 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let blob = base64::decode(body).map_err(|_| Error{kind: InvalidFormat})?;
+        let key = PublicKey::from_bytes(&blob)?;
+        if key.key_type() != algorithm {
+            return Err(Error{kind: InvalidKey});
+        }
+        Ok((key, comment.into()))
+    }
+
+    pub fn to_openssh_line(&self, comment: &str) -> Result<String, Error> {
+        let mut line = format!("{} {}", self.key_type(), base64::encode(&self.to_bytes()?));
+        if comment.len() > 0 {
+            line.push(' ');
+            line.push_str(comment);
+        }
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ED25519_LINE : &'static str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK6HslpsJSnoarkkdo5GRYMRmbmjEs/Iwwl8G3J54QBO test@krypt.co";
+    const ECDSA_LINE : &'static str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBNZKo1XjDtLaWgnNwz4Aw6/blSKHIYYI9h3zkg7GerHJ4CEmqeXMQm1YaNqO3OZFk7pF4NGHGowIQ3puDDIEhzijUfYjgPqZR4+PZvMlk+GgG/SJpOloRRVOD3O4iR5X7A== ecdsa@krypt.co";
+    const RSA_LINE : &'static str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDt0Ur4ZRYhdH9s+YSlhKvmOp1JnRqDUkMmRcJSWt8nKncLDI+UckdJg1FOQenIXTHzj/J//wwWDMWmdDbMyi6HdE2fTxGocc/WSAlrdpfxJoGi4uMsnpr0ohp/ieFOPpZfF6aXuXMCVWRaXq8S0d1M7i0xIq1gh3DSU9ixa6kVZt7haEzPRNFaF74UcI+86Qq63K6mjLGs8F2pY8XTFj1JyvcN8guQ2opKPuaVlWvDAyGoIMu2G2sYljNsSQkBoqJmL4HXTwPRI1LIoLPEPJfClFV4dxwuVN0rjYxrCML3+Qh4O5Ny0s5R2swvZ17xZaquMh/dr/wTbowZ5/XrifYt";
+
+    #[test]
+    fn openssh_lines_round_trip() {
+        for line in &[ED25519_LINE, ECDSA_LINE, RSA_LINE] {
+            let (key, comment) = PublicKey::from_openssh_line(line).unwrap();
+            assert!(key.to_openssh_line(&comment).unwrap() == *line);
+        }
+        let (key, comment) = PublicKey::from_openssh_line(ED25519_LINE).unwrap();
+        assert!(key.key_type() == "ssh-ed25519");
+        assert!(comment == "test@krypt.co");
+        let (_, comment) = PublicKey::from_openssh_line(RSA_LINE).unwrap();
+        assert!(comment == "");
+    }
+
+    #[test]
+    fn comment_keeps_inner_whitespace() {
+        let line = ED25519_LINE.replace("test@krypt.co", " my laptop key \n");
+        let (_, comment) = PublicKey::from_openssh_line(&line).unwrap();
+        assert!(comment == "my laptop key");
+    }
+
+    #[test]
+    fn mismatched_prefix_is_rejected() {
+        let line = ED25519_LINE.replace("ssh-ed25519 ", "ssh-rsa ");
+        match PublicKey::from_openssh_line(&line) {
+            Err(Error{kind: InvalidKey}) => {},
+            _ => assert!("expected" == "InvalidKey"),
+        }
+        assert!(PublicKey::from_openssh_line("ssh-ed25519").is_err());
+        assert!(PublicKey::from_openssh_line("ssh-ed25519 not-base64!").is_err());
+    }
+
+    #[test]
+    fn fingerprints_match_ssh_keygen() {
+        let (key, _) = PublicKey::from_openssh_line(ED25519_LINE).unwrap();
+        assert!(key.fingerprint(HashAlg::Sha256).unwrap().to_string() == "SHA256:J5FnSrIEM2V0e22jItR4gnnNFGpuqcsaNaAtuptsar0");
+        assert!(key.randomart(HashAlg::Sha256).unwrap() == "+--[ED25519 256]--+\n\
+|    +o+ +.       |\n\
+|     B O o .     |\n\
+|  . o X X + +    |\n\
+| o . * B * o .   |\n\
+|o . o * S o      |\n\
+|.. . + . +       |\n\
+|. o .            |\n\
+|.= + .           |\n\
+|Oo.E+            |\n\
++----[SHA256]-----+");
+        let (key, _) = PublicKey::from_openssh_line(ED25519_LINE).unwrap();
+        assert!(key.fingerprint(HashAlg::Md5).unwrap().to_string() == "MD5:5b:c0:bd:03:c3:2f:7c:1f:b8:85:27:f1:af:d9:88:5a");
+        assert!(key.randomart(HashAlg::Md5).unwrap() == "+--[ED25519 256]--+\n\
+|                 |\n\
+|       o .       |\n\
+|        * o      |\n\
+|       . = *     |\n\
+|        S X =    |\n\
+|         = B o   |\n\
+|        . E . .  |\n\
+|         . . =   |\n\
+|        ... + .  |\n\
++------[MD5]------+");
+        let (key, _) = PublicKey::from_openssh_line(ECDSA_LINE).unwrap();
+        assert!(key.fingerprint(HashAlg::Sha256).unwrap().to_string() == "SHA256:srhaa9+VCkorfmEqry/YHbSNIvMhn5kIznLtJcxzzRs");
+        assert!(key.randomart(HashAlg::Sha256).unwrap() == "+---[ECDSA 384]---+\n\
+|                 |\n\
+|                 |\n\
+|                 |\n\
+|    .            |\n\
+|   . +. S        |\n\
+|= oo*..=   .     |\n\
+|=O @Oo= E o      |\n\
+|*o@o=X o =       |\n\
+|oO=**.. +        |\n\
++----[SHA256]-----+");
+        let (key, _) = PublicKey::from_openssh_line(RSA_LINE).unwrap();
+        assert!(key.fingerprint(HashAlg::Sha256).unwrap().to_string() == "SHA256:sRN0lDOEnFzHQWs3SHBB8pDOhN0djDDSi3+GXPBMp0I");
+        assert!(key.randomart(HashAlg::Sha256).unwrap() == "+---[RSA 2048]----+\n\
+|       ooX@XO*.. |\n\
+|       .*oEO=o+  |\n\
+|        o* O=oo  |\n\
+|        .+=.=. . |\n\
+|        So +     |\n\
+|         .+ o    |\n\
+|           o     |\n\
+|                 |\n\
+|                 |\n\
++----[SHA256]-----+");
+        assert!(key.bits() == 2048);
+    }
+
+    #[test]
+    fn generated_key_line_verifies_signature() {
+        use private_key::{KeyType, PrivateKey};
+        let (private_key, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let line = private_key.public_key().to_openssh_line("generated").unwrap();
+        let (public_key, _) = PublicKey::from_openssh_line(&line).unwrap();
+        let signature = private_key.sign(b"message").unwrap();
+        assert!(public_key.verify(&signature, b"message"));
+        assert!(!public_key.verify(&signature, b"other message"));
+    }
+}
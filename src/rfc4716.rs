@@ -0,0 +1,238 @@
+use public_key::PublicKey;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use base64;
+
+//  The SSH2 public key file format of RFC 4716, as exported by `ssh-keygen -e`
+//  and commercial SSH servers.
+
+pub const BEGIN_LINE : &'static str = "---- BEGIN SSH2 PUBLIC KEY ----";
+pub const END_LINE : &'static str = "---- END SSH2 PUBLIC KEY ----";
+const LINE_WIDTH : usize = 72;
+const MAX_TAG_LEN : usize = 64;
+const MAX_VALUE_LEN : usize = 1024;
+
+pub struct SSH2PublicKey {
+    pub public_key: PublicKey,
+    /// Header tags and values in file order, with line continuations joined.
+    pub headers: Vec<(String, String)>,
+}
+
+impl SSH2PublicKey {
+    pub fn new(public_key: PublicKey, comment: &str) -> Self {
+        let mut headers = vec![];
+        if comment.len() > 0 {
+            //  Quotes and backslashes inside the quoted comment are escaped, so a
+            //  comment ending in a backslash does not read as a line continuation.
+            let escaped = comment.replace('\\', "\\\\").replace('"', "\\\"");
+            headers.push(("Comment".into(), format!("\"{}\"", escaped)));
+        }
+        SSH2PublicKey{
+            public_key: public_key,
+            headers: headers,
+        }
+    }
+
+    /// The first header with a case-insensitive match on `tag`.
+    pub fn header(&self, tag: &str) -> Option<&str> {
+        self.headers.iter().find(|h| h.0.eq_ignore_ascii_case(tag)).map(|h| h.1.as_str())
+    }
+
+    /// The Comment header, without any surrounding quotes. Within quotes,
+    /// `\"` and `\\` are unescaped; other backslashes are kept as written.
+    pub fn comment(&self) -> Option<String> {
+        self.header("Comment").map(|value| {
+            if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                unescape(&value[1..value.len() - 1])
+            } else {
+                value.into()
+            }
+        })
+    }
+
+    pub fn from_rfc4716(text: &str) -> Result<Self, Error> {
+        let mut lines = text.lines().map(|l| l.trim_end_matches('\r'));
+        if !lines.any(|l| l.trim() == BEGIN_LINE) {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let mut headers = vec![];
+        let mut body = String::new();
+        let mut continued : Option<String> = None;
+        let mut ended = false;
+        for line in lines {
+            if let Some(mut header) = continued.take() {
+                if line.ends_with('\\') {
+                    header.push_str(&line[..line.len() - 1]);
+                    continued = Some(header);
+                } else {
+                    header.push_str(line);
+                    headers.push(parse_header(&header)?);
+                }
+                continue;
+            }
+            if line.trim() == END_LINE {
+                ended = true;
+                break;
+            }
+            //  Headers precede the body, which being base64 never contains a colon.
+            if line.contains(':') && body.len() == 0 {
+                if line.ends_with('\\') {
+                    continued = Some(line[..line.len() - 1].into());
+                } else {
+                    headers.push(parse_header(line)?);
+                }
+            } else {
+                body.push_str(line.trim());
+            }
+        }
+        if !ended || continued.is_some() {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let blob = base64::decode(&body).map_err(|_| Error{kind: InvalidFormat})?;
+        Ok(SSH2PublicKey{
+            public_key: PublicKey::from_bytes(&blob)?,
+            headers: headers,
+        })
+    }
+
+    pub fn to_rfc4716(&self) -> Result<String, Error> {
+        let mut out = String::from(BEGIN_LINE);
+        out.push('\n');
+        for &(ref tag, ref value) in &self.headers {
+            if tag.len() == 0 || tag.len() > MAX_TAG_LEN || value.len() > MAX_VALUE_LEN {
+                return Err(Error{kind: InvalidLength});
+            }
+            //  RFC 4716 has no escape for a value ending in a backslash, which
+            //  would read back as a line continuation.
+            if tag.contains(':') || tag.contains('\n') || value.contains('\n') || value.ends_with('\\') {
+                return Err(Error{kind: InvalidFormat});
+            }
+            write_header_line(&mut out, &format!("{}: {}", tag, value));
+        }
+        let body = base64::encode(&self.public_key.to_bytes()?);
+        for line in body.as_bytes().chunks(LINE_WIDTH) {
+            out.push_str(&String::from_utf8_lossy(line));
+            out.push('\n');
+        }
+        out.push_str(END_LINE);
+        out.push('\n');
+        Ok(out)
+    }
+}
+
+fn parse_header(line: &str) -> Result<(String, String), Error> {
+    let mut parts = line.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(tag), Some(value)) if tag.len() > 0 && tag.len() <= MAX_TAG_LEN => {
+            Ok((tag.into(), value.trim().into()))
+        },
+        _ => Err(Error{kind: InvalidFormat}),
+    }
+}
+
+fn unescape(quoted: &str) -> String {
+    let mut out = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if next == '\\' || next == '"' {
+                    out.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+//  Splits a header over several lines, each ending in a backslash, so that no
+//  line exceeds the 72 byte limit. Splits fall on UTF-8 character boundaries.
+fn write_header_line(out: &mut String, header: &str) {
+    let mut rest = header;
+    while rest.len() > LINE_WIDTH {
+        let mut split = LINE_WIDTH - 1;
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        out.push_str(&rest[..split]);
+        out.push_str("\\\n");
+        rest = &rest[split..];
+    }
+    out.push_str(rest);
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    //  Output of `ssh-keygen -e`, which wraps the body at 70 characters.
+    const RSA_SSH2_KEY : &'static str = "---- BEGIN SSH2 PUBLIC KEY ----
+Comment: \"2048-bit RSA, converted by root@vm from OpenSSH\"
+AAAAB3NzaC1yc2EAAAADAQABAAABAQDt0Ur4ZRYhdH9s+YSlhKvmOp1JnRqDUkMmRcJSWt
+8nKncLDI+UckdJg1FOQenIXTHzj/J//wwWDMWmdDbMyi6HdE2fTxGocc/WSAlrdpfxJoGi
+4uMsnpr0ohp/ieFOPpZfF6aXuXMCVWRaXq8S0d1M7i0xIq1gh3DSU9ixa6kVZt7haEzPRN
+FaF74UcI+86Qq63K6mjLGs8F2pY8XTFj1JyvcN8guQ2opKPuaVlWvDAyGoIMu2G2sYljNs
+SQkBoqJmL4HXTwPRI1LIoLPEPJfClFV4dxwuVN0rjYxrCML3+Qh4O5Ny0s5R2swvZ17xZa
+quMh/dr/wTbowZ5/XrifYt
+---- END SSH2 PUBLIC KEY ----
+";
+    const RSA_LINE : &'static str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDt0Ur4ZRYhdH9s+YSlhKvmOp1JnRqDUkMmRcJSWt8nKncLDI+UckdJg1FOQenIXTHzj/J//wwWDMWmdDbMyi6HdE2fTxGocc/WSAlrdpfxJoGi4uMsnpr0ohp/ieFOPpZfF6aXuXMCVWRaXq8S0d1M7i0xIq1gh3DSU9ixa6kVZt7haEzPRNFaF74UcI+86Qq63K6mjLGs8F2pY8XTFj1JyvcN8guQ2opKPuaVlWvDAyGoIMu2G2sYljNsSQkBoqJmL4HXTwPRI1LIoLPEPJfClFV4dxwuVN0rjYxrCML3+Qh4O5Ny0s5R2swvZ17xZaquMh/dr/wTbowZ5/XrifYt";
+
+    #[test]
+    fn ssh_keygen_export_parses() {
+        let key = SSH2PublicKey::from_rfc4716(RSA_SSH2_KEY).unwrap();
+        let (expected, _) = PublicKey::from_openssh_line(RSA_LINE).unwrap();
+        assert!(key.public_key == expected);
+        assert!(key.comment() == Some("2048-bit RSA, converted by root@vm from OpenSSH".to_string()));
+        assert!(key.header("comment").is_some());
+    }
+
+    #[test]
+    fn ssh2_key_round_trips() {
+        let (public_key, _) = PublicKey::from_openssh_line(RSA_LINE).unwrap();
+        let comment = "a comment long enough to need a continuation line, with a xxü straddling the split";
+        let mut key = SSH2PublicKey::new(public_key, comment);
+        key.headers.push(("x-command".into(), "/home/me/bin/lock-in-guest.sh".into()));
+        let text = key.to_rfc4716().unwrap();
+        assert!(text.lines().all(|l| l.len() <= 72));
+        assert!(text.lines().nth(1).unwrap().ends_with('\\'));
+        let parsed = SSH2PublicKey::from_rfc4716(&text).unwrap();
+        assert!(parsed.public_key == key.public_key);
+        assert!(parsed.comment() == Some(comment.to_string()));
+        assert!(parsed.header("X-Command") == Some("/home/me/bin/lock-in-guest.sh"));
+        assert!(parsed.to_rfc4716().unwrap() == text);
+    }
+
+    #[test]
+    fn quotes_and_backslashes_round_trip() {
+        let (public_key, _) = PublicKey::from_openssh_line(RSA_LINE).unwrap();
+        for comment in &["say \"hi\"", "\"", "C:\\Users\\me\\", "\\\\\"\\", "a comment long enough to need a continuation line, ending in a backslash \\"] {
+            let text = SSH2PublicKey::new(public_key.clone(), comment).to_rfc4716().unwrap();
+            let parsed = SSH2PublicKey::from_rfc4716(&text).unwrap();
+            assert!(parsed.comment() == Some(comment.to_string()));
+            assert!(parsed.to_rfc4716().unwrap() == text);
+        }
+
+        let mut key = SSH2PublicKey::new(public_key, "");
+        key.headers.push(("x-path".into(), "C:\\Users\\me\\".into()));
+        match key.to_rfc4716() {
+            Err(Error{kind: InvalidFormat}) => {},
+            _ => assert!("expected" == "InvalidFormat"),
+        }
+        key.headers[0].1 = "C:\\Users\\me".into();
+        let parsed = SSH2PublicKey::from_rfc4716(&key.to_rfc4716().unwrap()).unwrap();
+        assert!(parsed.header("X-Path") == Some("C:\\Users\\me"));
+    }
+
+    #[test]
+    fn malformed_ssh2_keys_fail() {
+        let unterminated = RSA_SSH2_KEY.replace(END_LINE, "");
+        assert!(SSH2PublicKey::from_rfc4716(&unterminated).is_err());
+        let truncated = RSA_SSH2_KEY.replace("AAAAB3NzaC1yc2E", "");
+        assert!(SSH2PublicKey::from_rfc4716(&truncated).is_err());
+    }
+}
@@ -1,10 +1,26 @@
 use mpint::*;
+use der::*;
+use ssh::Signature;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+use rand::Rng;
+use num_bigint::BigUint;
+use num_integer::Integer;
+use num_traits::{One, Zero};
 
-#[derive(Deserialize, Debug)]
+pub const RSA_TYPE : &'static str = "ssh-rsa";
+pub const RSA_SHA2_256 : &'static str = "rsa-sha2-256";
+pub const RSA_SHA2_512 : &'static str = "rsa-sha2-512";
+
+pub const RSA_MIN_BITS : usize = 2048;
+pub const RSA_MAX_BITS : usize = 8192;
+const RSA_PUBLIC_EXPONENT : u32 = 65537;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct RSAPublicKey {
-    _type: String,
-    public_exponent: MPUint,
-    modulus: MPUint,
+    pub _type: String,
+    pub public_exponent: MPUint,
+    pub modulus: MPUint,
 }
 
 impl RSAPublicKey {
@@ -37,8 +53,214 @@ impl RSAPublicKey {
 
 #[derive(Deserialize, Debug)]
 pub struct RSASignature {
-    _type: String,
-    signature: MPUint,
+    pub _type: String,
+    pub signature: MPUint,
+}
+
+//  Components are kept in the order OpenSSH stores them: n, e, d, iqmp, p, q.
+#[derive(Clone)]
+pub struct RSAPrivateKey {
+    pub modulus: MPUint,
+    pub public_exponent: MPUint,
+    pub private_exponent: MPUint,
+    pub iqmp: MPUint,
+    pub p: MPUint,
+    pub q: MPUint,
+}
+
+impl RSAPrivateKey {
+    pub fn generate<R: Rng>(bits: usize, rng: &mut R) -> Result<Self, Error> {
+        if bits < RSA_MIN_BITS || bits > RSA_MAX_BITS || bits % 2 != 0 {
+            return Err(Error{kind: InvalidLength});
+        }
+        let e = BigUint::from(RSA_PUBLIC_EXPONENT);
+        loop {
+            let p = generate_prime(bits / 2, &e, rng);
+            let q = generate_prime(bits / 2, &e, rng);
+            if p == q {
+                continue;
+            }
+            let (p, q) = if p > q { (p, q) } else { (q, p) };
+            let n = &p * &q;
+            if n.bits() as usize != bits {
+                continue;
+            }
+            let one = BigUint::one();
+            let lambda = (&p - &one).lcm(&(&q - &one));
+            let d = match e.modinv(&lambda) {
+                Some(d) => d,
+                None => continue,
+            };
+            let iqmp = match q.modinv(&p) {
+                Some(iqmp) => iqmp,
+                None => continue,
+            };
+            return Ok(RSAPrivateKey{
+                modulus: MPUint{be_bytes: n.to_bytes_be()},
+                public_exponent: MPUint{be_bytes: e.to_bytes_be()},
+                private_exponent: MPUint{be_bytes: d.to_bytes_be()},
+                iqmp: MPUint{be_bytes: iqmp.to_bytes_be()},
+                p: MPUint{be_bytes: p.to_bytes_be()},
+                q: MPUint{be_bytes: q.to_bytes_be()},
+            });
+        }
+    }
+
+    pub fn public_key(&self) -> RSAPublicKey {
+        RSAPublicKey{
+            _type: RSA_TYPE.into(),
+            public_exponent: self.public_exponent.clone(),
+            modulus: self.modulus.clone(),
+        }
+    }
+
+    /// Checks fields read from a file or agent request: p and q must be above 1 and
+    /// multiply to n, iqmp must invert q mod p, and ring must accept the whole key.
+    pub fn validate(&self) -> Result<(), Error> {
+        let n = BigUint::from_bytes_be(&self.modulus.be_bytes);
+        let iqmp = BigUint::from_bytes_be(&self.iqmp.be_bytes);
+        let p = BigUint::from_bytes_be(&self.p.be_bytes);
+        let q = BigUint::from_bytes_be(&self.q.be_bytes);
+        let one = BigUint::one();
+        if p <= one || q <= one || &p * &q != n || (&iqmp * &q) % &p != one {
+            return Err(Error{kind: InvalidKey});
+        }
+        ring::signature::RsaKeyPair::from_der(&self.to_pkcs1_der()?).map_err(|_| Error{kind: InvalidKey})?;
+        Ok(())
+    }
+
+    //  PKCS#1 RSAPrivateKey, deriving the CRT exponents OpenSSH does not store.
+    pub fn to_pkcs1_der(&self) -> Result<Vec<u8>, Error> {
+        let d = BigUint::from_bytes_be(&self.private_exponent.be_bytes);
+        let p = BigUint::from_bytes_be(&self.p.be_bytes);
+        let q = BigUint::from_bytes_be(&self.q.be_bytes);
+        if p <= BigUint::one() || q <= BigUint::one() {
+            return Err(Error{kind: InvalidKey});
+        }
+        let dmp1 = &d % (&p - BigUint::one());
+        let dmq1 = &d % (&q - BigUint::one());
+
+        let mut writer = Writer::new();
+        writer.write_sequence(|w| {
+            w.write_small_integer(0);
+            w.write_integer(&self.modulus);
+            w.write_integer(&self.public_exponent);
+            w.write_integer(&self.private_exponent);
+            w.write_integer(&self.p);
+            w.write_integer(&self.q);
+            w.write_integer(&MPUint{be_bytes: dmp1.to_bytes_be()});
+            w.write_integer(&MPUint{be_bytes: dmq1.to_bytes_be()});
+            w.write_integer(&self.iqmp);
+        });
+        Ok(writer.into_inner())
+    }
+
+    pub fn from_pkcs1_der(der: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(der);
+        let mut sequence = reader.read_sequence()?;
+        reader.finish()?;
+        //  Version 1 would introduce multi-prime keys, which OpenSSH cannot represent.
+        if sequence.read_small_integer()? != 0 {
+            return Err(Error{kind: UnsupportedAlgorithm("multi-prime RSA".into())});
+        }
+        let modulus = sequence.read_integer()?;
+        let public_exponent = sequence.read_integer()?;
+        let private_exponent = sequence.read_integer()?;
+        let p = sequence.read_integer()?;
+        let q = sequence.read_integer()?;
+        let _dmp1 = sequence.read_integer()?;
+        let _dmq1 = sequence.read_integer()?;
+        let iqmp = sequence.read_integer()?;
+        sequence.finish()?;
+        let key = RSAPrivateKey{
+            modulus: modulus,
+            public_exponent: public_exponent,
+            private_exponent: private_exponent,
+            iqmp: iqmp,
+            p: p,
+            q: q,
+        };
+        key.validate()?;
+        Ok(key)
+    }
+
+    //  ring only signs with SHA-2, so legacy `ssh-rsa` (SHA-1) signatures can be verified but not produced.
+    pub fn sign(&self, message: &[u8], algorithm: &str) -> Result<Signature, Error> {
+        use ring::signature::{RsaKeyPair, RsaEncoding};
+        use ring::rand::SystemRandom;
+        let padding: &'static dyn RsaEncoding = match algorithm {
+            RSA_SHA2_256 => &ring::signature::RSA_PKCS1_SHA256,
+            RSA_SHA2_512 => &ring::signature::RSA_PKCS1_SHA512,
+            _ => return Err(Error{kind: UnsupportedAlgorithm(algorithm.into())}),
+        };
+        let key_pair = RsaKeyPair::from_der(&self.to_pkcs1_der()?).map_err(|_| Error{kind: InvalidKey})?;
+        let mut signature = vec![0; key_pair.public().modulus_len()];
+        key_pair.sign(padding, &SystemRandom::new(), message, &mut signature)
+            .map_err(|_| Error{kind: Crypto})?;
+        Ok(Signature{
+            _type: algorithm.into(),
+            blob: signature,
+        })
+    }
+}
+
+const SMALL_PRIMES : [u32; 53] = [
+    3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191,
+    193, 197, 199, 211, 223, 227, 229, 233, 239, 241, 251];
+const MILLER_RABIN_ROUNDS : usize = 20;
+
+fn random_biguint<R: Rng>(bits: usize, rng: &mut R) -> BigUint {
+    let mut bytes = vec![0; (bits + 7) / 8];
+    rng.fill_bytes(&mut bytes);
+    let excess = bytes.len() * 8 - bits;
+    bytes[0] &= 0xff >> excess;
+    BigUint::from_bytes_be(&bytes)
+}
+
+//  Random prime of exactly `bits` bits with the top two bits set, so that the
+//  product of two such primes has exactly twice as many bits, and with p - 1
+//  coprime to the public exponent.
+fn generate_prime<R: Rng>(bits: usize, e: &BigUint, rng: &mut R) -> BigUint {
+    let one = BigUint::one();
+    loop {
+        let mut candidate = random_biguint(bits, rng);
+        candidate.set_bit(bits as u64 - 1, true);
+        candidate.set_bit(bits as u64 - 2, true);
+        candidate.set_bit(0, true);
+        if SMALL_PRIMES.iter().any(|sp| (&candidate % sp).is_zero()) {
+            continue;
+        }
+        if !(&candidate - &one).gcd(e).is_one() {
+            continue;
+        }
+        if is_probable_prime(&candidate, rng) {
+            return candidate;
+        }
+    }
+}
+
+fn is_probable_prime<R: Rng>(n: &BigUint, rng: &mut R) -> bool {
+    let one = BigUint::one();
+    let two = BigUint::from(2u32);
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+    'witness: for _ in 0..MILLER_RABIN_ROUNDS {
+        let a = random_biguint(n.bits() as usize, rng) % (n - 3u32) + &two;
+        let mut x = a.modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
 }
 
 #[cfg(test)]
@@ -106,5 +328,27 @@ mod test {
 
         assert!(rsa_public_key.verify(&rsa_signature, &message_bytes));
     }
-    
+
+    #[test]
+    fn test_generate_sign_verify() {
+        use rand::{SeedableRng, XorShiftRng};
+        use serde_ser;
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let private_key = RSAPrivateKey::generate(2048, &mut rng).unwrap();
+        assert!(private_key.modulus.be_bytes.len() == 256);
+        assert!(private_key.sign(b"message", RSA_TYPE).is_err());
+        for algorithm in &[RSA_SHA2_256, RSA_SHA2_512] {
+            let signature = private_key.sign(b"message", algorithm).unwrap();
+            let rsa_signature: RSASignature = serde_de::from_slice(&serde_ser::to_vec(&signature).unwrap()).unwrap();
+            assert!(private_key.public_key().verify(&rsa_signature, b"message"));
+            assert!(!private_key.public_key().verify(&rsa_signature, b"other message"));
+        }
+    }
+
+    #[test]
+    fn test_generate_rejects_small_keys() {
+        use rand::{SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        assert!(RSAPrivateKey::generate(1024, &mut rng).is_err());
+    }
 }
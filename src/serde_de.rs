@@ -23,6 +23,12 @@ pub enum ErrorKind {
     Io(std::io::Error),
     Utf8(std::str::Utf8Error),
     Custom(String),
+    UnsupportedAlgorithm(String),
+    InvalidKey,
+    InvalidFormat,
+    PassphraseRequired,
+    WrongPassphrase,
+    Crypto,
 }
 
 use self::ErrorKind::*;
@@ -42,6 +48,12 @@ impl error::Error for Error {
              Io(ref io_err) => StdError::description(io_err),
              Utf8(ref utf8_err) => StdError::description(utf8_err),
              Custom(ref s) => s,
+             UnsupportedAlgorithm(ref s) => s,
+             InvalidKey => "invalid key",
+             InvalidFormat => "invalid format",
+             PassphraseRequired => "passphrase required",
+             WrongPassphrase => "incorrect passphrase",
+             Crypto => "cryptographic operation failed",
          }
      }
 }
@@ -87,6 +99,22 @@ pub struct Deserializer<R> {
     reader: R,
 }
 
+impl<R: Read> Deserializer<R> {
+    pub fn new(reader: R) -> Self {
+        Deserializer{reader: reader}
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next value from a stream of concatenated wire-format values.
+    pub fn next<T>(&mut self) -> Result<T, Error>
+        where T: de::DeserializeOwned {
+        serde::Deserialize::deserialize(self)
+    }
+}
+
 impl<'x ,'a, R: Read> de::Deserializer<'x> for &'a mut Deserializer<R> {
     type Error = Error;
     fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Self::Error> 
@@ -0,0 +1,291 @@
+use serde::ser;
+use serde;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std;
+use std::io::Write;
+
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Error {
+        Error{kind: Custom(msg.to_string())}
+    }
+}
+
+pub fn to_vec<T: ?Sized>(value: &T) -> Result<Vec<u8>, Error>
+    where T: serde::Serialize {
+    let mut serializer = Serializer{writer: Vec::new()};
+    value.serialize(&mut serializer)?;
+    Ok(serializer.writer)
+}
+
+pub fn to_writer<W, T: ?Sized>(writer: W, value: &T) -> Result<(), Error>
+    where W: Write, T: serde::Serialize {
+    let mut serializer = Serializer{writer: writer};
+    value.serialize(&mut serializer)
+}
+
+pub struct Serializer<W> {
+    writer: W,
+}
+
+impl<W: Write> Serializer<W> {
+    pub fn new(writer: W) -> Self {
+        Serializer{writer: writer}
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Appends the next value to a stream of concatenated wire-format values.
+    pub fn write<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        value.serialize(self)
+    }
+
+    fn write_length(&mut self, len: usize) -> Result<(), Error> {
+        if len > std::u32::MAX as usize {
+            return Err(Error{kind: InvalidLength});
+        }
+        self.writer.write_u32::<BigEndian>(len as u32)?;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::Serializer for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+    type SerializeMap = ser::Impossible<(), Error>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = ser::Impossible<(), Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.writer.write_u8(if v { 1 } else { 0 })?;
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.writer.write_u8(v)?;
+        Ok(())
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.writer.write_u32::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.writer.write_u64::<BigEndian>(v)?;
+        Ok(())
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_char(self, _: char) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_length(v.len())?;
+        self.writer.write_all(v)?;
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_some<T: ?Sized>(self, _: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_newtype_struct<T: ?Sized>(self, _: &'static str, _: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_newtype_variant<T: ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        match len {
+            Some(len) => {
+                self.write_length(len)?;
+                Ok(self)
+            },
+            None => Err(Error{kind: InvalidLength}),
+        }
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Error> {
+        Err(Error{kind: UnsupportedType})
+    }
+}
+
+impl<'a, W: Write> ser::SerializeSeq for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTuple for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTupleStruct for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::SerializeStruct for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), Error>
+        where T: serde::Serialize {
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::serde_de;
+
+    #[test]
+    fn ints_serialize() {
+        #[derive(Serialize)]
+        struct TestInts {
+            b: u8,
+            i: u32,
+            l: u64,
+        }
+        let wire = super::to_vec(&TestInts{
+            b: 0xff,
+            i: 0x01020304,
+            l: 0x0807060504030201,
+        }).unwrap();
+        assert!(wire == b"\xff\x01\x02\x03\x04\x08\x07\x06\x05\x04\x03\x02\x01".to_vec());
+    }
+
+    #[test]
+    fn str_and_bytes_serialize() {
+        assert!(super::to_vec("test").unwrap() == b"\x00\x00\x00\x04test".to_vec());
+        assert!(super::to_vec(&vec![0u8, 1, 2]).unwrap() == b"\x00\x00\x00\x03\x00\x01\x02".to_vec());
+    }
+
+    #[test]
+    fn struct_round_trips() {
+        #[derive(Serialize, Deserialize, PartialEq, Eq)]
+        struct TestStruct {
+            _type: String,
+            flag: bool,
+            blob: Vec<u8>,
+            fixed: (u8, u32),
+        }
+        let value = TestStruct{
+            _type: "ssh-test".into(),
+            flag: true,
+            blob: vec![0xde, 0xad, 0xbe, 0xef],
+            fixed: (7, 42),
+        };
+        let wire = super::to_vec(&value).unwrap();
+        let deserialized: TestStruct = serde_de::from_slice(&wire).unwrap();
+        assert!(deserialized == value);
+    }
+}
@@ -1,9 +1,9 @@
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PublicKeyHeader {
     pub _type: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Signature {
     pub _type: String,
     pub blob: Vec<u8>,
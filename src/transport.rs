@@ -0,0 +1,488 @@
+use cipher::constant_time_eq;
+use serde_de::Error;
+use serde_de::ErrorKind::*;
+
+use byteorder::{BigEndian, ByteOrder};
+use ring::{aead, hmac};
+
+//  Packet protection for the SSH transport once keys are exchanged. Classic ciphers
+//  encrypt the whole packet and append a MAC of the plaintext, or with the
+//  `-etm@openssh.com` MACs leave the length in the clear and MAC the ciphertext.
+//  AEAD ciphers also keep the length out of the encryption proper, authenticating it.
+
+pub struct CipherSpec {
+    pub name: &'static str,
+    pub key_len: usize,
+    pub iv_len: usize,
+    pub block_size: usize,
+    /// The length of an AEAD cipher's tag; such ciphers need no MAC.
+    pub auth_len: usize,
+}
+
+pub struct MacSpec {
+    pub name: &'static str,
+    pub key_len: usize,
+    pub mac_len: usize,
+    /// Encrypt-then-MAC.
+    pub etm: bool,
+}
+
+pub const CIPHERS : &'static [CipherSpec] = &[
+    CipherSpec{name: "chacha20-poly1305@openssh.com", key_len: 64, iv_len: 0, block_size: 8, auth_len: 16},
+    CipherSpec{name: "aes128-gcm@openssh.com", key_len: 16, iv_len: 12, block_size: 16, auth_len: 16},
+    CipherSpec{name: "aes256-gcm@openssh.com", key_len: 32, iv_len: 12, block_size: 16, auth_len: 16},
+    CipherSpec{name: "aes128-ctr", key_len: 16, iv_len: 16, block_size: 16, auth_len: 0},
+    CipherSpec{name: "aes192-ctr", key_len: 24, iv_len: 16, block_size: 16, auth_len: 0},
+    CipherSpec{name: "aes256-ctr", key_len: 32, iv_len: 16, block_size: 16, auth_len: 0},
+];
+
+pub const MACS : &'static [MacSpec] = &[
+    MacSpec{name: "hmac-sha2-256-etm@openssh.com", key_len: 32, mac_len: 32, etm: true},
+    MacSpec{name: "hmac-sha2-512-etm@openssh.com", key_len: 64, mac_len: 64, etm: true},
+    MacSpec{name: "hmac-sha2-256", key_len: 32, mac_len: 32, etm: false},
+    MacSpec{name: "hmac-sha2-512", key_len: 64, mac_len: 64, etm: false},
+];
+
+pub fn cipher_by_name(name: &str) -> Result<&'static CipherSpec, Error> {
+    CIPHERS.iter().find(|cipher| cipher.name == name).ok_or(Error{kind: UnsupportedAlgorithm(name.into())})
+}
+
+pub fn mac_by_name(name: &str) -> Result<&'static MacSpec, Error> {
+    MACS.iter().find(|mac| mac.name == name).ok_or(Error{kind: UnsupportedAlgorithm(name.into())})
+}
+
+/// Protects packets in one direction. A packet is the length field, padding length,
+/// payload and padding; `sequence_number` is the packet's number in that direction.
+pub trait PacketCipher {
+    fn block_size(&self) -> usize;
+
+    /// Whether the length field is sent unencrypted (though authenticated), which also
+    /// leaves it out of block alignment.
+    fn length_in_clear(&self) -> bool;
+
+    /// The tag or MAC following each packet.
+    fn tag_len(&self) -> usize;
+
+    /// Encrypts `packet` in place and appends its tag.
+    fn seal(&mut self, sequence_number: u32, packet: &mut Vec<u8>) -> Result<(), Error>;
+
+    /// How much of a packet must be read to learn its length.
+    fn header_len(&self) -> usize {
+        if self.length_in_clear() { 4 } else { self.block_size() }
+    }
+
+    /// Returns the packet length from its first `header_len` bytes, which a cipher
+    /// encrypting the length decrypts in place.
+    fn open_length(&mut self, sequence_number: u32, header: &mut [u8]) -> Result<usize, Error>;
+
+    /// Authenticates and decrypts a whole packet in place, given its header as left
+    /// by `open_length`, failing with `Crypto` if the tag is wrong.
+    fn open(&mut self, sequence_number: u32, packet: &mut [u8], tag: &[u8]) -> Result<(), Error>;
+}
+
+/// No protection, before the first key exchange completes.
+pub struct Plain;
+
+impl PacketCipher for Plain {
+    fn block_size(&self) -> usize { 8 }
+    fn length_in_clear(&self) -> bool { false }
+    fn tag_len(&self) -> usize { 0 }
+    fn header_len(&self) -> usize { 4 }
+
+    fn seal(&mut self, _sequence_number: u32, _packet: &mut Vec<u8>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn open_length(&mut self, _sequence_number: u32, header: &mut [u8]) -> Result<usize, Error> {
+        Ok(BigEndian::read_u32(header) as usize)
+    }
+
+    fn open(&mut self, _sequence_number: u32, _packet: &mut [u8], _tag: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn check_len(data: &[u8], len: usize) -> Result<(), Error> {
+    if data.len() != len {
+        return Err(Error{kind: InvalidLength});
+    }
+    Ok(())
+}
+
+/// An HMAC over the sequence number and packet.
+pub struct Mac {
+    pub spec: &'static MacSpec,
+    key: hmac::Key,
+}
+
+impl Mac {
+    pub fn new(name: &str, key: &[u8]) -> Result<Self, Error> {
+        let spec = mac_by_name(name)?;
+        check_len(key, spec.key_len)?;
+        let algorithm = if spec.mac_len == 32 { hmac::HMAC_SHA256 } else { hmac::HMAC_SHA512 };
+        Ok(Mac{
+            spec: spec,
+            key: hmac::Key::new(algorithm, key),
+        })
+    }
+
+    pub fn sign(&self, sequence_number: u32, packet: &[u8]) -> Vec<u8> {
+        let mut sequence = [0; 4];
+        BigEndian::write_u32(&mut sequence, sequence_number);
+        let mut context = hmac::Context::with_key(&self.key);
+        context.update(&sequence);
+        context.update(packet);
+        context.sign().as_ref().to_vec()
+    }
+}
+
+enum AesCtr {
+    Aes128(ctr::Ctr128BE<aes::Aes128>),
+    Aes192(ctr::Ctr128BE<aes::Aes192>),
+    Aes256(ctr::Ctr128BE<aes::Aes256>),
+}
+
+impl AesCtr {
+    fn new(key: &[u8], iv: &[u8]) -> Result<Self, Error> {
+        use ctr::cipher::KeyIvInit;
+        check_len(iv, 16)?;
+        Ok(match key.len() {
+            16 => AesCtr::Aes128(ctr::Ctr128BE::new(key.into(), iv.into())),
+            24 => AesCtr::Aes192(ctr::Ctr128BE::new(key.into(), iv.into())),
+            32 => AesCtr::Aes256(ctr::Ctr128BE::new(key.into(), iv.into())),
+            _ => return Err(Error{kind: InvalidLength}),
+        })
+    }
+
+    //  The keystream runs on across packets.
+    fn apply(&mut self, data: &mut [u8]) {
+        use ctr::cipher::StreamCipher;
+        match *self {
+            AesCtr::Aes128(ref mut cipher) => cipher.apply_keystream(data),
+            AesCtr::Aes192(ref mut cipher) => cipher.apply_keystream(data),
+            AesCtr::Aes256(ref mut cipher) => cipher.apply_keystream(data),
+        }
+    }
+}
+
+/// `aes*-ctr` with an HMAC.
+pub struct CtrHmac {
+    cipher: AesCtr,
+    mac: Mac,
+}
+
+impl CtrHmac {
+    pub fn new(key: &[u8], iv: &[u8], mac: Mac) -> Result<Self, Error> {
+        Ok(CtrHmac{
+            cipher: AesCtr::new(key, iv)?,
+            mac: mac,
+        })
+    }
+}
+
+impl PacketCipher for CtrHmac {
+    fn block_size(&self) -> usize { 16 }
+    fn length_in_clear(&self) -> bool { self.mac.spec.etm }
+    fn tag_len(&self) -> usize { self.mac.spec.mac_len }
+
+    fn seal(&mut self, sequence_number: u32, packet: &mut Vec<u8>) -> Result<(), Error> {
+        let tag = if self.mac.spec.etm {
+            self.cipher.apply(&mut packet[4..]);
+            self.mac.sign(sequence_number, packet)
+        } else {
+            let tag = self.mac.sign(sequence_number, packet);
+            self.cipher.apply(packet);
+            tag
+        };
+        packet.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    fn open_length(&mut self, _sequence_number: u32, header: &mut [u8]) -> Result<usize, Error> {
+        if !self.mac.spec.etm {
+            self.cipher.apply(header);
+        }
+        Ok(BigEndian::read_u32(header) as usize)
+    }
+
+    fn open(&mut self, sequence_number: u32, packet: &mut [u8], tag: &[u8]) -> Result<(), Error> {
+        if self.mac.spec.etm {
+            if !constant_time_eq(&self.mac.sign(sequence_number, packet), tag) {
+                return Err(Error{kind: Crypto});
+            }
+            self.cipher.apply(&mut packet[4..]);
+            return Ok(());
+        }
+        self.cipher.apply(&mut packet[self.block_size()..]);
+        if !constant_time_eq(&self.mac.sign(sequence_number, packet), tag) {
+            return Err(Error{kind: Crypto});
+        }
+        Ok(())
+    }
+}
+
+/// `aes128-gcm@openssh.com` and `aes256-gcm@openssh.com` (RFC 5647): the length is
+/// additional data, and the nonce's 64-bit invocation counter counts packets.
+pub struct AesGcm {
+    key: aead::LessSafeKey,
+    nonce: [u8; 12],
+}
+
+impl AesGcm {
+    pub fn new(key: &[u8], iv: &[u8]) -> Result<Self, Error> {
+        check_len(iv, 12)?;
+        let algorithm = match key.len() {
+            16 => &aead::AES_128_GCM,
+            32 => &aead::AES_256_GCM,
+            _ => return Err(Error{kind: InvalidLength}),
+        };
+        let key = aead::UnboundKey::new(algorithm, key).map_err(|_| Error{kind: InvalidKey})?;
+        let mut nonce = [0; 12];
+        nonce.copy_from_slice(iv);
+        Ok(AesGcm{
+            key: aead::LessSafeKey::new(key),
+            nonce: nonce,
+        })
+    }
+
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let nonce = aead::Nonce::assume_unique_for_key(self.nonce);
+        let invocation = BigEndian::read_u64(&self.nonce[4..]).wrapping_add(1);
+        BigEndian::write_u64(&mut self.nonce[4..], invocation);
+        nonce
+    }
+}
+
+impl PacketCipher for AesGcm {
+    fn block_size(&self) -> usize { 16 }
+    fn length_in_clear(&self) -> bool { true }
+    fn tag_len(&self) -> usize { 16 }
+
+    fn seal(&mut self, _sequence_number: u32, packet: &mut Vec<u8>) -> Result<(), Error> {
+        let nonce = self.next_nonce();
+        let (length, body) = packet.split_at_mut(4);
+        let tag = self.key.seal_in_place_separate_tag(nonce, aead::Aad::from(&*length), body)
+            .map_err(|_| Error{kind: Crypto})?;
+        packet.extend_from_slice(tag.as_ref());
+        Ok(())
+    }
+
+    fn open_length(&mut self, _sequence_number: u32, header: &mut [u8]) -> Result<usize, Error> {
+        Ok(BigEndian::read_u32(header) as usize)
+    }
+
+    fn open(&mut self, _sequence_number: u32, packet: &mut [u8], tag: &[u8]) -> Result<(), Error> {
+        let nonce = self.next_nonce();
+        let mut body = packet[4..].to_vec();
+        body.extend_from_slice(tag);
+        let plaintext_len = self.key.open_in_place(nonce, aead::Aad::from(&packet[..4]), &mut body)
+            .map_err(|_| Error{kind: Crypto})?.len();
+        packet[4..].copy_from_slice(&body[..plaintext_len]);
+        Ok(())
+    }
+}
+
+/// `chacha20-poly1305@openssh.com`: the second half of the key encrypts the length,
+/// the first half the rest of the packet and the Poly1305 key, each with the
+/// sequence number as nonce. The tag covers the encrypted length and packet.
+pub struct ChaCha20Poly1305 {
+    main_key: [u8; 32],
+    header_key: [u8; 32],
+}
+
+impl ChaCha20Poly1305 {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        check_len(key, 64)?;
+        let mut main_key = [0; 32];
+        let mut header_key = [0; 32];
+        main_key.copy_from_slice(&key[..32]);
+        header_key.copy_from_slice(&key[32..]);
+        Ok(ChaCha20Poly1305{
+            main_key: main_key,
+            header_key: header_key,
+        })
+    }
+
+    fn keystream(key: &[u8; 32], sequence_number: u32, counter: u32, data: &mut [u8]) {
+        use chacha20::ChaCha20Legacy;
+        use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+        let mut nonce = [0; 8];
+        BigEndian::write_u64(&mut nonce, sequence_number as u64);
+        let mut cipher = ChaCha20Legacy::new(key.into(), (&nonce).into());
+        cipher.seek(counter as u64 * 64);
+        cipher.apply_keystream(data);
+    }
+
+    fn tag(&self, sequence_number: u32, ciphertext: &[u8]) -> Vec<u8> {
+        use poly1305::Poly1305;
+        use poly1305::universal_hash::KeyInit;
+        let mut poly_key = [0; 32];
+        ChaCha20Poly1305::keystream(&self.main_key, sequence_number, 0, &mut poly_key);
+        Poly1305::new((&poly_key).into()).compute_unpadded(ciphertext).to_vec()
+    }
+}
+
+impl PacketCipher for ChaCha20Poly1305 {
+    fn block_size(&self) -> usize { 8 }
+    fn length_in_clear(&self) -> bool { true }
+    fn tag_len(&self) -> usize { 16 }
+
+    fn seal(&mut self, sequence_number: u32, packet: &mut Vec<u8>) -> Result<(), Error> {
+        ChaCha20Poly1305::keystream(&self.header_key, sequence_number, 0, &mut packet[..4]);
+        ChaCha20Poly1305::keystream(&self.main_key, sequence_number, 1, &mut packet[4..]);
+        let tag = self.tag(sequence_number, packet);
+        packet.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    //  The header stays encrypted until the tag over it is checked.
+    fn open_length(&mut self, sequence_number: u32, header: &mut [u8]) -> Result<usize, Error> {
+        let mut length = [0; 4];
+        length.copy_from_slice(&header[..4]);
+        ChaCha20Poly1305::keystream(&self.header_key, sequence_number, 0, &mut length);
+        Ok(BigEndian::read_u32(&length) as usize)
+    }
+
+    fn open(&mut self, sequence_number: u32, packet: &mut [u8], tag: &[u8]) -> Result<(), Error> {
+        if !constant_time_eq(&self.tag(sequence_number, packet), tag) {
+            return Err(Error{kind: Crypto});
+        }
+        ChaCha20Poly1305::keystream(&self.header_key, sequence_number, 0, &mut packet[..4]);
+        ChaCha20Poly1305::keystream(&self.main_key, sequence_number, 1, &mut packet[4..]);
+        Ok(())
+    }
+}
+
+/// The protection for one direction from the negotiated cipher and MAC names and
+/// derived keys. AEAD ciphers ignore the MAC, whose name may then be anything.
+pub fn new_packet_cipher(cipher: &str, key: &[u8], iv: &[u8], mac: &str, mac_key: &[u8]) -> Result<Box<dyn PacketCipher + Send>, Error> {
+    let spec = cipher_by_name(cipher)?;
+    check_len(key, spec.key_len)?;
+    check_len(iv, spec.iv_len)?;
+    Ok(match spec.name {
+        "chacha20-poly1305@openssh.com" => Box::new(ChaCha20Poly1305::new(key)?),
+        "aes128-gcm@openssh.com" | "aes256-gcm@openssh.com" => Box::new(AesGcm::new(key, iv)?),
+        _ => Box::new(CtrHmac::new(key, iv, Mac::new(mac, mac_key)?)?),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex;
+
+    //  A packet with a 7-byte payload, 16-byte aligned with or without its length field.
+    fn packet(length_in_clear: bool) -> Vec<u8> {
+        let (len, padding) = if length_in_clear { (16, 8) } else { (28, 20) };
+        let mut packet = vec![0, 0, 0, len, padding];
+        packet.extend_from_slice(b"payload");
+        packet.extend_from_slice(&vec![0xaa; padding as usize]);
+        packet
+    }
+
+    fn seal_and_open(seal: &mut dyn PacketCipher, open: &mut dyn PacketCipher, sequence_number: u32) -> Vec<u8> {
+        let plain = packet(seal.length_in_clear());
+        let mut sealed = plain.clone();
+        seal.seal(sequence_number, &mut sealed).unwrap();
+        let tag_at = sealed.len() - seal.tag_len();
+
+        let mut received = sealed[..tag_at].to_vec();
+        let header_len = open.header_len();
+        assert!(open.open_length(sequence_number, &mut received[..header_len]).unwrap() == plain.len() - 4);
+        open.open(sequence_number, &mut received, &sealed[tag_at..]).unwrap();
+        assert!(received == plain);
+        sealed
+    }
+
+    #[test]
+    fn hmac_test_vectors() {
+        //  RFC 4231 test cases 1 and 2, the first four bytes of data standing in for the
+        //  sequence number. HMAC zero-pads short keys, so padding them here changes nothing.
+        let mac = Mac::new("hmac-sha2-256", &[&[0x0b; 20][..], &[0; 12][..]].concat()).unwrap();
+        assert!(hex::encode(&mac.sign(0x48692054, b"here")) == "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        let mac = Mac::new("hmac-sha2-256-etm@openssh.com", &[&b"Jefe"[..], &[0; 28][..]].concat()).unwrap();
+        assert!(hex::encode(&mac.sign(0x77686174, b" do ya want for nothing?")) == "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let mac = Mac::new("hmac-sha2-512", &[&b"Jefe"[..], &[0; 60][..]].concat()).unwrap();
+        assert!(hex::encode(&mac.sign(0x77686174, b" do ya want for nothing?")) == "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737");
+        assert!(Mac::new("hmac-sha2-256", &[0; 20]).is_err());
+        assert!(Mac::new("hmac-md5", &[0; 16]).is_err());
+    }
+
+    #[test]
+    fn aes_ctr_test_vectors() {
+        //  NIST SP 800-38A F.5.1 and F.5.5, with the keystream continuing across calls.
+        let iv = hex::decode("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").unwrap();
+        let plaintext = hex::decode("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51").unwrap();
+        for &(key, ciphertext) in &[
+            ("2b7e151628aed2a6abf7158809cf4f3c", "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff"),
+            ("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4", "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5"),
+        ] {
+            let mut cipher = AesCtr::new(&hex::decode(key).unwrap(), &iv).unwrap();
+            let mut data = plaintext.clone();
+            cipher.apply(&mut data[..16]);
+            cipher.apply(&mut data[16..]);
+            assert!(hex::encode(&data) == ciphertext);
+        }
+    }
+
+    #[test]
+    fn sealed_packet_test_vectors() {
+        //  Packet 3 under each construction, computed independently with OpenSSH's rules.
+        let key : Vec<u8> = (0..64).collect();
+        for &(cipher, key_len, iv, mac, mac_key, sealed) in &[
+            ("chacha20-poly1305@openssh.com", 64, &[][..], "", &[][..],
+                "fb1a929a88357dc25e25c51522e556a0114d3ce9245d98dd718a78ad880c7815878c7596"),
+            ("aes256-gcm@openssh.com", 32, &[7; 12][..], "", &[][..],
+                "00000010071ac7250360b6906c37b92a2afb5e3108bc0e03b32d2ea57f035d5b26ef32a6"),
+            ("aes128-ctr", 16, &[7; 16][..], "hmac-sha2-256-etm@openssh.com", &[9; 32][..],
+                "00000010f1ff90c56431f95ec234e2f15066d7b7e7d71b33b49a88b33ccc30e76bf4938105ce47fd627088dd956b6c7a262b8335"),
+            ("aes256-ctr", 32, &[7; 16][..], "hmac-sha2-512", &[9; 64][..],
+                "07cee8782d31b07bd5b30175dfdea9d854a856b191d843b113e8bcce050dc4aaeac209e4d42d5bb06ae79f2055981a1819255b777ff0de63fb4adae2a536c42bf6978c9fabd9758d0dcfb994c3ca98afb485e117eb7ac4814035bffaf88594c8"),
+        ] {
+            let mut seal = new_packet_cipher(cipher, &key[..key_len], iv, mac, mac_key).unwrap();
+            let mut open = new_packet_cipher(cipher, &key[..key_len], iv, mac, mac_key).unwrap();
+            assert!(hex::encode(&seal_and_open(&mut *seal, &mut *open, 3)) == sealed);
+        }
+    }
+
+    #[test]
+    fn ciphers_round_trip() {
+        for spec in CIPHERS {
+            for mac in MACS {
+                let key : Vec<u8> = (0..spec.key_len as u8).collect();
+                let iv = vec![7; spec.iv_len];
+                let mac_key = vec![9; mac.key_len];
+                let mut seal = new_packet_cipher(spec.name, &key, &iv, mac.name, &mac_key).unwrap();
+                let mut open = new_packet_cipher(spec.name, &key, &iv, mac.name, &mac_key).unwrap();
+                assert!(seal.block_size() == spec.block_size);
+                let first = seal_and_open(&mut *seal, &mut *open, 3);
+                //  Cipher state and sequence numbers make each packet's protection differ.
+                assert!(seal_and_open(&mut *seal, &mut *open, 4) != first);
+            }
+        }
+        assert!(new_packet_cipher("aes128-cbc", &[0; 16], &[0; 16], "hmac-sha2-256", &[0; 32]).is_err());
+        assert!(new_packet_cipher("aes128-ctr", &[0; 16], &[0; 16], "none", &[]).is_err());
+    }
+
+    #[test]
+    fn tampering_fails() {
+        for &(cipher, key_len, iv_len) in &[("chacha20-poly1305@openssh.com", 64, 0), ("aes256-gcm@openssh.com", 32, 12), ("aes128-ctr", 16, 16)] {
+            for &(mac, mac_key_len) in &[("hmac-sha2-256", 32), ("hmac-sha2-512-etm@openssh.com", 64)] {
+                let mut seal = new_packet_cipher(cipher, &vec![1; key_len], &vec![2; iv_len], mac, &vec![3; mac_key_len]).unwrap();
+                let mut open = new_packet_cipher(cipher, &vec![1; key_len], &vec![2; iv_len], mac, &vec![3; mac_key_len]).unwrap();
+                let mut sealed = packet(seal.length_in_clear());
+                seal.seal(0, &mut sealed).unwrap();
+                let tag_at = sealed.len() - seal.tag_len();
+                let mut received = sealed[..tag_at].to_vec();
+                let header_len = open.header_len();
+                open.open_length(0, &mut received[..header_len]).unwrap();
+                let last = received.len() - 1;
+                received[last] ^= 1;
+                match open.open(0, &mut received, &sealed[tag_at..]) {
+                    Err(Error{kind: Crypto}) => {},
+                    _ => assert!("expected" == "Crypto"),
+                }
+            }
+        }
+    }
+}
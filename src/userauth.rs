@@ -0,0 +1,482 @@
+use allowed_signers::parse_time;
+use authorized_keys::{AuthorizedKey, AuthorizedKeys, KeyOption};
+use certificate::{Certificate, CertType, CERT_SUFFIX};
+use public_key::PublicKey;
+use rsa::{RSA_TYPE, RSA_SHA2_256, RSA_SHA2_512};
+use serde_de::{self, Deserializer, Error};
+use serde_de::ErrorKind::*;
+use serde_ser::Serializer;
+use ssh::{PublicKeyHeader, Signature};
+use serde;
+
+use std::io::Cursor;
+
+//  The data signed for "publickey" user authentication (RFC 4252 section 7), and
+//  OpenSSH's "publickey-hostbound-v00@openssh.com", which also covers the server's
+//  host key so that an agent can tell which host it is authenticating to.
+
+pub const SSH_MSG_USERAUTH_REQUEST : u8 = 50;
+pub const PUBLICKEY_METHOD : &'static str = "publickey";
+pub const PUBLICKEY_HOSTBOUND_METHOD : &'static str = "publickey-hostbound-v00@openssh.com";
+pub const CONNECTION_SERVICE : &'static str = "ssh-connection";
+
+//  Certificate critical options sshd understands; certificates with others are refused.
+const CRITICAL_OPTIONS : &'static [&'static str] = &["force-command", "source-address", "verify-required"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserauthSignedData {
+    pub session_id: Vec<u8>,
+    pub user: String,
+    pub service: String,
+    /// `PUBLICKEY_METHOD` or `PUBLICKEY_HOSTBOUND_METHOD`.
+    pub method: String,
+    pub algorithm: String,
+    /// A public key or certificate blob.
+    pub public_key: Vec<u8>,
+    /// The server's host key, present exactly for the host-bound method.
+    pub host_key: Option<Vec<u8>>,
+}
+
+/// A publickey SSH_MSG_USERAUTH_REQUEST as received by a server. Without a
+/// signature it asks whether the key would be accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserauthRequest {
+    pub user: String,
+    pub service: String,
+    pub method: String,
+    pub algorithm: String,
+    pub public_key: Vec<u8>,
+    pub host_key: Option<Vec<u8>>,
+    /// A wire-format signature blob.
+    pub signature: Option<Vec<u8>>,
+}
+
+/// Why a server accepts a key.
+#[derive(Clone)]
+pub enum Authorization<'a> {
+    /// The key is listed in authorized_keys.
+    Key(&'a AuthorizedKey),
+    /// A user certificate signed by a `cert-authority` entry in authorized_keys.
+    CertAuthority(&'a AuthorizedKey, Certificate),
+    /// A user certificate signed by a trusted CA, as with sshd's `TrustedUserCAKeys`.
+    TrustedCa(Certificate),
+}
+
+impl<'a> Authorization<'a> {
+    /// The authorized_keys options that apply to the session.
+    pub fn options(&self) -> &[KeyOption] {
+        match *self {
+            Authorization::Key(entry) | Authorization::CertAuthority(entry, _) => &entry.options,
+            Authorization::TrustedCa(_) => &[],
+        }
+    }
+
+    pub fn certificate(&self) -> Option<&Certificate> {
+        match *self {
+            Authorization::Key(_) => None,
+            Authorization::CertAuthority(_, ref certificate) | Authorization::TrustedCa(ref certificate) => Some(certificate),
+        }
+    }
+}
+
+fn next<T>(cursor: &mut Cursor<&[u8]>) -> Result<T, Error> where T: for<'x> serde::Deserialize<'x> {
+    Deserializer::new(cursor).next()
+}
+
+fn strip_cert_suffix(name: &str) -> (&str, bool) {
+    match name.ends_with(CERT_SUFFIX) {
+        true => (&name[..name.len() - CERT_SUFFIX.len()], true),
+        false => (name, false),
+    }
+}
+
+/// Whether `algorithm` is a signature algorithm for the key or certificate `key_blob`:
+/// its own type, or for RSA also `rsa-sha2-256` and `rsa-sha2-512`.
+pub fn algorithm_matches_key(algorithm: &str, key_blob: &[u8]) -> bool {
+    let key_type = match serde_de::from_slice::<PublicKeyHeader>(key_blob) {
+        Ok(header) => header._type,
+        _ => return false,
+    };
+    let (key_type, key_is_cert) = strip_cert_suffix(&key_type);
+    let (algorithm, algorithm_is_cert) = strip_cert_suffix(algorithm);
+    key_is_cert == algorithm_is_cert && (algorithm == key_type
+        || (key_type == RSA_TYPE && (algorithm == RSA_SHA2_256 || algorithm == RSA_SHA2_512)))
+}
+
+/// Verifies `signature` over `data` by the key or certificate `key_blob` using the
+/// negotiated `algorithm`, which must suit the key and match the signature's type.
+/// SHA-1 `ssh-rsa` signatures are refused.
+pub fn verify_algorithm_signature(algorithm: &str, key_blob: &[u8], signature: &[u8], data: &[u8]) -> bool {
+    let (signature_algorithm, is_cert) = strip_cert_suffix(algorithm);
+    if signature_algorithm == RSA_TYPE || !algorithm_matches_key(algorithm, key_blob) {
+        return false;
+    }
+    match serde_de::from_slice::<Signature>(signature) {
+        Ok(ref parsed) if parsed._type == signature_algorithm => {},
+        _ => return false,
+    }
+    let key_blob = match is_cert {
+        true => match Certificate::from_bytes(key_blob).and_then(|certificate| certificate.public_key.to_bytes()) {
+            Ok(key_blob) => key_blob,
+            _ => return false,
+        },
+        false => key_blob.to_vec(),
+    };
+    ::verify_signature(&key_blob, signature, data)
+}
+
+impl UserauthSignedData {
+    /// Parses signed data, failing with `InvalidFormat` unless it is exactly a
+    /// publickey authentication request whose algorithm suits its key.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(data);
+        let session_id = next(&mut cursor)?;
+        if next::<u8>(&mut cursor)? != SSH_MSG_USERAUTH_REQUEST {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let user = next(&mut cursor)?;
+        let service = next(&mut cursor)?;
+        let method : String = next(&mut cursor)?;
+        if method != PUBLICKEY_METHOD && method != PUBLICKEY_HOSTBOUND_METHOD {
+            return Err(Error{kind: InvalidFormat});
+        }
+        if next::<u8>(&mut cursor)? != 1 {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let algorithm : String = next(&mut cursor)?;
+        let public_key : Vec<u8> = next(&mut cursor)?;
+        let host_key = match method == PUBLICKEY_HOSTBOUND_METHOD {
+            true => Some(next(&mut cursor)?),
+            false => None,
+        };
+        if cursor.position() as usize != data.len() || !algorithm_matches_key(&algorithm, &public_key) {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(UserauthSignedData{
+            session_id: session_id,
+            user: user,
+            service: service,
+            method: method,
+            algorithm: algorithm,
+            public_key: public_key,
+            host_key: host_key,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&self.session_id)?;
+        ser.write(&SSH_MSG_USERAUTH_REQUEST)?;
+        ser.write(&self.user)?;
+        ser.write(&self.service)?;
+        ser.write(&self.method)?;
+        ser.write(&1u8)?;
+        ser.write(&self.algorithm)?;
+        ser.write(&self.public_key)?;
+        if let Some(ref host_key) = self.host_key {
+            ser.write(host_key)?;
+        }
+        Ok(ser.into_inner())
+    }
+
+    /// The authenticating key, failing for certificates.
+    pub fn key(&self) -> Result<PublicKey, Error> {
+        PublicKey::from_bytes(&self.public_key)
+    }
+
+    /// The host key for the host-bound method.
+    pub fn host(&self) -> Option<Result<PublicKey, Error>> {
+        self.host_key.as_ref().map(|host_key| PublicKey::from_bytes(host_key))
+    }
+}
+
+//  Checks a user certificate as sshd does. With a `principals` list from the
+//  authorized_keys entry, a certificate principal must appear in it; otherwise
+//  the certificate must name the user.
+fn certificate_authorized(certificate: &Certificate, user: &str, principals: Option<&str>, time: u64) -> bool {
+    let principal_matches = match principals {
+        Some(list) => certificate.principals.iter().any(|principal| list.split(',').any(|p| p == principal)),
+        None => certificate.principals.iter().any(|principal| principal == user),
+    };
+    certificate.cert_type == CertType::User
+        && principal_matches
+        && certificate.is_valid_at(time)
+        && certificate.critical_options.iter().all(|&(ref name, _)| CRITICAL_OPTIONS.contains(&name.as_str()))
+        && certificate.verify_signature()
+}
+
+fn expired(entry: &AuthorizedKey, time: u64) -> bool {
+    entry.options.iter().any(|option| match *option {
+        KeyOption::ExpiryTime(ref expiry) => parse_time(expiry).map(|expiry| expiry < time).unwrap_or(true),
+        _ => false,
+    })
+}
+
+/// Finds the authorized_keys entry accepting `key_blob` for `user` at `time`: a
+/// listed key, or a user certificate from a `cert-authority` entry. Entries past
+/// their `expiry-time` are skipped; `from` and certificate `source-address`
+/// restrictions are left to the caller, which knows the client address.
+pub fn authorize<'a>(key_blob: &[u8], user: &str, authorized_keys: &'a AuthorizedKeys, time: u64) -> Option<Authorization<'a>> {
+    let entries = authorized_keys.keys().into_iter().filter(|entry| !expired(entry, time));
+    match Certificate::from_bytes(key_blob) {
+        Ok(certificate) => {
+            for entry in entries {
+                let principals = entry.options.iter().filter_map(|option| match *option {
+                    KeyOption::Principals(ref principals) => Some(&principals[..]),
+                    _ => None,
+                }).next();
+                if entry.is_cert_authority() && entry.public_key == certificate.signature_key
+                    && certificate_authorized(&certificate, user, principals, time) {
+                    return Some(Authorization::CertAuthority(entry, certificate));
+                }
+            }
+            None
+        },
+        Err(_) => {
+            let key = PublicKey::from_bytes(key_blob).ok()?;
+            entries.into_iter().find(|entry| entry.matches(&key)).map(Authorization::Key)
+        },
+    }
+}
+
+/// Accepts a user certificate for `user` signed by one of the trusted `cas`.
+pub fn authorize_trusted_ca(key_blob: &[u8], user: &str, cas: &[PublicKey], time: u64) -> Option<Authorization<'static>> {
+    let certificate = Certificate::from_bytes(key_blob).ok()?;
+    if cas.contains(&certificate.signature_key) && certificate_authorized(&certificate, user, None, time) {
+        return Some(Authorization::TrustedCa(certificate));
+    }
+    None
+}
+
+impl UserauthRequest {
+    /// Parses a publickey request message, starting with its type byte.
+    pub fn parse(message: &[u8]) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(message);
+        if next::<u8>(&mut cursor)? != SSH_MSG_USERAUTH_REQUEST {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let user = next(&mut cursor)?;
+        let service = next(&mut cursor)?;
+        let method : String = next(&mut cursor)?;
+        if method != PUBLICKEY_METHOD && method != PUBLICKEY_HOSTBOUND_METHOD {
+            return Err(Error{kind: InvalidFormat});
+        }
+        let signed = match next::<u8>(&mut cursor)? {
+            0 => false,
+            1 => true,
+            _ => return Err(Error{kind: InvalidFormat}),
+        };
+        let algorithm = next(&mut cursor)?;
+        let public_key = next(&mut cursor)?;
+        let host_key = match method == PUBLICKEY_HOSTBOUND_METHOD {
+            true => Some(next(&mut cursor)?),
+            false => None,
+        };
+        let signature = match signed {
+            true => Some(next(&mut cursor)?),
+            false => None,
+        };
+        if cursor.position() as usize != message.len() {
+            return Err(Error{kind: InvalidFormat});
+        }
+        Ok(UserauthRequest{
+            user: user,
+            service: service,
+            method: method,
+            algorithm: algorithm,
+            public_key: public_key,
+            host_key: host_key,
+            signature: signature,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(Vec::new());
+        ser.write(&SSH_MSG_USERAUTH_REQUEST)?;
+        ser.write(&self.user)?;
+        ser.write(&self.service)?;
+        ser.write(&self.method)?;
+        ser.write(&(self.signature.is_some() as u8))?;
+        ser.write(&self.algorithm)?;
+        ser.write(&self.public_key)?;
+        if let Some(ref host_key) = self.host_key {
+            ser.write(host_key)?;
+        }
+        if let Some(ref signature) = self.signature {
+            ser.write(signature)?;
+        }
+        Ok(ser.into_inner())
+    }
+
+    /// The data the client signed for this request in session `session_id`.
+    pub fn signed_data(&self, session_id: &[u8]) -> UserauthSignedData {
+        UserauthSignedData{
+            session_id: session_id.to_vec(),
+            user: self.user.clone(),
+            service: self.service.clone(),
+            method: self.method.clone(),
+            algorithm: self.algorithm.clone(),
+            public_key: self.public_key.clone(),
+            host_key: self.host_key.clone(),
+        }
+    }
+
+    /// Whether the request carries a valid signature for `session_id`, made with the
+    /// algorithm it names, which must suit its key. Like sshd, legacy `ssh-rsa`
+    /// (SHA-1) signatures are refused.
+    pub fn verify_signature(&self, session_id: &[u8]) -> bool {
+        let signature = match self.signature {
+            Some(ref signature) => signature,
+            None => return false,
+        };
+        match self.signed_data(session_id).to_bytes() {
+            Ok(data) => verify_algorithm_signature(&self.algorithm, &self.public_key, signature, &data),
+            _ => false,
+        }
+    }
+
+    /// Verifies the request's signature and finds the authorized_keys entry accepting it.
+    pub fn verify<'a>(&self, session_id: &[u8], authorized_keys: &'a AuthorizedKeys, time: u64) -> Option<Authorization<'a>> {
+        if !self.verify_signature(session_id) {
+            return None;
+        }
+        authorize(&self.public_key, &self.user, authorized_keys, time)
+    }
+
+    /// Verifies the request's signature and accepts certificates from the trusted `cas`.
+    pub fn verify_trusted_ca(&self, session_id: &[u8], cas: &[PublicKey], time: u64) -> Option<Authorization<'static>> {
+        if !self.verify_signature(session_id) {
+            return None;
+        }
+        authorize_trusted_ca(&self.public_key, &self.user, cas, time)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use private_key::{PrivateKey, KeyType};
+
+    fn request(public_key: Vec<u8>, algorithm: &str, host_key: Option<Vec<u8>>) -> UserauthSignedData {
+        UserauthSignedData{
+            session_id: vec![7; 32],
+            user: "deploy".into(),
+            service: CONNECTION_SERVICE.into(),
+            method: if host_key.is_some() { PUBLICKEY_HOSTBOUND_METHOD } else { PUBLICKEY_METHOD }.into(),
+            algorithm: algorithm.into(),
+            public_key: public_key,
+            host_key: host_key,
+        }
+    }
+
+    #[test]
+    fn signed_data_round_trips() {
+        let (_, key) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (_, host_key) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        for data in &[request(key.clone(), "ssh-ed25519", None), request(key.clone(), "ssh-ed25519", Some(host_key))] {
+            let bytes = data.to_bytes().unwrap();
+            assert!(UserauthSignedData::parse(&bytes).unwrap() == *data);
+            assert!(data.key().unwrap().to_bytes().unwrap() == key);
+        }
+        let bytes = request(key, "ssh-ed25519", None).to_bytes().unwrap();
+        assert!(bytes[..36] == [&[0, 0, 0, 32][..], &[7; 32][..]].concat()[..]);
+        assert!(bytes[36] == SSH_MSG_USERAUTH_REQUEST);
+    }
+
+    #[test]
+    fn algorithm_must_suit_key() {
+        let (_, rsa) = PrivateKey::generate(KeyType::Rsa(2048)).unwrap();
+        let (_, ed25519) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        assert!(algorithm_matches_key(RSA_SHA2_512, &rsa));
+        assert!(algorithm_matches_key(RSA_TYPE, &rsa));
+        assert!(!algorithm_matches_key(RSA_SHA2_256, &ed25519));
+        assert!(!algorithm_matches_key("ssh-ed25519-cert-v01@openssh.com", &ed25519));
+        match UserauthSignedData::parse(&request(ed25519, "ecdsa-sha2-nistp256", None).to_bytes().unwrap()) {
+            Err(Error{kind: InvalidFormat}) => {},
+            _ => assert!("expected" == "InvalidFormat"),
+        }
+    }
+
+    #[test]
+    fn other_payloads_are_refused() {
+        let (_, key) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let mut bytes = request(key.clone(), "ssh-ed25519", None).to_bytes().unwrap();
+        bytes.push(0);
+        assert!(UserauthSignedData::parse(&bytes).is_err());
+        let mut password = request(key.clone(), "ssh-ed25519", None);
+        password.method = "password".into();
+        assert!(UserauthSignedData::parse(&password.to_bytes().unwrap()).is_err());
+        //  A host-bound request without its host key.
+        let mut hostbound = request(key, "ssh-ed25519", None);
+        hostbound.method = PUBLICKEY_HOSTBOUND_METHOD.into();
+        assert!(UserauthSignedData::parse(&hostbound.to_bytes().unwrap()).is_err());
+        assert!(UserauthSignedData::parse(b"SSHSIG").is_err());
+    }
+
+    fn signed_request(private_key: &PrivateKey, public_key: Vec<u8>, algorithm: &str) -> UserauthRequest {
+        let mut request = UserauthRequest{
+            user: "deploy".into(),
+            service: CONNECTION_SERVICE.into(),
+            method: PUBLICKEY_METHOD.into(),
+            algorithm: algorithm.into(),
+            public_key: public_key,
+            host_key: None,
+            signature: None,
+        };
+        let data = request.signed_data(&[7; 32]).to_bytes().unwrap();
+        let signature_algorithm = strip_cert_suffix(algorithm).0.to_string();
+        request.signature = Some(private_key.sign_with_algorithm(&data, &signature_algorithm).unwrap());
+        request
+    }
+
+    #[test]
+    fn server_verifies_authorized_keys() {
+        let (private_key, key_blob) = PrivateKey::generate(KeyType::Rsa(2048)).unwrap();
+        let line = private_key.public_key().to_openssh_line("deploy").unwrap();
+        let authorized_keys = AuthorizedKeys::parse(&format!("no-pty {}\n", line));
+        let request = signed_request(&private_key, key_blob.clone(), RSA_SHA2_256);
+        let request = UserauthRequest::parse(&request.to_bytes().unwrap()).unwrap();
+        match request.verify(&[7; 32], &authorized_keys, 0) {
+            Some(Authorization::Key(entry)) => assert!(entry.options == vec![KeyOption::Flag("no-pty".into())]),
+            _ => assert!("expected" == "Key"),
+        }
+        assert!(request.verify(&[8; 32], &authorized_keys, 0).is_none());
+
+        //  The named algorithm must be the one used.
+        let mut mismatched = request.clone();
+        mismatched.algorithm = RSA_SHA2_512.into();
+        assert!(mismatched.verify(&[7; 32], &authorized_keys, 0).is_none());
+        let expiring = AuthorizedKeys::parse(&format!("expiry-time=\"20200101\" {}\n", line));
+        assert!(request.verify(&[7; 32], &expiring, 1500000000).is_some());
+        assert!(request.verify(&[7; 32], &expiring, 1700000000).is_none());
+    }
+
+    #[test]
+    fn server_verifies_certificates() {
+        let (ca, _) = PrivateKey::generate(KeyType::Ed25519).unwrap();
+        let (private_key, _) = PrivateKey::generate(KeyType::EcdsaSha2Nistp256).unwrap();
+        let mut certificate = Certificate::new(private_key.public_key(), CertType::User, "deploy@laptop").unwrap();
+        certificate.principals = vec!["deploy".into()];
+        certificate.valid_after = 0;
+        certificate.valid_before = 2000000000;
+        certificate.sign(&ca).unwrap();
+        let algorithm = certificate.key_type();
+        let request = signed_request(&private_key, certificate.to_bytes().unwrap(), &algorithm);
+
+        let ca_line = ca.public_key().to_openssh_line("ca").unwrap();
+        let authorized_keys = AuthorizedKeys::parse(&format!("cert-authority {}\n", ca_line));
+        match request.verify(&[7; 32], &authorized_keys, 1700000000) {
+            Some(Authorization::CertAuthority(_, certificate)) => assert!(certificate.key_id == "deploy@laptop"),
+            _ => assert!("expected" == "CertAuthority"),
+        }
+        assert!(request.verify_trusted_ca(&[7; 32], &[ca.public_key()], 1700000000).is_some());
+        assert!(request.verify_trusted_ca(&[7; 32], &[private_key.public_key()], 1700000000).is_none());
+        assert!(request.verify(&[7; 32], &authorized_keys, 2000000000).is_none());
+
+        //  A CA entry without cert-authority, or whose principals exclude the certificate's, refuses it.
+        assert!(request.verify(&[7; 32], &AuthorizedKeys::parse(&ca_line), 1700000000).is_none());
+        let other = AuthorizedKeys::parse(&format!("cert-authority,principals=\"admin\" {}\n", ca_line));
+        assert!(request.verify(&[7; 32], &other, 1700000000).is_none());
+        assert!(authorize(&request.public_key, "root", &authorized_keys, 1700000000).is_none());
+    }
+}